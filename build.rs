@@ -0,0 +1,31 @@
+// Copyright 2024 Aurelia Peters
+//
+// This file is part of GraphQL Cacher.
+// 
+// GraphQL Cacher is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// 
+// GraphQL Cacher is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License along with GraphQL Cacher. If not, see <https://www.gnu.org/licenses/>. 
+//! Refuses to build the application for Compute@Edge with an empty schema.graphql, since queries
+//! without an entry in PROCESSING_INSTRUCTIONS would then never be partitioned. Builds for other
+//! targets, such as those running the tests, do not need a schema.
+use std::env;
+use std::fs;
+
+/// Set this environment variable to build for Compute@Edge without a schema anyway
+const ALLOW_EMPTY_SCHEMA: &str = "GRAPHQL_CACHER_ALLOW_EMPTY_SCHEMA";
+
+fn main() {
+    println!("cargo:rerun-if-changed=schema.graphql");
+    println!("cargo:rerun-if-env-changed={}", ALLOW_EMPTY_SCHEMA);
+    let sdl = fs::read_to_string("schema.graphql").unwrap_or_default();
+    let is_wasm = env::var("CARGO_CFG_TARGET_ARCH").as_deref() == Ok("wasm32");
+    if is_wasm && sdl.trim().is_empty() && env::var_os(ALLOW_EMPTY_SCHEMA).is_none() {
+        panic!(
+            "schema.graphql is empty. Put the backend's schema SDL in it before building, or set {} \
+             to build without one.",
+            ALLOW_EMPTY_SCHEMA
+        );
+    }
+}
@@ -0,0 +1,527 @@
+// Copyright 2024 Aurelia Peters
+//
+// This file is part of GraphQL Operation Partitioner.
+// 
+// GraphQL Operation Partitioner is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// 
+// GraphQL Operation Partitioner is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License along with GraphQL Cacher. If not, see <https://www.gnu.org/licenses/>. 
+//! Union, intersection and difference of selection sets, operations and documents
+use crate::{
+    comparisions::{argument_signatures, directive_signatures},
+    fragments::referenced_fragments,
+    operation_selection_set, operation_selection_set_mut,
+    variables::prune_variable_definitions,
+};
+use graphql_parser::query::{
+    Definition, Document, FragmentDefinition, OperationDefinition, Selection, SelectionSet, Text,
+    TypeCondition, VariableDefinition,
+};
+use itertools::{Either, Itertools};
+use std::collections::HashSet;
+
+/// Trait used to combine GraphQL selection sets, operations and documents as sets of selections.
+/// Two selections are the same if they are fields with the same response key, name, arguments and
+/// directives; inline fragments with the same type condition and directives; or spreads of the
+/// same fragment with the same directives. The order of arguments and directives does not matter.
+/// The selection sets of fields and inline fragments that are the same are combined in turn.
+///
+/// Selections are compared as they are written: a field selected within a fragment is not the same
+/// as the same field selected directly. Fragment spreads are compared by name only, without
+/// looking into the fragments.
+pub trait Algebra: Sized {
+    /// Return everything selected by either `self` or `other`. Selections that are the same are
+    /// merged, so the result holds no duplicates.
+    ///
+    /// # Examples
+    /// ```
+    /// use graphql_parser::parse_query;
+    /// use partition_operation::algebra::Algebra;
+    ///
+    /// let a = parse_query::<&str>("{ alpha, beta { one } }").unwrap();
+    /// let b = parse_query::<&str>("{ beta { one, two }, gamma }").unwrap();
+    /// assert_eq!(
+    ///     "{\n  alpha\n  beta {\n    one\n    two\n  }\n  gamma\n}\n",
+    ///     a.union(&b).to_string()
+    /// );
+    /// ```
+    fn union(&self, other: &Self) -> Self;
+
+    /// Return everything selected by both `self` and `other`. Fields and inline fragments whose
+    /// selection sets have nothing in common are left out, which may leave the result empty.
+    ///
+    /// # Examples
+    /// ```
+    /// use graphql_parser::parse_query;
+    /// use partition_operation::algebra::Algebra;
+    ///
+    /// let a = parse_query::<&str>("{ alpha, beta { one, two } }").unwrap();
+    /// let b = parse_query::<&str>("{ beta { two, three }, gamma }").unwrap();
+    /// assert_eq!(
+    ///     "{\n  beta {\n    two\n  }\n}\n",
+    ///     a.intersection(&b).to_string()
+    /// );
+    /// ```
+    fn intersection(&self, other: &Self) -> Self;
+
+    /// Return everything selected by `self` but not by `other`. Fields and inline fragments whose
+    /// selection sets are emptied are left out, which may leave the result empty.
+    ///
+    /// # Examples
+    /// ```
+    /// use graphql_parser::parse_query;
+    /// use partition_operation::algebra::Algebra;
+    ///
+    /// let a = parse_query::<&str>("{ alpha, beta { one, two } }").unwrap();
+    /// let b = parse_query::<&str>("{ alpha, beta { two } }").unwrap();
+    /// assert_eq!(
+    ///     "{\n  beta {\n    one\n  }\n}\n",
+    ///     a.difference(&b).to_string()
+    /// );
+    /// ```
+    fn difference(&self, other: &Self) -> Self;
+}
+
+impl<'a, T: Text<'a> + Clone> Algebra for SelectionSet<'a, T> {
+    fn union(&self, other: &Self) -> Self {
+        let mut items: Vec<Selection<'a, T>> = vec![];
+        for selection in self.items.iter().chain(&other.items) {
+            let signature = signature(selection);
+            match items
+                .iter_mut()
+                .find(|item| self::signature(item) == signature)
+            {
+                Some(Selection::Field(field)) => {
+                    if let Selection::Field(other) = selection {
+                        field.selection_set = field.selection_set.union(&other.selection_set);
+                    }
+                }
+                Some(Selection::InlineFragment(inline_fragment)) => {
+                    if let Selection::InlineFragment(other) = selection {
+                        inline_fragment.selection_set =
+                            inline_fragment.selection_set.union(&other.selection_set);
+                    }
+                }
+                Some(Selection::FragmentSpread(_)) => (),
+                None => items.push(deduplicated(selection)),
+            }
+        }
+        SelectionSet {
+            span: self.span,
+            items,
+        }
+    }
+
+    fn intersection(&self, other: &Self) -> Self {
+        combine_selection_sets(self, other, Combination::Intersection)
+    }
+
+    fn difference(&self, other: &Self) -> Self {
+        combine_selection_sets(self, other, Combination::Difference)
+    }
+}
+
+impl<'a, T: Text<'a> + Clone> Algebra for OperationDefinition<'a, T> {
+    /// The result takes its operation type, name and directives from `self`, and the variable
+    /// definitions of both operations
+    fn union(&self, other: &Self) -> Self {
+        combine_operations(self, other, Combination::Union, &[])
+    }
+
+    /// The result takes its operation type, name and directives from `self`, and keeps only the
+    /// variable definitions it uses
+    fn intersection(&self, other: &Self) -> Self {
+        combine_operations(self, other, Combination::Intersection, &[])
+    }
+
+    /// The result takes its operation type, name and directives from `self`, and keeps only the
+    /// variable definitions it uses
+    fn difference(&self, other: &Self) -> Self {
+        combine_operations(self, other, Combination::Difference, &[])
+    }
+}
+
+impl<'a, T: Text<'a> + Clone> Algebra for Document<'a, T> {
+    /// Operations are paired up by name and combined as operations are. An operation without a
+    /// counterpart is kept as it is. The result carries exactly the fragment definitions its
+    /// operations reference, taken from `self` where both documents define a fragment.
+    fn union(&self, other: &Self) -> Self {
+        combine_documents(self, other, Combination::Union)
+    }
+
+    /// Operations are paired up by name and combined as operations are. Operations without a
+    /// counterpart are left out. The result carries exactly the fragment definitions its
+    /// operations reference.
+    fn intersection(&self, other: &Self) -> Self {
+        combine_documents(self, other, Combination::Intersection)
+    }
+
+    /// Operations are paired up by name and combined as operations are. Operations of `self`
+    /// without a counterpart are kept as they are. The result carries exactly the fragment
+    /// definitions its operations reference.
+    fn difference(&self, other: &Self) -> Self {
+        combine_documents(self, other, Combination::Difference)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Combination {
+    Union,
+    Intersection,
+    Difference,
+}
+
+/// Identify a selection by everything but its selection set. Selections with the same signature
+/// are the same selection.
+pub(crate) fn signature<'a, T: Text<'a>>(selection: &Selection<'a, T>) -> String {
+    match selection {
+        Selection::Field(field) => format!(
+            "{}: {}({}) {}",
+            field.alias.as_ref().unwrap_or(&field.name).as_ref(),
+            field.name.as_ref(),
+            argument_signatures(&field.arguments).join(", "),
+            directive_signatures(field.directives.iter()).join(" ")
+        ),
+        Selection::FragmentSpread(spread) => format!(
+            "...{} {}",
+            spread.fragment_name.as_ref(),
+            directive_signatures(spread.directives.iter()).join(" ")
+        ),
+        Selection::InlineFragment(inline_fragment) => format!(
+            "... {} {}",
+            match &inline_fragment.type_condition {
+                Some(TypeCondition::On(type_name)) => type_name.as_ref(),
+                None => "",
+            },
+            directive_signatures(inline_fragment.directives.iter()).join(" ")
+        ),
+    }
+}
+
+/// Return a copy of the selection with any duplicates within it merged
+fn deduplicated<'a, T: Text<'a> + Clone>(selection: &Selection<'a, T>) -> Selection<'a, T> {
+    let mut selection = selection.clone();
+    match &mut selection {
+        Selection::Field(field) => {
+            field.selection_set = field.selection_set.union(&empty(&field.selection_set))
+        }
+        Selection::InlineFragment(inline_fragment) => {
+            inline_fragment.selection_set = inline_fragment
+                .selection_set
+                .union(&empty(&inline_fragment.selection_set))
+        }
+        Selection::FragmentSpread(_) => (),
+    }
+    selection
+}
+
+fn empty<'a, T: Text<'a>>(selection_set: &SelectionSet<'a, T>) -> SelectionSet<'a, T> {
+    SelectionSet {
+        span: selection_set.span,
+        items: vec![],
+    }
+}
+
+/// Intersect or subtract selection sets. Fragment spreads, and fields without a selection set on
+/// either side, are treated as a whole. Fields and inline fragments whose selection sets are
+/// emptied are left out.
+fn combine_selection_sets<'a, T: Text<'a> + Clone>(
+    selection_set: &SelectionSet<'a, T>,
+    other: &SelectionSet<'a, T>,
+    combination: Combination,
+) -> SelectionSet<'a, T> {
+    let selection_set = selection_set.union(&empty(selection_set));
+    let other = other.union(&empty(other));
+    let mut items = vec![];
+    for selection in selection_set.items {
+        let signature = signature(&selection);
+        let counterpart = other
+            .items
+            .iter()
+            .find(|item| self::signature(item) == signature);
+        let combined = match (selection, counterpart) {
+            (_, None) if combination == Combination::Intersection => None,
+            (selection, None) => Some(selection),
+            (Selection::Field(mut field), Some(Selection::Field(other)))
+                if !field.selection_set.items.is_empty()
+                    && !other.selection_set.items.is_empty() =>
+            {
+                field.selection_set =
+                    combine_selection_sets(&field.selection_set, &other.selection_set, combination);
+                if field.selection_set.items.is_empty() {
+                    None
+                } else {
+                    Some(Selection::Field(field))
+                }
+            }
+            (
+                Selection::InlineFragment(mut inline_fragment),
+                Some(Selection::InlineFragment(other)),
+            ) => {
+                inline_fragment.selection_set = combine_selection_sets(
+                    &inline_fragment.selection_set,
+                    &other.selection_set,
+                    combination,
+                );
+                if inline_fragment.selection_set.items.is_empty() {
+                    None
+                } else {
+                    Some(Selection::InlineFragment(inline_fragment))
+                }
+            }
+            (selection, Some(_)) if combination == Combination::Intersection => Some(selection),
+            (_, Some(_)) => None,
+        };
+        items.extend(combined);
+    }
+    SelectionSet {
+        span: selection_set.span,
+        items,
+    }
+}
+
+/// Combine two operations, resolving fragment spreads against the given fragment definitions when
+/// pruning variable definitions
+fn combine_operations<'a, T: Text<'a> + Clone>(
+    operation: &OperationDefinition<'a, T>,
+    other: &OperationDefinition<'a, T>,
+    combination: Combination,
+    fragments: &[FragmentDefinition<'a, T>],
+) -> OperationDefinition<'a, T> {
+    let selection_set = operation_selection_set(operation);
+    let other_selection_set = operation_selection_set(other);
+    let combined = match combination {
+        Combination::Union => selection_set.union(other_selection_set),
+        Combination::Intersection => selection_set.intersection(other_selection_set),
+        Combination::Difference => selection_set.difference(other_selection_set),
+    };
+    let mut operation = operation.clone();
+    *operation_selection_set_mut(&mut operation) = combined;
+    let mut combined = operation;
+    if combination == Combination::Union {
+        if let (Some(definitions), Some(other_definitions)) = (
+            variable_definitions_mut(&mut combined),
+            variable_definitions(other),
+        ) {
+            for definition in other_definitions {
+                if !definitions.iter().any(|d| d.name == definition.name) {
+                    definitions.push(definition.clone());
+                }
+            }
+        }
+    } else {
+        prune_variable_definitions(&mut combined, fragments);
+    }
+    combined
+}
+
+pub(crate) fn variable_definitions<'o, 'a, T: Text<'a>>(
+    operation: &'o OperationDefinition<'a, T>,
+) -> Option<&'o Vec<VariableDefinition<'a, T>>> {
+    match operation {
+        OperationDefinition::SelectionSet(_) => None,
+        OperationDefinition::Query(query) => Some(&query.variable_definitions),
+        OperationDefinition::Mutation(mutation) => Some(&mutation.variable_definitions),
+        OperationDefinition::Subscription(subscription) => Some(&subscription.variable_definitions),
+    }
+}
+
+fn variable_definitions_mut<'o, 'a, T: Text<'a>>(
+    operation: &'o mut OperationDefinition<'a, T>,
+) -> Option<&'o mut Vec<VariableDefinition<'a, T>>> {
+    match operation {
+        OperationDefinition::SelectionSet(_) => None,
+        OperationDefinition::Query(query) => Some(&mut query.variable_definitions),
+        OperationDefinition::Mutation(mutation) => Some(&mut mutation.variable_definitions),
+        OperationDefinition::Subscription(subscription) => {
+            Some(&mut subscription.variable_definitions)
+        }
+    }
+}
+
+fn operation_name<'o, 'a, T: Text<'a>>(
+    operation: &'o OperationDefinition<'a, T>,
+) -> Option<&'o str> {
+    match operation {
+        OperationDefinition::SelectionSet(_) => None,
+        OperationDefinition::Query(query) => query.name.as_ref().map(AsRef::as_ref),
+        OperationDefinition::Mutation(mutation) => mutation.name.as_ref().map(AsRef::as_ref),
+        OperationDefinition::Subscription(subscription) => {
+            subscription.name.as_ref().map(AsRef::as_ref)
+        }
+    }
+}
+
+fn combine_documents<'a, T: Text<'a> + Clone>(
+    document: &Document<'a, T>,
+    other: &Document<'a, T>,
+    combination: Combination,
+) -> Document<'a, T> {
+    let (operations, mut fragments) = split_document(document);
+    let (other_operations, other_fragments) = split_document(other);
+    for fragment in other_fragments {
+        if !fragments.iter().any(|f| f.name == fragment.name) {
+            fragments.push(fragment);
+        }
+    }
+
+    let mut combined = vec![];
+    for operation in &operations {
+        let name = operation_name(operation);
+        match other_operations.iter().find(|o| operation_name(o) == name) {
+            Some(other) => combined.push(combine_operations(
+                operation,
+                other,
+                combination,
+                &fragments,
+            )),
+            None if combination == Combination::Intersection => (),
+            None => combined.push(operation.clone()),
+        }
+    }
+    if combination == Combination::Union {
+        for other in &other_operations {
+            let name = operation_name(other);
+            if !operations.iter().any(|o| operation_name(o) == name) {
+                combined.push(other.clone());
+            }
+        }
+    }
+
+    let referenced = combined
+        .iter()
+        .flat_map(|operation| referenced_fragments(operation_selection_set(operation), &fragments))
+        .map(|fragment| fragment.name.as_ref().to_string())
+        .collect::<HashSet<_>>();
+    let fragments = fragments
+        .into_iter()
+        .filter(|fragment| referenced.contains(fragment.name.as_ref()));
+    Document {
+        definitions: combined
+            .into_iter()
+            .map(Definition::Operation)
+            .chain(fragments.map(Definition::Fragment))
+            .collect(),
+    }
+}
+
+fn split_document<'a, T: Text<'a> + Clone>(
+    document: &Document<'a, T>,
+) -> (
+    Vec<OperationDefinition<'a, T>>,
+    Vec<FragmentDefinition<'a, T>>,
+) {
+    document
+        .definitions
+        .iter()
+        .cloned()
+        .partition_map(|definition| match definition {
+            Definition::Operation(operation) => Either::Left(operation),
+            Definition::Fragment(fragment) => Either::Right(fragment),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Algebra;
+    use crate::comparisions::semantically_equal;
+    use crate::Operations;
+    use anyhow::Result;
+    use graphql_parser::{parse_query, query::OperationDefinition};
+
+    fn operation(query: &str) -> Result<OperationDefinition<'_, &str>> {
+        Ok(parse_query::<&str>(query)?.operations().pop().unwrap())
+    }
+
+    #[test]
+    fn union_merges_fields_regardless_of_argument_order() -> Result<()> {
+        let a = operation(r#"{ team(abbrev: "NYG", year: 2023) { name } }"#)?;
+        let b = operation(r#"{ team(year: 2023, abbrev: "NYG") { id } }"#)?;
+        let expected = operation(r#"{ team(abbrev: "NYG", year: 2023) { name, id } }"#)?;
+        assert!(semantically_equal(&expected, &a.union(&b)));
+        Ok(())
+    }
+
+    #[test]
+    fn union_keeps_fields_with_different_arguments_or_aliases_apart() -> Result<()> {
+        let a = operation(r#"{ nyg: team(abbrev: "NYG") { name } }"#)?;
+        let b =
+            operation(r#"{ dal: team(abbrev: "DAL") { name }, team(abbrev: "NYG") { name } }"#)?;
+        assert_eq!(a.union(&b).to_string().matches("team(").count(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn union_removes_duplicates_within_an_operation() -> Result<()> {
+        let a = operation("{ alpha { one }, alpha { two }, ... on Query { beta }, beta }")?;
+        let expected = operation("{ alpha { one, two }, ... on Query { beta }, beta }")?;
+        assert_eq!(expected.to_string(), a.union(&a).to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn union_combines_variable_definitions() -> Result<()> {
+        let a = operation("query Q($a: ID!) { alpha(id: $a) }")?;
+        let b = operation("query Q($a: ID!, $b: ID!) { beta(id: $b) }")?;
+        let expected = operation("query Q($a: ID!, $b: ID!) { alpha(id: $a), beta(id: $b) }")?;
+        assert_eq!(expected.to_string(), a.union(&b).to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn difference_prunes_variable_definitions() -> Result<()> {
+        let a = operation("query Q($a: ID!, $b: ID!) { alpha(id: $a), beta(id: $b) }")?;
+        let b = operation("query Q($b: ID!) { beta(id: $b) }")?;
+        let expected = operation("query Q($a: ID!) { alpha(id: $a) }")?;
+        assert_eq!(expected.to_string(), a.difference(&b).to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn difference_of_a_leaf_removes_the_whole_field() -> Result<()> {
+        let a = operation("{ alpha { one }, beta }")?;
+        let b = operation("{ alpha, gamma { two } }")?;
+        assert_eq!(
+            operation("{ beta }")?.to_string(),
+            a.difference(&b).to_string()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn intersection_of_disjoint_operations_is_empty() -> Result<()> {
+        let a = operation("{ alpha { one } }")?;
+        let b = operation("{ alpha { two }, beta }")?;
+        let intersection = a.intersection(&b);
+        assert!(crate::operation_selection_set(&intersection)
+            .items
+            .is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn documents_carry_only_referenced_fragments() -> Result<()> {
+        let a = parse_query::<&str>(
+            "query Q { alpha { ...A }, beta { ...B } }
+            fragment A on Alpha { one }
+            fragment B on Beta { two }",
+        )?;
+        let b = parse_query::<&str>(
+            "query Q { beta { ...B } }
+            fragment B on Beta { two }",
+        )?;
+        let expected = parse_query::<&str>(
+            "query Q { alpha { ...A } }
+            fragment A on Alpha { one }",
+        )?;
+        assert_eq!(expected.to_string(), a.difference(&b).to_string());
+        let expected = parse_query::<&str>(
+            "query Q { beta { ...B } }
+            fragment B on Beta { two }",
+        )?;
+        assert_eq!(expected.to_string(), a.intersection(&b).to_string());
+        assert_eq!(a.to_string(), a.union(&b).to_string());
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+// Copyright 2024 Aurelia Peters
+//
+// This file is part of GraphQL Operation Partitioner.
+// 
+// GraphQL Operation Partitioner is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// 
+// GraphQL Operation Partitioner is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License along with GraphQL Cacher. If not, see <https://www.gnu.org/licenses/>. 
+//! Analysis of what operations select, e.g. to choose partition paths or to track API usage
+use crate::{
+    fragments::expand_fragment_spreads,
+    operation_selection_set,
+    visit::{walk_selection_set, Context, Control, Visitor},
+};
+use graphql_parser::query::{Definition, Document, Field, FragmentDefinition, Text};
+use std::collections::HashSet;
+
+/// List the path of every leaf field that the document's operations select, in the syntax
+/// described under "Query Path Syntax" in README.md, so that any of them can be used to partition
+/// the operation. Each element is the response key of a field, qualified with the type condition
+/// of the innermost fragment it is selected within, if any. Fragment spreads are followed into
+/// their definitions. Each path is listed once, in the order in which it is first selected.
+///
+/// # Examples
+/// ```
+/// use graphql_parser::parse_query;
+/// use partition_operation::analysis::leaf_paths;
+///
+/// let document = parse_query::<&str>(
+///     "{ team(id: 1) { name, logo: image(size: 64), ...Coach }, season }
+///     fragment Coach on Team { coach { name } }",
+/// )
+/// .unwrap();
+/// assert_eq!(
+///     leaf_paths(&document),
+///     vec!["team.name", "team.logo", "team.coach[on Team].name", "season"]
+/// );
+/// ```
+pub fn leaf_paths<'a, T: Text<'a> + Clone>(document: &Document<'a, T>) -> Vec<String> {
+    let fragments = document
+        .definitions
+        .iter()
+        .filter_map(|definition| match definition {
+            Definition::Fragment(fragment) => Some(fragment.clone()),
+            Definition::Operation(_) => None,
+        })
+        .collect::<Vec<FragmentDefinition<'a, T>>>();
+    let mut leaves = LeafPaths::default();
+    for definition in &document.definitions {
+        if let Definition::Operation(operation) = definition {
+            let selection_set =
+                expand_fragment_spreads(operation_selection_set(operation), &fragments);
+            walk_selection_set(&selection_set, &mut leaves);
+        }
+    }
+    leaves.paths
+}
+
+/// Collects the paths of leaf fields, without duplicates
+#[derive(Default)]
+struct LeafPaths {
+    paths: Vec<String>,
+    seen: HashSet<String>,
+}
+
+impl<'a, T: Text<'a>> Visitor<'a, T> for LeafPaths {
+    fn enter_field(&mut self, field: &Field<'a, T>, context: &Context) -> Control {
+        if field.selection_set.items.is_empty() {
+            let path = context.path().join(".");
+            if self.seen.insert(path.clone()) {
+                self.paths.push(path);
+            }
+        }
+        Control::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::leaf_paths;
+    use crate::{path::validate_path, Partition};
+    use graphql_parser::parse_query;
+
+    #[test]
+    fn leaf_paths_can_partition_the_operation() {
+        let document = parse_query::<&str>(
+            r#"query Q($id: ID!) {
+                home: team(id: $id) { name, ... on Team { abbrev, name, ...Logo } }
+                away: team(id: 2) { name }
+                season
+            }
+            fragment Logo on Team { logo { url }, ... on Named { name } }"#,
+        )
+        .unwrap();
+        let paths = leaf_paths(&document);
+        assert_eq!(
+            paths,
+            vec![
+                "home.name",
+                "home.abbrev[on Team]",
+                "home.name[on Team]",
+                "home.logo[on Team].url",
+                "home.name[on Named]",
+                "away.name",
+                "season",
+            ]
+        );
+        for path in &paths {
+            assert!(validate_path(path).is_ok(), "Path \"{}\" is valid", path);
+            assert!(
+                document.clone().partition_by_path(path).is_ok(),
+                "Path \"{}\" partitions the operation",
+                path
+            );
+        }
+    }
+}
@@ -0,0 +1,277 @@
+// Copyright 2024 Aurelia Peters
+//
+// This file is part of GraphQL Operation Partitioner.
+// 
+// GraphQL Operation Partitioner is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// 
+// GraphQL Operation Partitioner is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License along with GraphQL Cacher. If not, see <https://www.gnu.org/licenses/>. 
+//! Command-line tool for partitioning GraphQL documents offline, e.g. to try out partition paths
+//! against real query files. Run with `--help` for usage.
+use graphql_parser::{
+    parse_query,
+    query::{Definition, Document, OperationDefinition},
+};
+use partition_operation::{normalize::normalize, Partition, PartitionError};
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::{self, Read},
+    process,
+};
+
+const USAGE: &str = "Usage: partition [--check] [--operation NAME] [--variable NAME=BOOL]... PATH [FILE]
+
+Partition the GraphQL document in FILE (or read from standard input) by PATH, as described
+under \"Query Path Syntax\" in README.md. Prints the part of the operation PATH selects (LEFT)
+and what is left of it (RIGHT), each with the variables and fragments it uses. The document is
+normalized first, as GraphQL Cacher does before partitioning: fragments are inlined, @skip and
+@include are evaluated with the given variables, and selections of the same field are merged.
+
+Options:
+  -o, --operation NAME       Partition the operation named NAME, if the document has several
+  -v, --variable NAME=BOOL   Set the Boolean variable NAME to BOOL (true or false)
+  -c, --check                Print nothing, but exit with status 1 if PATH does not match
+  -h, --help                 Print this message
+
+A PATH that matches the whole operation leaves RIGHT empty, and still counts as a match.
+Exits with status 1 if the document cannot be partitioned by PATH, and 2 on any other error.";
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Args {
+    path: String,
+    file: Option<String>,
+    operation_name: Option<String>,
+    variables: HashMap<String, bool>,
+    check: bool,
+}
+
+fn main() {
+    let args = match parse_args(env::args().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(why) => fail(2, &format!("{}\n\n{}", why, USAGE)),
+    };
+
+    let mut query = String::new();
+    let read = match &args.file {
+        Some(file) => fs::read_to_string(file).map(|text| query = text),
+        None => io::stdin().read_to_string(&mut query).map(|_| ()),
+    };
+    if let Err(why) = read {
+        fail(2, &format!("Could not read query: {}", why));
+    }
+    let document = match parse_query::<&str>(&query) {
+        Ok(document) => document,
+        Err(why) => fail(2, &format!("Could not parse query: {}", why)),
+    };
+    let document = match select_operation(document, args.operation_name.as_deref()) {
+        Ok(document) => document,
+        Err(why) => fail(2, &why),
+    };
+    let document = match normalize(document, |name| args.variables.get(name).copied()) {
+        Ok(document) => document,
+        Err(why) => fail(1, &why.to_string()),
+    };
+
+    match document.clone().partition_by_path(&args.path) {
+        Ok(_) | Err(PartitionError::EmptyResult { .. }) if args.check => (),
+        Ok((left, right)) => print!("{}\n{}", describe("LEFT", &left), describe("RIGHT", &right)),
+        Err(PartitionError::EmptyResult { .. }) => {
+            print!("{}\n# RIGHT\n# (empty)\n", describe("LEFT", &document))
+        }
+        Err(why) => fail(1, &why.to_string()),
+    }
+}
+
+fn fail(status: i32, message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(status)
+}
+
+/// Parse the command-line arguments, not including the program name. Returns `None` if help was
+/// asked for.
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Option<Args>, String> {
+    let mut parsed = Args::default();
+    let mut positional = vec![];
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-c" | "--check" => parsed.check = true,
+            "-o" | "--operation" => match args.next() {
+                Some(name) => parsed.operation_name = Some(name),
+                None => return Err(format!("{} requires an operation name", arg)),
+            },
+            "-v" | "--variable" => {
+                let variable = args.next().unwrap_or_default();
+                match variable.split_once('=') {
+                    Some((name, value)) if !name.is_empty() => {
+                        let value = value.parse().map_err(|_| {
+                            format!("Variable \"{}\" must be true or false", name)
+                        })?;
+                        parsed.variables.insert(name.to_string(), value);
+                    }
+                    _ => return Err(format!("{} requires a variable, as NAME=BOOL", arg)),
+                }
+            }
+            _ if arg.starts_with('-') && arg != "-" => {
+                return Err(format!("Unknown option \"{}\"", arg))
+            }
+            _ => positional.push(arg),
+        }
+    }
+    let mut positional = positional.into_iter();
+    parsed.path = positional.next().ok_or("No path given")?;
+    // "-" reads from standard input, as no file does
+    parsed.file = positional.next().filter(|file| file != "-");
+    if let Some(arg) = positional.next() {
+        return Err(format!("Unexpected argument \"{}\"", arg));
+    }
+    Ok(Some(parsed))
+}
+
+/// Reduce the document to the operation with the given name and the fragment definitions. Without
+/// a name, the document is returned as it is.
+fn select_operation<'a>(
+    document: Document<'a, &'a str>,
+    operation_name: Option<&str>,
+) -> Result<Document<'a, &'a str>, String> {
+    let operation_name = match operation_name {
+        Some(operation_name) => operation_name,
+        None => return Ok(document),
+    };
+    let definitions = document
+        .definitions
+        .into_iter()
+        .filter(|definition| match definition {
+            Definition::Operation(OperationDefinition::Query(query)) => {
+                query.name == Some(operation_name)
+            }
+            Definition::Operation(OperationDefinition::Mutation(mutation)) => {
+                mutation.name == Some(operation_name)
+            }
+            Definition::Operation(OperationDefinition::Subscription(subscription)) => {
+                subscription.name == Some(operation_name)
+            }
+            Definition::Operation(OperationDefinition::SelectionSet(_)) => false,
+            Definition::Fragment(_) => true,
+        })
+        .collect::<Vec<_>>();
+    if definitions
+        .iter()
+        .any(|definition| matches!(definition, Definition::Operation(_)))
+    {
+        Ok(Document { definitions })
+    } else {
+        Err(format!(
+            "No operation named \"{}\" in document",
+            operation_name
+        ))
+    }
+}
+
+/// Print one side of a partition, preceded by the variables and fragments it uses
+fn describe<'a>(label: &str, document: &Document<'a, &'a str>) -> String {
+    let mut variables = vec![];
+    let mut fragments = vec![];
+    for definition in &document.definitions {
+        match definition {
+            Definition::Operation(OperationDefinition::Query(query)) => variables.extend(
+                query
+                    .variable_definitions
+                    .iter()
+                    .map(|variable| format!("${}", variable.name)),
+            ),
+            Definition::Operation(_) => (),
+            Definition::Fragment(fragment) => fragments.push(fragment.name.to_string()),
+        }
+    }
+    format!(
+        "# {}\n# Variables: {}\n# Fragments: {}\n{}",
+        label,
+        list(&variables),
+        list(&fragments),
+        document
+    )
+}
+
+fn list(items: &[String]) -> String {
+    if items.is_empty() {
+        "(none)".to_string()
+    } else {
+        items.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{describe, parse_args, select_operation, Args};
+    use graphql_parser::parse_query;
+    use partition_operation::Partition;
+
+    fn args(args: &[&str]) -> Result<Option<Args>, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parse_args_reads_options_and_positional_arguments() {
+        assert_eq!(
+            args(&["--check", "matchup.prediction", "-o", "Q", "-v", "x=true", "query.graphql"]),
+            Ok(Some(Args {
+                path: "matchup.prediction".to_string(),
+                file: Some("query.graphql".to_string()),
+                operation_name: Some("Q".to_string()),
+                variables: vec![("x".to_string(), true)].into_iter().collect(),
+                check: true,
+            }))
+        );
+        assert_eq!(
+            args(&["alpha", "-"]).unwrap().unwrap().file,
+            None,
+            "\"-\" is standard input"
+        );
+        assert_eq!(args(&["alpha", "--help"]), Ok(None));
+        assert!(args(&[]).is_err(), "Path is required");
+        assert!(args(&["alpha", "a.graphql", "b.graphql"]).is_err());
+        assert!(args(&["alpha", "--operation"]).is_err());
+        assert!(args(&["alpha", "--verbose"]).is_err());
+        assert!(args(&["alpha", "--variable"]).is_err());
+        assert!(args(&["alpha", "--variable", "x"]).is_err());
+        assert!(args(&["alpha", "--variable", "x=1"]).is_err());
+    }
+
+    #[test]
+    fn select_operation_keeps_the_named_operation_and_fragments() {
+        let document = parse_query::<&str>(
+            "query A { alpha { ...F } } query B { beta } fragment F on Alpha { one }",
+        )
+        .unwrap();
+        let selected = select_operation(document.clone(), Some("A")).unwrap();
+        assert_eq!(
+            parse_query::<&str>("query A { alpha { ...F } } fragment F on Alpha { one }")
+                .unwrap()
+                .to_string(),
+            selected.to_string()
+        );
+        assert!(select_operation(document, Some("C")).is_err());
+    }
+
+    #[test]
+    fn describe_lists_variables_and_fragments() {
+        let document =
+            parse_query::<&str>(include_str!("../../fixtures/matchupAnalysis.graphql")).unwrap();
+        let (left, right) = document
+            .partition_by_path("matchupAnalysis.somePrediction")
+            .unwrap();
+        let left = describe("LEFT", &left);
+        let right = describe("RIGHT", &right);
+        assert!(left.starts_with("# LEFT\n# Variables: $"), "{}", left);
+        assert!(left.contains("# Fragments: (none)\n"), "{}", left);
+        assert!(right.contains("# Fragments: MaTeamInfo\n"), "{}", right);
+    }
+}
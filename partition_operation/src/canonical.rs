@@ -0,0 +1,308 @@
+// Copyright 2024 Aurelia Peters
+//
+// This file is part of GraphQL Operation Partitioner.
+// 
+// GraphQL Operation Partitioner is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// 
+// GraphQL Operation Partitioner is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License along with GraphQL Cacher. If not, see <https://www.gnu.org/licenses/>. 
+//! A canonical form for GraphQL operations, and a fingerprint of it. Operations that differ only
+//! in formatting, in the order of their selections, arguments, variable definitions and fragment
+//! definitions, or in aliases that repeat the field name have the same canonical form.
+use std::fmt;
+
+use graphql_parser::query::{
+    Definition, Directive, Document, OperationDefinition, Selection, SelectionSet, Text,
+};
+
+/// Trait used to bring GraphQL documents and operations into canonical form
+pub trait Canonicalize<'a, T: Text<'a>>: Sized + fmt::Display {
+    /// Return the canonical form of this document or operation. Within every selection set,
+    /// selections are sorted, as are the arguments of fields and directives and the variable
+    /// definitions of operations; aliases that are the same as the field name are removed. The
+    /// order of directives and of list values is significant, so it is kept.
+    ///
+    /// Note that the order of keys in a response follows the order of the selections, so a
+    /// response to the canonical form may list its keys in a different order than a response to
+    /// the original.
+    fn canonicalize(&self) -> Self;
+
+    /// Print the canonical form without any insignificant whitespace
+    ///
+    /// # Examples
+    /// ```
+    /// use graphql_parser::parse_query;
+    /// use partition_operation::canonical::Canonicalize;
+    ///
+    /// let a = parse_query::<&str>("{ team(id: 1, size: 2) { name: name, abbrev } }").unwrap();
+    /// let b = parse_query::<&str>("{\n  team(size: 2, id: 1) {\n    abbrev\n    name\n  }\n}")
+    ///     .unwrap();
+    /// assert_eq!("{team(id:1 size:2){abbrev name}}", a.canonical_string());
+    /// assert_eq!(a.canonical_string(), b.canonical_string());
+    /// assert_eq!(a.fingerprint(), b.fingerprint());
+    /// ```
+    fn canonical_string(&self) -> String {
+        minify(&self.canonicalize().to_string())
+    }
+
+    /// Return a fingerprint of the canonical form
+    fn fingerprint(&self) -> Fingerprint {
+        Fingerprint::of(&self.canonical_string())
+    }
+}
+
+impl<'a, T: Text<'a> + Clone> Canonicalize<'a, T> for Document<'a, T> {
+    /// Operations keep their order; fragment definitions are sorted by name and follow the
+    /// operations
+    fn canonicalize(&self) -> Self {
+        let mut operations = vec![];
+        let mut fragments = vec![];
+        for definition in &self.definitions {
+            match definition {
+                Definition::Operation(operation) => {
+                    operations.push(Definition::Operation(operation.canonicalize()))
+                }
+                Definition::Fragment(fragment) => {
+                    let mut fragment = fragment.clone();
+                    canonicalize_directives(&mut fragment.directives);
+                    canonicalize_selection_set(&mut fragment.selection_set);
+                    fragments.push(fragment);
+                }
+            }
+        }
+        fragments.sort_by(|a, b| a.name.cmp(&b.name));
+        operations.extend(fragments.into_iter().map(Definition::Fragment));
+        Document {
+            definitions: operations,
+        }
+    }
+}
+
+impl<'a, T: Text<'a> + Clone> Canonicalize<'a, T> for OperationDefinition<'a, T> {
+    fn canonicalize(&self) -> Self {
+        let mut operation = self.clone();
+        let (variable_definitions, directives, selection_set) = match &mut operation {
+            OperationDefinition::SelectionSet(selection_set) => {
+                canonicalize_selection_set(selection_set);
+                return operation;
+            }
+            OperationDefinition::Query(query) => (
+                &mut query.variable_definitions,
+                &mut query.directives,
+                &mut query.selection_set,
+            ),
+            OperationDefinition::Mutation(mutation) => (
+                &mut mutation.variable_definitions,
+                &mut mutation.directives,
+                &mut mutation.selection_set,
+            ),
+            OperationDefinition::Subscription(subscription) => (
+                &mut subscription.variable_definitions,
+                &mut subscription.directives,
+                &mut subscription.selection_set,
+            ),
+        };
+        variable_definitions.sort_by(|a, b| a.name.cmp(&b.name));
+        canonicalize_directives(directives);
+        canonicalize_selection_set(selection_set);
+        operation
+    }
+}
+
+/// A stable hash of the canonical form of an operation, printed as 16 hexadecimal digits. It does
+/// not depend on the platform or on the version of the compiler, so it can be used in cache keys
+/// and logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Fingerprint(u64);
+
+impl Fingerprint {
+    /// Compute the fingerprint of the given text using 64-bit FNV-1a
+    fn of(text: &str) -> Self {
+        const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        const PRIME: u64 = 0x0000_0100_0000_01b3;
+        Fingerprint(text.bytes().fold(OFFSET_BASIS, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+        }))
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+fn canonicalize_selection_set<'a, T: Text<'a> + Clone>(selection_set: &mut SelectionSet<'a, T>) {
+    for selection in &mut selection_set.items {
+        match selection {
+            Selection::Field(field) => {
+                if field.alias.as_ref() == Some(&field.name) {
+                    field.alias = None;
+                }
+                field.arguments.sort_by(|a, b| a.0.cmp(&b.0));
+                canonicalize_directives(&mut field.directives);
+                canonicalize_selection_set(&mut field.selection_set);
+            }
+            Selection::FragmentSpread(spread) => canonicalize_directives(&mut spread.directives),
+            Selection::InlineFragment(inline_fragment) => {
+                canonicalize_directives(&mut inline_fragment.directives);
+                canonicalize_selection_set(&mut inline_fragment.selection_set);
+            }
+        }
+    }
+    // Printed selections begin with the response key, fragment name or type condition, so this
+    // orders selections by those first
+    selection_set
+        .items
+        .sort_by_cached_key(|selection| minify(&print_selection(selection)));
+}
+
+fn print_selection<'a, T: Text<'a>>(selection: &Selection<'a, T>) -> String {
+    match selection {
+        Selection::Field(field) => field.to_string(),
+        Selection::FragmentSpread(spread) => spread.to_string(),
+        Selection::InlineFragment(inline_fragment) => inline_fragment.to_string(),
+    }
+}
+
+fn canonicalize_directives<'a, T: Text<'a>>(directives: &mut [Directive<'a, T>]) {
+    for directive in directives {
+        directive.arguments.sort_by(|a, b| a.0.cmp(&b.0));
+    }
+}
+
+/// Remove insignificant whitespace and commas from printed GraphQL. Whitespace is only needed
+/// between two names or numbers; strings are copied verbatim.
+fn minify(text: &str) -> String {
+    let mut minified = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    let mut separated = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                if separated && minified.ends_with(is_name_char) {
+                    minified.push(' ');
+                }
+                separated = false;
+                minified.push(c);
+                let block = text_starts_block(&mut chars, &mut minified);
+                copy_string(&mut chars, &mut minified, block);
+            }
+            c if c.is_whitespace() || c == ',' => separated = true,
+            c => {
+                if separated && is_name_char(c) && minified.ends_with(is_name_char) {
+                    minified.push(' ');
+                }
+                separated = false;
+                minified.push(c);
+            }
+        }
+    }
+    minified
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Having just copied an opening quote, copy the rest of a block string's opening quotes if there
+/// are any. Returns true if a block string was opened.
+fn text_starts_block(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+    minified: &mut String,
+) -> bool {
+    let mut lookahead = chars.clone();
+    if lookahead.next() == Some('"') && lookahead.next() == Some('"') {
+        chars.next();
+        chars.next();
+        minified.push_str("\"\"");
+        true
+    } else {
+        false
+    }
+}
+
+/// Copy the remainder of a string, including its closing quotes
+fn copy_string(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+    minified: &mut String,
+    block: bool,
+) {
+    while let Some(c) = chars.next() {
+        minified.push(c);
+        match c {
+            '\\' => minified.extend(chars.next()),
+            '"' if !block => return,
+            '"' if minified.ends_with("\"\"\"") => return,
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{minify, Canonicalize};
+    use graphql_parser::parse_query;
+
+    #[test]
+    fn canonical_form_ignores_order_formatting_and_redundant_aliases() {
+        let a = parse_query::<&str>(
+            r#"query Q($b: Int, $a: ID) {
+                team(id: $a, size: $b) { abbrev, name: name, ... on Team { logo } }
+                ...Extra
+            }
+            fragment Extra on Query { viewer { id } }
+            fragment Abc on Query { now }"#,
+        )
+        .unwrap();
+        let b = parse_query::<&str>(
+            r#"fragment Abc on Query { now }
+            query Q($a: ID, $b: Int) {
+                ...Extra
+                team(size: $b, id: $a) { ... on Team { logo } name abbrev }
+            }
+            fragment Extra on Query { viewer { id } }"#,
+        )
+        .unwrap();
+        assert_eq!(a.canonical_string(), b.canonical_string());
+        assert_eq!(a.fingerprint(), b.fingerprint());
+        assert_eq!(
+            "query Q($a:ID$b:Int){...Extra team(id:$a size:$b){...on Team{logo}abbrev name}}\
+            fragment Abc on Query{now}fragment Extra on Query{viewer{id}}",
+            a.canonical_string()
+        );
+    }
+
+    #[test]
+    fn canonical_form_keeps_aliases_and_list_order() {
+        let a = parse_query::<&str>("{ home: team(id: 1) { id }, teams(ids: [1, 2]) }").unwrap();
+        let b = parse_query::<&str>("{ team(id: 1) { id }, teams(ids: [2, 1]) }").unwrap();
+        assert_ne!(a.fingerprint(), b.fingerprint());
+        assert_eq!(
+            "{home:team(id:1){id}teams(ids:[1 2])}",
+            a.canonical_string()
+        );
+    }
+
+    #[test]
+    fn minify_keeps_strings_intact() {
+        assert_eq!(
+            r#"{a(b:"x,  y \" z"c:"""one
+  two""")}"#,
+            minify("{\n  a(b: \"x,  y \\\" z\", c: \"\"\"one\n  two\"\"\")\n}")
+        );
+        assert_eq!(
+            "{a(b:1 c:-2 d:A e:1.5)}",
+            minify("{ a(b: 1, c: -2, d: A, e: 1.5) }")
+        );
+    }
+
+    #[test]
+    fn fingerprint_is_stable() {
+        let document = parse_query::<&str>("{ alpha }").unwrap();
+        assert_eq!("{alpha}", document.canonical_string());
+        // FNV-1a of "{alpha}"
+        assert_eq!("342cdf2e8396742d", document.fingerprint().to_string());
+    }
+}
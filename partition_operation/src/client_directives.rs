@@ -0,0 +1,273 @@
+// Copyright 2024 Aurelia Peters
+//
+// This file is part of GraphQL Operation Partitioner.
+// 
+// GraphQL Operation Partitioner is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// 
+// GraphQL Operation Partitioner is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License along with GraphQL Cacher. If not, see <https://www.gnu.org/licenses/>. 
+//! Directives with which clients mark the cache policy of parts of a query themselves:
+//! `@edgePrivate` and `@edgeCache(ttl: Int)`. They are meant for the partitioner only, so they must
+//! be stripped before a query is sent on to the backend.
+use crate::{planner::Policy, variables::prune_variable_definitions};
+use graphql_parser::query::{
+    Definition, Directive, Document, OperationDefinition, Selection, SelectionSet, Text, Value,
+};
+use itertools::{Either, Itertools};
+use std::convert::TryFrom;
+
+/// Marks a selection as specific to the user making the request
+pub const EDGE_PRIVATE: &str = "edgePrivate";
+/// Marks a selection as public, to be cached for `ttl` seconds if given. A `ttl` of 0 marks the
+/// selection as uncacheable.
+pub const EDGE_CACHE: &str = "edgeCache";
+
+/// Read the policy given by the client directives among the given directives, if any. If both are
+/// present, `@edgePrivate` wins. A `ttl` that is not a literal integer is ignored.
+pub(crate) fn directive_policy<'a, T: Text<'a>>(directives: &[Directive<'a, T>]) -> Option<Policy> {
+    if directives.iter().any(|d| d.name.as_ref() == EDGE_PRIVATE) {
+        return Some(Policy::Private);
+    }
+    let directive = directives.iter().find(|d| d.name.as_ref() == EDGE_CACHE)?;
+    let ttl = directive
+        .arguments
+        .iter()
+        .find(|(name, _)| name.as_ref() == "ttl")
+        .and_then(|(_, value)| match value {
+            Value::Int(ttl) => ttl.as_i64(),
+            _ => None,
+        });
+    match ttl {
+        Some(0) => Some(Policy::Uncacheable),
+        ttl => Some(Policy::Public {
+            ttl: ttl.and_then(|ttl| u32::try_from(ttl).ok()),
+        }),
+    }
+}
+
+/// Returns true if any operation or fragment in the document uses a client directive
+///
+/// # Examples
+/// ```
+/// use graphql_parser::parse_query;
+/// use partition_operation::client_directives::has_client_directives;
+///
+/// let document = parse_query::<&str>("{ alpha, beta @edgePrivate }").unwrap();
+/// assert!(has_client_directives(&document));
+/// let document = parse_query::<&str>("{ alpha, beta @include(if: true) }").unwrap();
+/// assert!(!has_client_directives(&document));
+/// ```
+pub fn has_client_directives<'a, T: Text<'a>>(document: &Document<'a, T>) -> bool {
+    document
+        .definitions
+        .iter()
+        .any(|definition| match definition {
+            Definition::Operation(operation) => {
+                let (directives, selection_set) = operation_parts(operation);
+                is_client_directive_in(directives)
+                    || selection_set_has_client_directives(selection_set)
+            }
+            Definition::Fragment(fragment) => {
+                is_client_directive_in(&fragment.directives)
+                    || selection_set_has_client_directives(&fragment.selection_set)
+            }
+        })
+}
+
+/// Remove all client directives from the document, along with the definitions of any variables
+/// that are no longer used
+///
+/// # Examples
+/// ```
+/// use graphql_parser::parse_query;
+/// use partition_operation::client_directives::strip_client_directives;
+///
+/// let mut document =
+///     parse_query::<&str>("{ alpha @edgeCache(ttl: 60), beta @edgePrivate @include(if: true) }")
+///         .unwrap();
+/// strip_client_directives(&mut document);
+/// assert_eq!(
+///     "{\n  alpha\n  beta @include(if: true)\n}\n",
+///     document.to_string()
+/// );
+/// ```
+pub fn strip_client_directives<'a, T: Text<'a>>(document: &mut Document<'a, T>) {
+    for definition in &mut document.definitions {
+        match definition {
+            Definition::Operation(OperationDefinition::SelectionSet(selection_set)) => {
+                strip_selection_set(selection_set)
+            }
+            Definition::Operation(OperationDefinition::Query(query)) => {
+                retain_other_directives(&mut query.directives);
+                strip_selection_set(&mut query.selection_set);
+            }
+            Definition::Operation(OperationDefinition::Mutation(mutation)) => {
+                retain_other_directives(&mut mutation.directives);
+                strip_selection_set(&mut mutation.selection_set);
+            }
+            Definition::Operation(OperationDefinition::Subscription(subscription)) => {
+                retain_other_directives(&mut subscription.directives);
+                strip_selection_set(&mut subscription.selection_set);
+            }
+            Definition::Fragment(fragment) => {
+                retain_other_directives(&mut fragment.directives);
+                strip_selection_set(&mut fragment.selection_set);
+            }
+        }
+    }
+
+    let (mut operations, fragments): (Vec<_>, Vec<_>) = document
+        .definitions
+        .drain(..)
+        .partition_map(|definition| match definition {
+            Definition::Operation(operation) => Either::Left(operation),
+            Definition::Fragment(fragment) => Either::Right(fragment),
+        });
+    for operation in &mut operations {
+        prune_variable_definitions(operation, &fragments);
+    }
+    document.definitions = operations
+        .into_iter()
+        .map(Definition::Operation)
+        .chain(fragments.into_iter().map(Definition::Fragment))
+        .collect();
+}
+
+fn is_client_directive<'a, T: Text<'a>>(directive: &Directive<'a, T>) -> bool {
+    let name = directive.name.as_ref();
+    name == EDGE_PRIVATE || name == EDGE_CACHE
+}
+
+fn is_client_directive_in<'a, T: Text<'a>>(directives: &[Directive<'a, T>]) -> bool {
+    directives.iter().any(is_client_directive)
+}
+
+fn retain_other_directives<'a, T: Text<'a>>(directives: &mut Vec<Directive<'a, T>>) {
+    directives.retain(|directive| !is_client_directive(directive));
+}
+
+fn selection_set_has_client_directives<'a, T: Text<'a>>(
+    selection_set: &SelectionSet<'a, T>,
+) -> bool {
+    selection_set.items.iter().any(|selection| match selection {
+        Selection::Field(field) => {
+            is_client_directive_in(&field.directives)
+                || selection_set_has_client_directives(&field.selection_set)
+        }
+        Selection::FragmentSpread(spread) => is_client_directive_in(&spread.directives),
+        Selection::InlineFragment(inline_fragment) => {
+            is_client_directive_in(&inline_fragment.directives)
+                || selection_set_has_client_directives(&inline_fragment.selection_set)
+        }
+    })
+}
+
+fn strip_selection_set<'a, T: Text<'a>>(selection_set: &mut SelectionSet<'a, T>) {
+    for selection in &mut selection_set.items {
+        match selection {
+            Selection::Field(field) => {
+                retain_other_directives(&mut field.directives);
+                strip_selection_set(&mut field.selection_set);
+            }
+            Selection::FragmentSpread(spread) => retain_other_directives(&mut spread.directives),
+            Selection::InlineFragment(inline_fragment) => {
+                retain_other_directives(&mut inline_fragment.directives);
+                strip_selection_set(&mut inline_fragment.selection_set);
+            }
+        }
+    }
+}
+
+/// Return the directives and selection set of an operation. An operation that is just a
+/// selection set has no directives.
+pub(crate) fn operation_parts<'o, 'a, T: Text<'a>>(
+    operation: &'o OperationDefinition<'a, T>,
+) -> (&'o [Directive<'a, T>], &'o SelectionSet<'a, T>) {
+    match operation {
+        OperationDefinition::SelectionSet(selection_set) => (&[], selection_set),
+        OperationDefinition::Query(query) => (&query.directives, &query.selection_set),
+        OperationDefinition::Mutation(mutation) => (&mutation.directives, &mutation.selection_set),
+        OperationDefinition::Subscription(subscription) => {
+            (&subscription.directives, &subscription.selection_set)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::strip_client_directives;
+    use crate::planner::{Plan, Policy, PolicyMap};
+    use graphql_parser::parse_query;
+
+    #[test]
+    fn plan_follows_client_directives() {
+        let policies = PolicyMap::new(Policy::Public { ttl: None })
+            .with_field_type("Query.matchupAnalysis", "MatchupAnalysis")
+            .with_policy("MatchupAnalysis.teams", Policy::Private)
+            .with_policy("MatchupAnalysis.lastUpdated", Policy::Public { ttl: Some(600) });
+        // Client directives only make policies more restrictive: "teams" stays private despite
+        // its @edgeCache, and no TTL ends up longer than the operation's 60 seconds
+        let document = parse_query::<&str>(
+            r#"query Q @edgeCache(ttl: 60) {
+                matchupAnalysis {
+                    teams @edgeCache(ttl: 300) { name }
+                    somePrediction @edgePrivate { score }
+                    viewer { ...Viewer @edgeCache(ttl: 0) }
+                    lastUpdated
+                    schedule @edgeCache(ttl: 30) { week }
+                    standings @edgeCache(ttl: 300) { rank }
+                }
+            }
+            fragment Viewer on Viewer { id }"#,
+        )
+        .unwrap();
+        let plan = document
+            .plan(&policies)
+            .unwrap()
+            .into_iter()
+            .map(|planned| (planned.policy, planned.partition.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (
+                    Policy::Private,
+                    "query Q @edgeCache(ttl: 60) {\n  matchupAnalysis {\n    teams @edgeCache(ttl: 300) {\n      name\n    }\n    somePrediction @edgePrivate {\n      score\n    }\n  }\n}\n"
+                        .to_string()
+                ),
+                (
+                    Policy::Uncacheable,
+                    "query Q @edgeCache(ttl: 60) {\n  matchupAnalysis {\n    viewer {\n      ...Viewer @edgeCache(ttl: 0)\n    }\n  }\n}\n\nfragment Viewer on Viewer {\n  id\n}\n"
+                        .to_string()
+                ),
+                (
+                    Policy::Public { ttl: Some(30) },
+                    "query Q @edgeCache(ttl: 60) {\n  matchupAnalysis {\n    schedule @edgeCache(ttl: 30) {\n      week\n    }\n  }\n}\n"
+                        .to_string()
+                ),
+                (
+                    Policy::Public { ttl: Some(60) },
+                    "query Q @edgeCache(ttl: 60) {\n  matchupAnalysis {\n    lastUpdated\n    standings @edgeCache(ttl: 300) {\n      rank\n    }\n  }\n}\n"
+                        .to_string()
+                ),
+            ],
+            plan
+        );
+    }
+
+    #[test]
+    fn strip_client_directives_prunes_variables_they_used() {
+        let mut document = parse_query::<&str>(
+            r#"query Q($ttl: Int, $id: ID) @edgeCache {
+                team(id: $id) @edgeCache(ttl: $ttl) { ...T @edgePrivate }
+            }
+            fragment T on Team @edgePrivate { name }"#,
+        )
+        .unwrap();
+        strip_client_directives(&mut document);
+        assert_eq!(
+            "query Q($id: ID) {\n  team(id: $id) {\n    ...T\n  }\n}\n\nfragment T on Team {\n  name\n}\n",
+            document.to_string()
+        );
+    }
+}
@@ -7,17 +7,528 @@
 // GraphQL Operation Partitioner is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
 // 
 // You should have received a copy of the GNU General Public License along with GraphQL Cacher. If not, see <https://www.gnu.org/licenses/>. 
+//! Order-insensitive comparison and diffing of GraphQL operations
 use graphql_parser::query::{
-    Directive, Field, FragmentSpread, InlineFragment, Query, SelectionSet, Value,
-    VariableDefinition,
+    Directive, Document, Field, FragmentSpread, InlineFragment, OperationDefinition, Query,
+    Selection, SelectionSet, Text, TypeCondition, Value, VariableDefinition,
 };
 use std::collections::HashMap;
+use std::fmt;
 
+use crate::algebra::Algebra;
 use crate::fields_and_fragments::FieldsAndFragments;
+use crate::fragments::expand_fragment_spreads;
+use crate::normalize::collapse_inline_fragments;
+use crate::{into_operation_and_fragments, operation_selection_set_mut, PartitionError};
 
-pub(crate) fn compare_queries<'a, 'b>(
-    expected: &'b Query<'a, &'a str>,
-    got: &'b Query<'a, &'a str>,
+/// Returns true if the two operations are semantically equal, that is, if they differ at most in
+/// formatting and in the order of selections, arguments, directives and variable definitions. An
+/// operation that is just a selection set is equal to an anonymous query with the same selection
+/// set.
+///
+/// # Examples
+/// ```
+/// use graphql_parser::query::{parse_query, Definition};
+/// use partition_operation::comparisions::semantically_equal;
+///
+/// let a = parse_query::<&str>("query Q($id: ID) { team(id: $id) { name, abbrev } }").unwrap();
+/// let b = parse_query::<&str>("query Q($id: ID) { team(id: $id) { abbrev name } }").unwrap();
+/// match (&a.definitions[0], &b.definitions[0]) {
+///     (Definition::Operation(a), Definition::Operation(b)) => assert!(semantically_equal(a, b)),
+///     _ => unreachable!(),
+/// }
+/// ```
+pub fn semantically_equal<'a, T: Text<'a>>(
+    a: &OperationDefinition<'a, T>,
+    b: &OperationDefinition<'a, T>,
+) -> bool {
+    compare_operations(a, b).0
+}
+
+/// Compare two operations, ignoring the order of selections, arguments and variable definitions.
+/// Returns whether they match and, if not, the reason why.
+pub fn compare_operations<'a, 'b, T: Text<'a>>(
+    expected: &'b OperationDefinition<'a, T>,
+    got: &'b OperationDefinition<'a, T>,
+) -> (bool, Option<String>) {
+    let (expected, got) = (OperationParts::from(expected), OperationParts::from(got));
+    if expected.kind != got.kind {
+        return (
+            false,
+            Some(format!(
+                "Operation types do not match. {} != {}",
+                expected.kind, got.kind
+            )),
+        );
+    }
+    if expected.name != got.name {
+        return (
+            false,
+            Some(format!(
+                "Operation names do not match. \"{}\" != \"{}\"",
+                expected.name.map_or("None", AsRef::as_ref),
+                got.name.map_or("None", AsRef::as_ref)
+            )),
+        );
+    }
+    let (directives_match, failure_reason) =
+        compare_directive_vecs(expected.directives, got.directives);
+    if !directives_match {
+        return (
+            false,
+            failure_reason.map(|reason| format!("Directives do not match: {}", reason.as_str())),
+        );
+    }
+    let (variable_definitions_match, failure_reason) =
+        compare_variable_definition_vecs(expected.variable_definitions, got.variable_definitions);
+    if !variable_definitions_match {
+        return (
+            false,
+            failure_reason
+                .map(|reason| format!("Variable definitions do not match: {}", reason.as_str())),
+        );
+    }
+    compare_selection_sets(expected.selection_set, got.selection_set)
+}
+
+/// The parts that make up an operation of any type
+struct OperationParts<'b, 'a, T: Text<'a>> {
+    kind: &'static str,
+    name: Option<&'b T::Value>,
+    variable_definitions: &'b [VariableDefinition<'a, T>],
+    directives: &'b [Directive<'a, T>],
+    selection_set: &'b SelectionSet<'a, T>,
+}
+
+impl<'b, 'a, T: Text<'a>> From<&'b OperationDefinition<'a, T>> for OperationParts<'b, 'a, T> {
+    fn from(operation: &'b OperationDefinition<'a, T>) -> Self {
+        match operation {
+            OperationDefinition::SelectionSet(selection_set) => OperationParts {
+                kind: "query",
+                name: None,
+                variable_definitions: &[],
+                directives: &[],
+                selection_set,
+            },
+            OperationDefinition::Query(query) => OperationParts {
+                kind: "query",
+                name: query.name.as_ref(),
+                variable_definitions: &query.variable_definitions,
+                directives: &query.directives,
+                selection_set: &query.selection_set,
+            },
+            OperationDefinition::Mutation(mutation) => OperationParts {
+                kind: "mutation",
+                name: mutation.name.as_ref(),
+                variable_definitions: &mutation.variable_definitions,
+                directives: &mutation.directives,
+                selection_set: &mutation.selection_set,
+            },
+            OperationDefinition::Subscription(subscription) => OperationParts {
+                kind: "subscription",
+                name: subscription.name.as_ref(),
+                variable_definitions: &subscription.variable_definitions,
+                directives: &subscription.directives,
+                selection_set: &subscription.selection_set,
+            },
+        }
+    }
+}
+
+/// The differences between two operations, as found by [`diff`]. Selections are identified by
+/// their path, in the syntax described under "Query Path Syntax" in README.md: fields are named by
+/// their response key (alias or name), a field selected within a fragment carries the innermost
+/// fragment's type condition as a `[on Type]` qualifier, and fragment spreads are written as
+/// `...Name`. Variable definitions are identified as `$name`, and changes to the operation itself
+/// have an empty path.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Diff {
+    /// Selections and variable definitions present only in the second operation
+    pub added: Vec<String>,
+    /// Selections and variable definitions present only in the first operation
+    pub removed: Vec<String>,
+    /// Selections and variable definitions present in both operations that differ
+    pub changed: Vec<Change>,
+}
+
+/// A selection or variable definition present in both operations of a [`Diff`] that differs
+/// between them, other than in its own selection set
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub path: String,
+    pub reason: String,
+}
+
+impl Diff {
+    /// Returns true if there are no differences
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    fn change(&mut self, path: &str, reason: String) {
+        self.changed.push(Change {
+            path: path.to_string(),
+            reason,
+        });
+    }
+}
+
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for path in &self.added {
+            writeln!(f, "+ {}", path)?;
+        }
+        for path in &self.removed {
+            writeln!(f, "- {}", path)?;
+        }
+        for change in &self.changed {
+            let path = if change.path.is_empty() {
+                "(operation)"
+            } else {
+                change.path.as_str()
+            };
+            writeln!(f, "~ {}: {}", path, change.reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// List the selections and variable definitions that were added, removed or changed going from
+/// operation `a` to operation `b`. Order is ignored, and selections of the same response key are
+/// merged, as GraphQL does when executing an operation.
+///
+/// # Examples
+/// ```
+/// use graphql_parser::query::{parse_query, Definition};
+/// use partition_operation::comparisions::diff;
+///
+/// let a = parse_query::<&str>("{ team { name, abbrev } }").unwrap();
+/// let b = parse_query::<&str>("{ team { name, logo(size: 64) } }").unwrap();
+/// let diff = match (&a.definitions[0], &b.definitions[0]) {
+///     (Definition::Operation(a), Definition::Operation(b)) => diff(a, b),
+///     _ => unreachable!(),
+/// };
+/// assert_eq!(diff.added, vec!["team.logo"]);
+/// assert_eq!(diff.removed, vec!["team.abbrev"]);
+/// assert!(diff.changed.is_empty());
+/// ```
+pub fn diff<'a, T: Text<'a>>(
+    a: &OperationDefinition<'a, T>,
+    b: &OperationDefinition<'a, T>,
+) -> Diff {
+    let (a, b) = (OperationParts::from(a), OperationParts::from(b));
+    let mut diff = Diff::default();
+    if a.kind != b.kind {
+        diff.change("", format!("operation type {} != {}", a.kind, b.kind));
+    }
+    if a.name != b.name {
+        diff.change(
+            "",
+            format!(
+                "operation name {} != {}",
+                a.name.map_or("None", AsRef::as_ref),
+                b.name.map_or("None", AsRef::as_ref)
+            ),
+        );
+    }
+    let (a_directives, b_directives) = (
+        directive_signatures(a.directives.iter()),
+        directive_signatures(b.directives.iter()),
+    );
+    if a_directives != b_directives {
+        diff.change(
+            "",
+            format!(
+                "directives {} != {}",
+                print_directives(&a_directives),
+                print_directives(&b_directives)
+            ),
+        );
+    }
+    diff_variable_definitions(a.variable_definitions, b.variable_definitions, &mut diff);
+    diff_selection_sets(&[a.selection_set], &[b.selection_set], "", &mut diff);
+    diff
+}
+
+/// Diff the operation of a document against the union of the operations of its partitions, as
+/// [`diff`] does. An empty diff means that the partitions together select exactly what the
+/// operation selects. Fragment spreads are expanded and redundant inline fragments collapsed in
+/// every document first, since partitioning or [`crate::normalize::inline_fragments`] may have
+/// done so. Every document must contain exactly one operation.
+///
+/// # Examples
+/// ```
+/// use graphql_parser::parse_query;
+/// use partition_operation::{comparisions::diff_partitions, Partition};
+///
+/// let document = parse_query::<&str>(
+///     "{ team { ...TeamFields } } fragment TeamFields on Team { name, abbrev }",
+/// )
+/// .unwrap();
+/// let (left, right) = document.clone().partition_by_path("team.abbrev").unwrap();
+/// assert!(diff_partitions(&document, &[left.clone(), right]).unwrap().is_empty());
+/// let diff = diff_partitions(&document, &[left]).unwrap();
+/// assert_eq!(diff.removed, vec!["team.name[on Team]"]);
+/// ```
+pub fn diff_partitions<'a, T: Text<'a> + Clone>(
+    document: &Document<'a, T>,
+    partitions: &[Document<'a, T>],
+) -> Result<Diff, PartitionError> {
+    let operation = expanded_operation(document)?;
+    let union = partitions
+        .iter()
+        .map(expanded_operation)
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .reduce(|union, partition| union.union(&partition))
+        .unwrap_or_else(|| operation.difference(&operation));
+    Ok(diff(&operation, &union))
+}
+
+/// Return the operation of a document with its fragment spreads expanded and its redundant
+/// inline fragments collapsed, so that it compares equal to the same operation with its fragments
+/// inlined
+fn expanded_operation<'a, T: Text<'a> + Clone>(
+    document: &Document<'a, T>,
+) -> Result<OperationDefinition<'a, T>, PartitionError> {
+    let (mut operation, fragments) = into_operation_and_fragments(document.clone())?;
+    let selection_set = operation_selection_set_mut(&mut operation);
+    *selection_set = expand_fragment_spreads(selection_set, &fragments);
+    collapse_inline_fragments(selection_set);
+    Ok(operation)
+}
+
+fn diff_variable_definitions<'a, T: Text<'a>>(
+    a: &[VariableDefinition<'a, T>],
+    b: &[VariableDefinition<'a, T>],
+    diff: &mut Diff,
+) {
+    for a in a {
+        let path = format!("${}", a.name.as_ref());
+        match b.iter().find(|b| b.name == a.name) {
+            None => diff.removed.push(path),
+            Some(b) => {
+                let (a_type, b_type) = (a.var_type.to_string(), b.var_type.to_string());
+                if a_type != b_type {
+                    diff.change(&path, format!("type {} != {}", a_type, b_type));
+                }
+                let (a_default, b_default) = (
+                    print_default_value(&a.default_value),
+                    print_default_value(&b.default_value),
+                );
+                if a_default != b_default {
+                    diff.change(
+                        &path,
+                        format!("default value {} != {}", a_default, b_default),
+                    );
+                }
+            }
+        }
+    }
+    for b in b {
+        if !a.iter().any(|a| a.name == b.name) {
+            diff.added.push(format!("${}", b.name.as_ref()));
+        }
+    }
+}
+
+/// A field or fragment spread of a selection set, merged with every other selection of the same
+/// path element. Fragments do not add a level to the response, so their contents are flattened
+/// into the selection set they appear in.
+struct Entry<'b, 'a, T: Text<'a>> {
+    key: String,
+    selection: &'b Selection<'a, T>,
+    /// The directives of the selection and of the fragments enclosing it
+    directives: Vec<&'b Directive<'a, T>>,
+    /// The selection sets of every field merged into this entry
+    selection_sets: Vec<&'b SelectionSet<'a, T>>,
+}
+
+fn diff_selection_sets<'b, 'a: 'b, T: Text<'a>>(
+    a: &[&'b SelectionSet<'a, T>],
+    b: &[&'b SelectionSet<'a, T>],
+    path: &str,
+    diff: &mut Diff,
+) {
+    let (a, b) = (flatten(a), flatten(b));
+    let child_path = |key: &str| {
+        if path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", path, key)
+        }
+    };
+    for a in &a {
+        let path = child_path(&a.key);
+        match b.iter().find(|b| b.key == a.key) {
+            None => diff.removed.push(path),
+            Some(b) => {
+                if let (Selection::Field(a_field), Selection::Field(b_field)) =
+                    (a.selection, b.selection)
+                {
+                    if a_field.name != b_field.name {
+                        diff.change(
+                            &path,
+                            format!(
+                                "field {} != {}",
+                                a_field.name.as_ref(),
+                                b_field.name.as_ref()
+                            ),
+                        );
+                    }
+                    let (a_arguments, b_arguments) = (
+                        argument_signatures(&a_field.arguments),
+                        argument_signatures(&b_field.arguments),
+                    );
+                    if a_arguments != b_arguments {
+                        diff.change(
+                            &path,
+                            format!(
+                                "arguments ({}) != ({})",
+                                a_arguments.join(", "),
+                                b_arguments.join(", ")
+                            ),
+                        );
+                    }
+                }
+                let (a_directives, b_directives) = (
+                    directive_signatures(a.directives.iter().copied()),
+                    directive_signatures(b.directives.iter().copied()),
+                );
+                if a_directives != b_directives {
+                    diff.change(
+                        &path,
+                        format!(
+                            "directives {} != {}",
+                            print_directives(&a_directives),
+                            print_directives(&b_directives)
+                        ),
+                    );
+                }
+                diff_selection_sets(&a.selection_sets, &b.selection_sets, &path, diff);
+            }
+        }
+    }
+    for b in &b {
+        if !a.iter().any(|a| a.key == b.key) {
+            diff.added.push(child_path(&b.key));
+        }
+    }
+}
+
+/// Flatten the given selection sets into a list of entries, one per path element
+fn flatten<'b, 'a: 'b, T: Text<'a>>(
+    selection_sets: &[&'b SelectionSet<'a, T>],
+) -> Vec<Entry<'b, 'a, T>> {
+    let mut entries = vec![];
+    for selection_set in selection_sets {
+        flatten_into(selection_set, None, &[], &mut entries);
+    }
+    entries
+}
+
+fn flatten_into<'b, 'a: 'b, T: Text<'a>>(
+    selection_set: &'b SelectionSet<'a, T>,
+    type_condition: Option<&'b str>,
+    fragment_directives: &[&'b Directive<'a, T>],
+    entries: &mut Vec<Entry<'b, 'a, T>>,
+) {
+    for selection in &selection_set.items {
+        let (key, directives, child_selection_set) = match selection {
+            Selection::Field(field) => {
+                let key = field.alias.as_ref().unwrap_or(&field.name).as_ref();
+                let key = match type_condition {
+                    Some(type_name) => format!("{}[on {}]", key, type_name),
+                    None => key.to_string(),
+                };
+                (key, &field.directives, Some(&field.selection_set))
+            }
+            Selection::FragmentSpread(spread) => (
+                format!("...{}", spread.fragment_name.as_ref()),
+                &spread.directives,
+                None,
+            ),
+            Selection::InlineFragment(inline_fragment) => {
+                let type_condition = match &inline_fragment.type_condition {
+                    Some(TypeCondition::On(type_name)) => Some(type_name.as_ref()),
+                    None => type_condition,
+                };
+                let mut directives = fragment_directives.to_vec();
+                directives.extend(&inline_fragment.directives);
+                flatten_into(
+                    &inline_fragment.selection_set,
+                    type_condition,
+                    &directives,
+                    entries,
+                );
+                continue;
+            }
+        };
+        match entries.iter_mut().find(|entry| entry.key == key) {
+            Some(entry) => entry.selection_sets.extend(child_selection_set),
+            None => {
+                let mut all_directives = fragment_directives.to_vec();
+                all_directives.extend(directives);
+                entries.push(Entry {
+                    key,
+                    selection,
+                    directives: all_directives,
+                    selection_sets: child_selection_set.into_iter().collect(),
+                })
+            }
+        }
+    }
+}
+
+fn print_default_value<'a, T: Text<'a>>(value: &Option<Value<'a, T>>) -> String {
+    value
+        .as_ref()
+        .map_or_else(|| "None".to_string(), ToString::to_string)
+}
+
+fn print_directives(signatures: &[String]) -> String {
+    if signatures.is_empty() {
+        "(none)".to_string()
+    } else {
+        signatures.join(" ")
+    }
+}
+
+/// Print arguments in a form that does not depend on their order
+pub(crate) fn argument_signatures<'a, T: Text<'a>>(
+    arguments: &[(T::Value, Value<'a, T>)],
+) -> Vec<String> {
+    let mut signatures = arguments
+        .iter()
+        .map(|(name, value)| format!("{}: {}", name.as_ref(), value))
+        .collect::<Vec<_>>();
+    signatures.sort();
+    signatures
+}
+
+/// Print directives in a form that does not depend on their order or that of their arguments
+pub(crate) fn directive_signatures<'b, 'a: 'b, T: Text<'a>>(
+    directives: impl Iterator<Item = &'b Directive<'a, T>>,
+) -> Vec<String> {
+    let mut signatures = directives
+        .map(|directive| {
+            let arguments = argument_signatures(&directive.arguments);
+            if arguments.is_empty() {
+                format!("@{}", directive.name.as_ref())
+            } else {
+                format!("@{}({})", directive.name.as_ref(), arguments.join(", "))
+            }
+        })
+        .collect::<Vec<_>>();
+    signatures.sort();
+    signatures
+}
+
+/// Compare two queries, ignoring the order of selections, arguments and variable definitions.
+/// Returns whether they match and, if not, the reason why.
+pub fn compare_queries<'a, 'b, T: Text<'a>>(
+    expected: &'b Query<'a, T>,
+    got: &'b Query<'a, T>,
 ) -> (bool, Option<String>) {
     // println!("Compare {} and {}", expected, got);
     if expected.name != got.name {
@@ -25,8 +536,8 @@ pub(crate) fn compare_queries<'a, 'b>(
             false,
             Some(format!(
                 "Query names do not match. \"{}\" != \"{}\"",
-                expected.name.unwrap_or("None"),
-                got.name.unwrap_or("None")
+                expected.name.as_ref().map_or("None", AsRef::as_ref),
+                got.name.as_ref().map_or("None", AsRef::as_ref)
             )),
         );
     }
@@ -50,9 +561,9 @@ pub(crate) fn compare_queries<'a, 'b>(
     compare_selection_sets(&expected.selection_set, &got.selection_set)
 }
 
-fn compare_variable_definition_vecs<'a>(
-    expected: &[VariableDefinition<'a, &'a str>],
-    got: &[VariableDefinition<'a, &'a str>],
+fn compare_variable_definition_vecs<'a, T: Text<'a>>(
+    expected: &[VariableDefinition<'a, T>],
+    got: &[VariableDefinition<'a, T>],
 ) -> (bool, Option<String>) {
     if expected.len() != got.len() {
         return (
@@ -80,43 +591,55 @@ fn compare_variable_definition_vecs<'a>(
     (all_match, last_failure_reason)
 }
 
-fn compare_variable_definitions<'a, 'b>(
-    expected: &'b VariableDefinition<'a, &'a str>,
-    got: &'b VariableDefinition<'a, &'a str>,
+fn compare_variable_definitions<'a, 'b, T: Text<'a>>(
+    expected: &'b VariableDefinition<'a, T>,
+    got: &'b VariableDefinition<'a, T>,
 ) -> (bool, Option<String>) {
     if expected.name != got.name {
         return (
             false,
             Some(format!(
                 "Variable names do not match. \"{}\" != \"{}\"",
-                expected.name, got.name
+                expected.name.as_ref(),
+                got.name.as_ref()
             )),
         );
     }
-    if expected.default_value != got.default_value {
+    let (expected_default, got_default) = (
+        print_default_value(&expected.default_value),
+        print_default_value(&got.default_value),
+    );
+    if expected_default != got_default {
         return (
             false,
             Some(format!(
-                "Default values do not match for variable \"{}\". {:?} != {:?}",
-                expected.name, expected.default_value, got.default_value
+                "Default values do not match for variable \"{}\". {} != {}",
+                expected.name.as_ref(),
+                expected_default,
+                got_default
             )),
         );
     }
-    if expected.var_type != got.var_type {
+    let (expected_type, got_type) = (expected.var_type.to_string(), got.var_type.to_string());
+    if expected_type != got_type {
         return (
             false,
             Some(format!(
-                "Types do not match for variable \"{}\". {:?} != {:?}",
-                expected.name, expected.var_type, got.var_type
+                "Types do not match for variable \"{}\". {} != {}",
+                expected.name.as_ref(),
+                expected_type,
+                got_type
             )),
         );
     }
     (true, None)
 }
 
-pub(crate) fn compare_selection_sets<'a, 'b>(
-    expected: &'b SelectionSet<'a, &'a str>,
-    got: &'b SelectionSet<'a, &'a str>,
+/// Compare two selection sets, ignoring the order of selections and arguments. Returns whether
+/// they match and, if not, the reason why.
+pub fn compare_selection_sets<'a, 'b, T: Text<'a>>(
+    expected: &'b SelectionSet<'a, T>,
+    got: &'b SelectionSet<'a, T>,
 ) -> (bool, Option<String>) {
     if expected.items.len() != got.items.len() {
         return (
@@ -142,9 +665,9 @@ pub(crate) fn compare_selection_sets<'a, 'b>(
     compare_field_vecs(expected.fields(), got.fields())
 }
 
-pub(crate) fn compare_inline_fragments<'a, 'b>(
-    expected: Vec<&'b InlineFragment<'a, &'a str>>,
-    got: Vec<&'b InlineFragment<'a, &'a str>>,
+pub fn compare_inline_fragments<'a, 'b, T: Text<'a>>(
+    expected: Vec<&'b InlineFragment<'a, T>>,
+    got: Vec<&'b InlineFragment<'a, T>>,
 ) -> (bool, Option<String>) {
     if expected.len() != got.len() {
         return (
@@ -156,15 +679,61 @@ pub(crate) fn compare_inline_fragments<'a, 'b>(
             )),
         );
     }
-    if !expected.is_empty() {
-        todo!("Handle inline fragments")
+
+    let mut last_failure_reason: Option<String> = None;
+
+    // This is not the most efficient way of doing this
+    let inline_fragments_match = expected.iter().all(|expected| {
+        got.iter().any(|got| {
+            let (inline_fragment_matches, failure_reason) = compare_inline_fragment(expected, got);
+            last_failure_reason = failure_reason;
+            inline_fragment_matches
+        })
+    });
+    (inline_fragments_match, last_failure_reason)
+}
+
+fn compare_inline_fragment<'a, 'b, T: Text<'a>>(
+    expected: &'b InlineFragment<'a, T>,
+    got: &'b InlineFragment<'a, T>,
+) -> (bool, Option<String>) {
+    let print = |type_condition: &Option<TypeCondition<'a, T>>| {
+        type_condition
+            .as_ref()
+            .map_or_else(|| "None".to_string(), ToString::to_string)
+    };
+    let (expected_type_condition, got_type_condition) =
+        (print(&expected.type_condition), print(&got.type_condition));
+    if expected_type_condition != got_type_condition {
+        return (
+            false,
+            Some(format!(
+                "Inline fragment type conditions do not match. {} != {}",
+                expected_type_condition, got_type_condition
+            )),
+        );
     }
-    (true, None)
+
+    let (directives_match, failure_reason) =
+        compare_directive_vecs(&expected.directives, &got.directives);
+    if !directives_match {
+        return (
+            false,
+            failure_reason.map(|reason| format!("Directives do not match: {}", reason.as_str())),
+        );
+    }
+
+    let (selection_sets_match, failure_reason) =
+        compare_selection_sets(&expected.selection_set, &got.selection_set);
+    (
+        selection_sets_match,
+        failure_reason.map(|s| format!("Selection sets do not match: {}", s.as_str())),
+    )
 }
 
-pub(crate) fn compare_fragment_spread_vecs<'a, 'b>(
-    expected: Vec<&'b FragmentSpread<'a, &'a str>>,
-    got: Vec<&'b FragmentSpread<'a, &'a str>>,
+pub fn compare_fragment_spread_vecs<'a, 'b, T: Text<'a>>(
+    expected: Vec<&'b FragmentSpread<'a, T>>,
+    got: Vec<&'b FragmentSpread<'a, T>>,
 ) -> (bool, Option<String>) {
     if expected.len() != got.len() {
         return (
@@ -183,7 +752,7 @@ pub(crate) fn compare_fragment_spread_vecs<'a, 'b>(
             if fragment_spread_a.fragment_name != fragment_spread_b.fragment_name {
                 last_failure_reason = Some(format!(
                     "Fragment spread \"{}\" missing",
-                    fragment_spread_a.fragment_name
+                    fragment_spread_a.fragment_name.as_ref()
                 ));
                 false
             } else {
@@ -194,9 +763,9 @@ pub(crate) fn compare_fragment_spread_vecs<'a, 'b>(
     (fragment_spreads_match, last_failure_reason)
 }
 
-pub(crate) fn compare_field_vecs<'a, 'b>(
-    expected: Vec<&'b Field<'a, &'a str>>,
-    got: Vec<&'b Field<'a, &'a str>>,
+pub fn compare_field_vecs<'a, 'b, T: Text<'a>>(
+    expected: Vec<&'b Field<'a, T>>,
+    got: Vec<&'b Field<'a, T>>,
 ) -> (bool, Option<String>) {
     if expected.len() != got.len() {
         return (
@@ -221,16 +790,19 @@ pub(crate) fn compare_field_vecs<'a, 'b>(
     (fields_match, last_failure_reason)
 }
 
-pub(crate) fn compare_fields<'a, 'b>(
-    expected: &'b Field<'a, &'a str>,
-    got: &'b Field<'a, &'a str>,
+/// Compare two fields, ignoring the order of selections and arguments. Returns whether they
+/// match and, if not, the reason why.
+pub fn compare_fields<'a, 'b, T: Text<'a>>(
+    expected: &'b Field<'a, T>,
+    got: &'b Field<'a, T>,
 ) -> (bool, Option<String>) {
     if expected.name != got.name {
         return (
             false,
             Some(format!(
                 "Field names do not match. \"{}\" != \"{}\"",
-                expected.name, got.name
+                expected.name.as_ref(),
+                got.name.as_ref()
             )),
         );
     }
@@ -240,8 +812,8 @@ pub(crate) fn compare_fields<'a, 'b>(
             false,
             Some(format!(
                 "Field aliases do not match. \"{}\" != \"{}\"",
-                expected.alias.unwrap_or(""),
-                got.alias.unwrap_or("")
+                expected.alias.as_ref().map_or("", AsRef::as_ref),
+                got.alias.as_ref().map_or("", AsRef::as_ref)
             )),
         );
     }
@@ -268,9 +840,9 @@ pub(crate) fn compare_fields<'a, 'b>(
     )
 }
 
-fn compare_directive_vecs<'a>(
-    expected: &[Directive<'a, &'a str>],
-    got: &[Directive<'a, &'a str>],
+fn compare_directive_vecs<'a, T: Text<'a>>(
+    expected: &[Directive<'a, T>],
+    got: &[Directive<'a, T>],
 ) -> (bool, Option<String>) {
     if expected.len() != got.len() {
         return (
@@ -295,13 +867,13 @@ fn compare_directive_vecs<'a>(
     (vecs_match, last_failure_reason)
 }
 
-fn compare_argument_vecs<'a>(
-    expected: &[(&'a str, Value<'a, &'a str>)],
-    got: &[(&'a str, Value<'a, &'a str>)],
+fn compare_argument_vecs<'a, T: Text<'a>>(
+    expected: &[(T::Value, Value<'a, T>)],
+    got: &[(T::Value, Value<'a, T>)],
 ) -> (bool, Option<String>) {
     let mut got_map = HashMap::new();
     for (key, val) in got {
-        got_map.insert(key, val);
+        got_map.insert(key.as_ref(), val);
     }
 
     if expected.len() != got.len() {
@@ -318,12 +890,14 @@ fn compare_argument_vecs<'a>(
     let mut failure_reason: Option<String> = None;
     let args_match = expected
         .iter()
-        .all(|(key, expected)| match got_map.get(key) {
+        .all(|(key, expected)| match got_map.get(key.as_ref()) {
             Some(got) => {
-                if *got != expected {
+                if got.to_string() != expected.to_string() {
                     failure_reason = Some(format!(
                         "Values for argument \"{}\" do not match. {} != {}",
-                        key, expected, got
+                        key.as_ref(),
+                        expected,
+                        got
                     ));
                     false
                 } else {
@@ -333,7 +907,7 @@ fn compare_argument_vecs<'a>(
             None => {
                 failure_reason = Some(format!(
                     "Expected argument to be present for key \"{}\", but none was found",
-                    key
+                    key.as_ref()
                 ));
                 false
             }
@@ -341,16 +915,17 @@ fn compare_argument_vecs<'a>(
     (args_match, failure_reason)
 }
 
-fn compare_directives<'a, 'b>(
-    expected: &'b Directive<'a, &'a str>,
-    got: &'b Directive<'a, &'a str>,
+fn compare_directives<'a, 'b, T: Text<'a>>(
+    expected: &'b Directive<'a, T>,
+    got: &'b Directive<'a, T>,
 ) -> (bool, Option<String>) {
     if expected.name != got.name {
         return (
             false,
             Some(format!(
                 "Directive names do not match. \"{}\" != \"{}\"",
-                expected.name, got.name
+                expected.name.as_ref(),
+                got.name.as_ref()
             )),
         );
     }
@@ -360,7 +935,7 @@ fn compare_directives<'a, 'b>(
         failure_reason.map(|reason| {
             format!(
                 "Argument mismatch for directive \"{}\": {}",
-                expected.name,
+                expected.name.as_ref(),
                 reason.as_str()
             )
         }),
@@ -379,7 +954,7 @@ mod tests {
         Pos,
     };
 
-    use super::compare_queries;
+    use super::{compare_queries, diff, semantically_equal, Change};
     // https://stackoverflow.com/a/69324393/132319
     macro_rules! cast {
         ($target: expr, $pat: path) => {{
@@ -407,8 +982,8 @@ mod tests {
             }
         }"#;
         let q2 = r#"{ myQuery { alpha, beta { one, two }, gamma { ...fsOne } } }"#;
-        let op1 = parse_query(q1).unwrap().operations().pop().unwrap();
-        let op2 = parse_query(q2).unwrap().operations().pop().unwrap();
+        let op1 = parse_query::<&str>(q1).unwrap().operations().pop().unwrap();
+        let op2 = parse_query::<&str>(q2).unwrap().operations().pop().unwrap();
         let ss1 = match op1 {
             OperationDefinition::Query(query) => query.selection_set,
             OperationDefinition::SelectionSet(ss) => ss,
@@ -442,8 +1017,8 @@ mod tests {
             }
         }"#;
         let q2 = r#"{ myQuery { alpha, beta { one, two }, gamma { ...fsOne } } }"#;
-        let op1 = parse_query(q1).unwrap().operations().pop().unwrap();
-        let op2 = parse_query(q2).unwrap().operations().pop().unwrap();
+        let op1 = parse_query::<&str>(q1).unwrap().operations().pop().unwrap();
+        let op2 = parse_query::<&str>(q2).unwrap().operations().pop().unwrap();
         let ss1 = match op1 {
             OperationDefinition::Query(query) => query.selection_set,
             OperationDefinition::SelectionSet(ss) => ss,
@@ -477,8 +1052,8 @@ mod tests {
             }
         }"#;
         let q2 = r#"{ myQuery { alpha, iota { one, two }, gamma { ...fsOne } } }"#;
-        let op1 = parse_query(q1).unwrap().operations().pop().unwrap();
-        let op2 = parse_query(q2).unwrap().operations().pop().unwrap();
+        let op1 = parse_query::<&str>(q1).unwrap().operations().pop().unwrap();
+        let op2 = parse_query::<&str>(q2).unwrap().operations().pop().unwrap();
         let ss1 = match op1 {
             OperationDefinition::Query(query) => query.selection_set,
             OperationDefinition::SelectionSet(ss) => ss,
@@ -603,7 +1178,7 @@ mod tests {
     fn fields_with_same_name_and_same_arguments_match() -> Result<()> {
         let f1 = cast!(
             cast!(
-                parse_query("{ field(arg1: $arg1, arg2: $arg2) }")?
+                parse_query::<&str>("{ field(arg1: $arg1, arg2: $arg2) }")?
                     .operations()
                     .pop()
                     .unwrap(),
@@ -616,7 +1191,7 @@ mod tests {
         );
         let f2 = cast!(
             cast!(
-                parse_query("{ field(arg2: $arg2, arg1: $arg1) }")?
+                parse_query::<&str>("{ field(arg2: $arg2, arg1: $arg1) }")?
                     .operations()
                     .pop()
                     .unwrap(),
@@ -642,7 +1217,7 @@ mod tests {
     fn fields_with_same_name_and_different_arguments_do_not_match() -> Result<()> {
         let f1 = cast!(
             cast!(
-                parse_query("{ field(arg1: $arg1, arg2: $arg2) }")?
+                parse_query::<&str>("{ field(arg1: $arg1, arg2: $arg2) }")?
                     .operations()
                     .pop()
                     .unwrap(),
@@ -655,7 +1230,7 @@ mod tests {
         );
         let f2 = cast!(
             cast!(
-                parse_query("{ field(arg2: $arg2, arg3: $arg1) }")?
+                parse_query::<&str>("{ field(arg2: $arg2, arg3: $arg1) }")?
                     .operations()
                     .pop()
                     .unwrap(),
@@ -754,4 +1329,78 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn selection_set_equals_anonymous_query() -> Result<()> {
+        let a = parse_query::<&str>("{ alpha }")?
+            .operations()
+            .pop()
+            .unwrap();
+        let b = parse_query::<&str>("query { alpha }")?
+            .operations()
+            .pop()
+            .unwrap();
+        let c = parse_query::<&str>("mutation { alpha }")?
+            .operations()
+            .pop()
+            .unwrap();
+        assert!(semantically_equal(&a, &b));
+        assert!(!semantically_equal(&a, &c));
+        Ok(())
+    }
+
+    #[test]
+    fn diff_lists_changes_by_path() -> Result<()> {
+        let a = parse_query::<&str>(
+            r#"query Q($id: ID!, $size: Int) {
+                team(id: $id) { name, logo(size: $size), ... on Team { abbrev } }
+            }"#,
+        )?
+        .operations()
+        .pop()
+        .unwrap();
+        let b = parse_query::<&str>(
+            r#"query Q($id: ID, $first: Int) {
+                team(id: $id) { name @include(if: true), logo(size: 64), ...Coach }
+            }"#,
+        )?
+        .operations()
+        .pop()
+        .unwrap();
+        let diff = diff(&a, &b);
+        assert_eq!(diff.added, vec!["$first", "team....Coach"]);
+        assert_eq!(diff.removed, vec!["$size", "team.abbrev[on Team]"]);
+        assert_eq!(
+            diff.changed,
+            vec![
+                Change {
+                    path: "$id".to_string(),
+                    reason: "type ID! != ID".to_string()
+                },
+                Change {
+                    path: "team.name".to_string(),
+                    reason: "directives (none) != @include(if: true)".to_string()
+                },
+                Change {
+                    path: "team.logo".to_string(),
+                    reason: "arguments (size: $size) != (size: 64)".to_string()
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn diff_merges_selections_of_the_same_response_key() -> Result<()> {
+        let a = parse_query::<&str>("{ team { name }, team { abbrev } }")?
+            .operations()
+            .pop()
+            .unwrap();
+        let b = parse_query::<&str>("{ team { abbrev, name } }")?
+            .operations()
+            .pop()
+            .unwrap();
+        assert!(diff(&a, &b).is_empty(), "{}", diff(&a, &b));
+        Ok(())
+    }
 }
@@ -0,0 +1,70 @@
+// Copyright 2024 Aurelia Peters
+//
+// This file is part of GraphQL Operation Partitioner.
+// 
+// GraphQL Operation Partitioner is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// 
+// GraphQL Operation Partitioner is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License along with GraphQL Cacher. If not, see <https://www.gnu.org/licenses/>. 
+//! The error type returned when an operation cannot be partitioned
+use std::fmt;
+
+/// The reasons partitioning can fail. Callers can choose how to proceed for each of them, e.g. by
+/// sending the operation unpartitioned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionError {
+    /// The path contains an element that is not valid. See "Query Path Syntax" in README.md
+    InvalidPathElement { element: String },
+    /// Only queries can be partitioned. Mutations and subscriptions must not be split into several
+    /// requests, since each request would perform the operation again.
+    UnsupportedOperationType { operation_type: String },
+    /// A document must contain exactly one operation to be partitioned
+    OperationCount { count: usize },
+    /// Nothing in the operation matched the path. When partitioning by any of several paths, `path`
+    /// lists them all, separated by commas.
+    PathNotFound { path: String },
+    /// The path matched everything in the operation, so nothing would be left for the other side.
+    /// When partitioning by any of several paths, `path` lists them all, separated by commas.
+    EmptyResult { path: String },
+    /// A fragment spread could not be replaced by the fragment's selections, because the fragment
+    /// is not defined or spreads itself
+    UnresolvedFragment { name: String },
+}
+
+impl fmt::Display for PartitionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PartitionError::InvalidPathElement { element } => {
+                write!(f, "Invalid element in path: \"{}\"", element)
+            }
+            PartitionError::UnsupportedOperationType { operation_type } => {
+                write!(
+                    f,
+                    "Cannot partition an operation of type {}",
+                    operation_type
+                )
+            }
+            PartitionError::OperationCount { count } => write!(
+                f,
+                "Expected exactly one operation in document, found {}",
+                count
+            ),
+            PartitionError::PathNotFound { path } => {
+                write!(f, "Path \"{}\" not found in operation", path)
+            }
+            PartitionError::EmptyResult { path } => write!(
+                f,
+                "Path \"{}\" matched the whole operation, leaving nothing behind",
+                path
+            ),
+            PartitionError::UnresolvedFragment { name } => write!(
+                f,
+                "Fragment \"{}\" is not defined or spreads itself, so it cannot be inlined",
+                name
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PartitionError {}
@@ -8,19 +8,18 @@
 // 
 // You should have received a copy of the GNU General Public License along with GraphQL Cacher. If not, see <https://www.gnu.org/licenses/>. 
 //! Provides views into a GraphQL selection set of fields, fragment spreads, and inline fragments
+use graphql_parser::query::{Field, FragmentSpread, InlineFragment, Selection, SelectionSet, Text};
 use itertools::Itertools;
-use graphql_parser::query::{Field, FragmentSpread, InlineFragment, Text, Selection, SelectionSet};
 pub(crate) trait FieldsAndFragments<'a, T: Text<'a>> {
     fn fields(&self) -> Vec<&Field<'a, T>>;
     fn fragment_spreads(&self) -> Vec<&FragmentSpread<'a, T>>;
     fn inline_fragments(&self) -> Vec<&InlineFragment<'a, T>>;
 }
 
-impl<'a> FieldsAndFragments<'a, &'a str> for SelectionSet<'a, &'a str> {
-
-    /// Return a list of references to all items in this Selection Set which 
+impl<'a, T: Text<'a>> FieldsAndFragments<'a, T> for SelectionSet<'a, T> {
+    /// Return a list of references to all items in this Selection Set which
     /// are Fields (https://spec.graphql.org/June2018/#sec-Language.Fields)
-    fn fields(&self) -> Vec<&Field<'a, &'a str>> {
+    fn fields(&self) -> Vec<&Field<'a, T>> {
         self.items
             .iter()
             .filter_map(|selection| match selection {
@@ -30,9 +29,9 @@ impl<'a> FieldsAndFragments<'a, &'a str> for SelectionSet<'a, &'a str> {
             .collect_vec()
     }
 
-    /// Return a list of references to all items in this Selection Set which 
+    /// Return a list of references to all items in this Selection Set which
     /// are Fragment Spreads (https://spec.graphql.org/June2018/#sec-Fragment-Spreads)
-    fn fragment_spreads(&self) -> Vec<&FragmentSpread<'a, &'a str>> {
+    fn fragment_spreads(&self) -> Vec<&FragmentSpread<'a, T>> {
         self.items
             .iter()
             .filter_map(|selection| match selection {
@@ -42,9 +41,9 @@ impl<'a> FieldsAndFragments<'a, &'a str> for SelectionSet<'a, &'a str> {
             .collect_vec()
     }
 
-    /// Return a list of references to all items in this Selection Set which 
+    /// Return a list of references to all items in this Selection Set which
     /// are Inline Fragments (https://spec.graphql.org/June2018/#sec-Inline-Fragments)
-    fn inline_fragments(&self) -> Vec<&InlineFragment<'a, &'a str>> {
+    fn inline_fragments(&self) -> Vec<&InlineFragment<'a, T>> {
         self.items
             .iter()
             .filter_map(|selection| match selection {
@@ -54,4 +53,3 @@ impl<'a> FieldsAndFragments<'a, &'a str> for SelectionSet<'a, &'a str> {
             .collect_vec()
     }
 }
-
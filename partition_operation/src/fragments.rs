@@ -0,0 +1,221 @@
+// Copyright 2024 Aurelia Peters
+//
+// This file is part of GraphQL Operation Partitioner.
+// 
+// GraphQL Operation Partitioner is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// 
+// GraphQL Operation Partitioner is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License along with GraphQL Cacher. If not, see <https://www.gnu.org/licenses/>. 
+//! Helpers for working with the fragment definitions of a GraphQL document
+use std::collections::HashSet;
+
+use graphql_parser::query::{
+    Field, FragmentDefinition, InlineFragment, Selection, SelectionSet, Text,
+};
+
+use crate::fields_and_fragments::FieldsAndFragments;
+
+/// Return the fragment definitions referenced by the given selection set, either directly or
+/// through other fragments. Definitions are returned in the order in which they appear in
+/// `fragments`; spreads of fragments that are not defined are ignored.
+pub(crate) fn referenced_fragments<'a, T: Text<'a> + Clone>(
+    selection_set: &SelectionSet<'a, T>,
+    fragments: &[FragmentDefinition<'a, T>],
+) -> Vec<FragmentDefinition<'a, T>> {
+    let mut seen = HashSet::new();
+    let mut pending = Vec::new();
+    collect_fragment_spreads(selection_set, &mut pending);
+
+    while let Some(name) = pending.pop() {
+        if seen.insert(name) {
+            if let Some(fragment) = fragments.iter().find(|f| f.name.as_ref() == name) {
+                collect_fragment_spreads(&fragment.selection_set, &mut pending);
+            }
+        }
+    }
+
+    fragments
+        .iter()
+        .filter(|f| seen.contains(f.name.as_ref()))
+        .cloned()
+        .collect()
+}
+
+/// Push the name of every fragment spread in the given selection set (at any depth) onto `names`
+fn collect_fragment_spreads<'s, 'a, T: Text<'a>>(
+    selection_set: &'s SelectionSet<'a, T>,
+    names: &mut Vec<&'s str>,
+) {
+    names.extend(
+        selection_set
+            .fragment_spreads()
+            .into_iter()
+            .map(|spread| spread.fragment_name.as_ref()),
+    );
+    for field in selection_set.fields() {
+        collect_fragment_spreads(&field.selection_set, names);
+    }
+    for inline_fragment in selection_set.inline_fragments() {
+        collect_fragment_spreads(&inline_fragment.selection_set, names);
+    }
+}
+
+/// Return a copy of the selection set in which every spread of one of the given fragments is
+/// replaced by an inline fragment with the fragment's type condition and selection set, and the
+/// directives of the spread followed by those of the fragment definition, so that client
+/// directives such as `@edgePrivate` on the definition still apply. Spreads of fragments that are
+/// not defined, or that would expand into themselves, are kept as they are.
+pub(crate) fn expand_fragment_spreads<'a, T: Text<'a> + Clone>(
+    selection_set: &SelectionSet<'a, T>,
+    fragments: &[FragmentDefinition<'a, T>],
+) -> SelectionSet<'a, T> {
+    expand_selection_set(selection_set, fragments, &mut vec![])
+}
+
+/// Return the name of a fragment that is among the given fragment definitions, but is still spread
+/// in a selection set whose spreads were expanded by [`expand_fragment_spreads`], i.e. one that
+/// spreads itself
+pub(crate) fn self_spreading_fragment<'a, T: Text<'a>>(
+    selection_set: &SelectionSet<'a, T>,
+    fragments: &[FragmentDefinition<'a, T>],
+) -> Option<String> {
+    let mut names = vec![];
+    collect_fragment_spreads(selection_set, &mut names);
+    names
+        .into_iter()
+        .find(|name| fragments.iter().any(|f| f.name.as_ref() == *name))
+        .map(str::to_string)
+}
+
+/// Expand the fragment spreads in a selection set. `expanding` holds the names of the fragments
+/// the selection set is within.
+fn expand_selection_set<'a, T: Text<'a> + Clone>(
+    selection_set: &SelectionSet<'a, T>,
+    fragments: &[FragmentDefinition<'a, T>],
+    expanding: &mut Vec<String>,
+) -> SelectionSet<'a, T> {
+    let items = selection_set
+        .items
+        .iter()
+        .map(|selection| match selection {
+            Selection::Field(field) => Selection::Field(Field {
+                selection_set: expand_selection_set(&field.selection_set, fragments, expanding),
+                ..field.clone()
+            }),
+            Selection::InlineFragment(inline_fragment) => {
+                Selection::InlineFragment(InlineFragment {
+                    selection_set: expand_selection_set(
+                        &inline_fragment.selection_set,
+                        fragments,
+                        expanding,
+                    ),
+                    ..inline_fragment.clone()
+                })
+            }
+            Selection::FragmentSpread(spread) => {
+                let name = spread.fragment_name.as_ref();
+                match fragments.iter().find(|f| f.name.as_ref() == name) {
+                    Some(fragment) if !expanding.iter().any(|n| n == name) => {
+                        expanding.push(name.to_string());
+                        let selection_set =
+                            expand_selection_set(&fragment.selection_set, fragments, expanding);
+                        expanding.pop();
+                        Selection::InlineFragment(InlineFragment {
+                            position: spread.position,
+                            type_condition: Some(fragment.type_condition.clone()),
+                            directives: spread
+                                .directives
+                                .iter()
+                                .chain(&fragment.directives)
+                                .cloned()
+                                .collect(),
+                            selection_set,
+                        })
+                    }
+                    _ => selection.clone(),
+                }
+            }
+        })
+        .collect();
+    SelectionSet {
+        span: selection_set.span,
+        items,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expand_fragment_spreads, referenced_fragments};
+    use graphql_parser::query::{
+        parse_query, Definition, Document, FragmentDefinition, OperationDefinition, SelectionSet,
+    };
+
+    #[test]
+    fn referenced_fragments_follows_nested_spreads() {
+        let doc = parse_query::<&str>(
+            r#"{ myQuery { ...One, ... on Thing { ...Two } } }
+            fragment One on Thing { id }
+            fragment Two on Thing { ...Three }
+            fragment Three on Thing { name }
+            fragment Unused on Thing { id }"#,
+        )
+        .unwrap();
+        let mut selection_set = None;
+        let mut fragments = vec![];
+        for def in doc.definitions {
+            match def {
+                Definition::Operation(OperationDefinition::SelectionSet(ss)) => {
+                    selection_set = Some(ss)
+                }
+                Definition::Fragment(fragment) => fragments.push(fragment),
+                _ => unreachable!(),
+            }
+        }
+        let names = referenced_fragments(&selection_set.unwrap(), &fragments)
+            .into_iter()
+            .map(|f| f.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["One", "Two", "Three"]);
+    }
+
+    #[test]
+    fn expand_fragment_spreads_inlines_nested_fragments() {
+        let doc = parse_query::<&str>(
+            r#"{ myQuery { ...One @include(if: true), ...Missing } }
+            fragment One on Thing { id, ...Two }
+            fragment Two on Thing { name, ...One }"#,
+        )
+        .unwrap();
+        let (selection_set, fragments) = split(doc);
+        let expected = parse_query::<&str>(
+            r#"{ myQuery { ... on Thing @include(if: true) { id, ... on Thing { name, ...One } }, ...Missing } }"#,
+        )
+        .unwrap();
+        let (expected, _) = split(expected);
+        assert_eq!(
+            expected.to_string(),
+            expand_fragment_spreads(&selection_set, &fragments).to_string()
+        );
+    }
+
+    fn split<'a>(
+        doc: Document<'a, &'a str>,
+    ) -> (
+        SelectionSet<'a, &'a str>,
+        Vec<FragmentDefinition<'a, &'a str>>,
+    ) {
+        let mut selection_set = None;
+        let mut fragments = vec![];
+        for def in doc.definitions {
+            match def {
+                Definition::Operation(OperationDefinition::SelectionSet(ss)) => {
+                    selection_set = Some(ss)
+                }
+                Definition::Fragment(fragment) => fragments.push(fragment),
+                _ => unreachable!(),
+            }
+        }
+        (selection_set.unwrap(), fragments)
+    }
+}
@@ -7,36 +7,42 @@
 // GraphQL Operation Partitioner is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
 // 
 // You should have received a copy of the GNU General Public License along with GraphQL Cacher. If not, see <https://www.gnu.org/licenses/>. 
-use anyhow::{Error, Result};
-use graphql_parser::{
-    query::{Definition, Document, Field, OperationDefinition, Selection, SelectionSet, Text},
-    Pos,
+pub use error::PartitionError;
+use fragments::{expand_fragment_spreads, referenced_fragments, self_spreading_fragment};
+use graphql_parser::query::{
+    Definition, Document, Field, FragmentDefinition, FragmentSpread, InlineFragment,
+    OperationDefinition, Selection, SelectionSet, Text,
 };
 use itertools::{Either, Itertools};
-use regex::Regex;
+use path::{validate_path, PathElement};
+use std::convert::TryFrom;
+use variables::prune_variable_definitions;
+use visit::{walk_selection_set_mut, Context, Control, VisitorMut};
 
-#[cfg(test)]
-mod comparisions;
+pub mod algebra;
+pub mod analysis;
+pub mod canonical;
+pub mod client_directives;
+pub mod comparisions;
+mod error;
 mod fields_and_fragments;
+mod fragments;
+pub mod normalize;
+mod path;
+pub mod planner;
+pub mod schema;
+pub mod source_map;
+mod variables;
+pub mod visit;
 
-// https://stackoverflow.com/a/69324393/132319
-macro_rules! cast {
-    ($target: expr, $pat: path) => {{
-        if let $pat(a) = $target {
-            // #1
-            a
-        } else {
-            panic!("mismatch variant when cast to {}", stringify!($pat)); // #2
-        }
-    }};
-}
-
+#[cfg(test)]
 pub(crate) trait Operations<'a, T: Text<'a>> {
     fn operations(self) -> Vec<OperationDefinition<'a, T>>;
 }
 
-impl<'a> Operations<'a, &'a str> for Document<'a, &'a str> {
-    fn operations(self) -> Vec<OperationDefinition<'a, &'a str>> {
+#[cfg(test)]
+impl<'a, T: Text<'a>> Operations<'a, T> for Document<'a, T> {
+    fn operations(self) -> Vec<OperationDefinition<'a, T>> {
         self.definitions
             .into_iter()
             .filter_map(|def| match def {
@@ -47,17 +53,121 @@ impl<'a> Operations<'a, &'a str> for Document<'a, &'a str> {
     }
 }
 
-type OperationDefinitionPartition<'a, T> = (OperationDefinition<'a, T>, OperationDefinition<'a, T>);
+/// The result of partitioning by several paths: one partition per path, followed by whatever is
+/// left over, if anything is.
+pub type Partitions<P> = (Vec<P>, Option<P>);
 
-/// Trait used to partition GraphQL Operations. Note that order is not necessarily preserved in a
-/// given selection set
-pub trait Partition<'a, T: Text<'a>> {
+/// Trait used to partition GraphQL Operations. Both sides of a partition keep the selections of
+/// every selection set in the order of the original operation.
+pub trait Partition<'a, T: Text<'a>>: Sized {
     /// Partition a GraphQL operation by path. See "Query Path Syntax" in README.md
-    fn partition_by_path(self, path: &str) -> Result<Option<OperationDefinitionPartition<'a, T>>>;
+    fn partition_by_path(self, path: &str) -> Result<(Self, Self), PartitionError>;
+
+    /// Partition a GraphQL operation by several paths. Each path is carved out of what remains
+    /// after the paths before it have been removed, so the result holds one partition per path
+    /// (in the order the paths were given) followed by the remainder, which is `None` if the
+    /// paths together match the whole operation. Returns an error if any of the paths cannot be
+    /// partitioned out of the remainder.
+    ///
+    /// # Examples: Partition a query by several paths
+    /// ```
+    /// use partition_operation::Partition;
+    /// use graphql_parser::parse_query;
+    ///
+    /// let query = parse_query::<&str>(
+    ///     "query MyQuery { myQuery { alpha, beta { one, two }, gamma } }",
+    /// )
+    /// .unwrap();
+    /// let op = query
+    ///     .definitions
+    ///     .into_iter()
+    ///     .find_map(|def| match def {
+    ///         graphql_parser::query::Definition::Operation(op_def) => Some(op_def),
+    ///         _ => None,
+    ///     })
+    ///     .unwrap();
+    /// let (partitions, remainder) = op
+    ///     .partition_by_paths(&["myQuery.alpha", "myQuery.beta.two"])
+    ///     .unwrap();
+    /// assert_eq!(partitions.len(), 2);
+    /// assert_eq!("query MyQuery {\n  myQuery {\n    alpha\n  }\n}\n", partitions[0].to_string());
+    /// assert_eq!(
+    ///     "query MyQuery {\n  myQuery {\n    beta {\n      two\n    }\n  }\n}\n",
+    ///     partitions[1].to_string()
+    /// );
+    /// ```
+    fn partition_by_paths(self, paths: &[&str]) -> Result<Partitions<Self>, PartitionError> {
+        let mut partitions = Vec::with_capacity(paths.len());
+        let mut remainder = Some(self);
+        for path in paths {
+            // A path after the one that took everything finds nothing
+            let operation = remainder.ok_or_else(|| PartitionError::PathNotFound {
+                path: path.to_string(),
+            })?;
+            let (left, right) = operation.split_by_any_path(&[path])?;
+            partitions.push(left);
+            remainder = right;
+        }
+        Ok((partitions, remainder))
+    }
+
+    /// Partition a GraphQL operation by several paths at once. Every selection matched by any of
+    /// the paths is collected into the left partition, and everything else goes into the right.
+    /// Paths that match nothing are ignored, but it is an error if none of them match.
+    ///
+    /// # Examples: Partition a query by any of several paths
+    /// ```
+    /// use partition_operation::Partition;
+    /// use graphql_parser::parse_query;
+    ///
+    /// let document =
+    ///     parse_query::<&str>("query MyQuery { myQuery { alpha, beta { one, two }, gamma } }")
+    ///         .unwrap();
+    /// let (left, right) = document
+    ///     .partition_by_any_path(&["myQuery.alpha", "myQuery.beta.two"])
+    ///     .unwrap();
+    /// assert_eq!(
+    ///     "query MyQuery {\n  myQuery {\n    alpha\n    beta {\n      two\n    }\n  }\n}\n",
+    ///     left.to_string()
+    /// );
+    /// assert_eq!(
+    ///     "query MyQuery {\n  myQuery {\n    beta {\n      one\n    }\n    gamma\n  }\n}\n",
+    ///     right.to_string()
+    /// );
+    /// ```
+    fn partition_by_any_path(self, paths: &[&str]) -> Result<(Self, Self), PartitionError> {
+        match self.split_by_any_path(paths)? {
+            (left, Some(right)) => Ok((left, right)),
+            (_, None) => Err(PartitionError::EmptyResult {
+                path: paths.join(", "),
+            }),
+        }
+    }
+
+    /// Partition a GraphQL operation by several paths at once, as
+    /// [`Partition::partition_by_any_path`] does, except that the right side is `None` if the
+    /// paths match the whole operation. Neither side ever holds an empty selection set: fields
+    /// and fragments everything beneath which matched go on the left as a whole.
+    ///
+    /// # Examples: Partition a query by paths that match all of it
+    /// ```
+    /// use partition_operation::Partition;
+    /// use graphql_parser::parse_query;
+    ///
+    /// let document = parse_query::<&str>("{ myQuery { alpha, beta { one } } }").unwrap();
+    /// let (left, right) = document
+    ///     .split_by_any_path(&["myQuery.alpha", "myQuery.beta.one"])
+    ///     .unwrap();
+    /// assert_eq!(
+    ///     "{\n  myQuery {\n    alpha\n    beta {\n      one\n    }\n  }\n}\n",
+    ///     left.to_string()
+    /// );
+    /// assert!(right.is_none());
+    /// ```
+    fn split_by_any_path(self, paths: &[&str]) -> Result<(Self, Option<Self>), PartitionError>;
 }
-// TODO: implement Partition for Document
 
-impl<'a> Partition<'a, &'a str> for OperationDefinition<'a, &'a str> {
+impl<'a, T: Text<'a> + Clone> Partition<'a, T> for OperationDefinition<'a, T> {
     /// # Examples: Partition a query
     /// ```
     /// use partition_operation::Partition;
@@ -77,7 +187,7 @@ impl<'a> Partition<'a, &'a str> for OperationDefinition<'a, &'a str> {
     ///     })
     ///     .collect::<Vec<graphql_parser::query::OperationDefinition<&str>>>();
     /// let op = operations.pop().unwrap();
-    /// let (left, right) = op.partition_by_path(path).unwrap().unwrap();
+    /// let (left, right) = op.partition_by_path(path).unwrap();
     /// println!("{}", left);
     /// println!("{}", right);
     /// dbg!(right.to_string());
@@ -95,164 +205,449 @@ impl<'a> Partition<'a, &'a str> for OperationDefinition<'a, &'a str> {
     /// let expected_right = r#"query MyQuery {
     ///   myQuery {
     ///     alpha
-    ///     gamma
     ///     beta {
     ///       two
     ///     }
+    ///     gamma
     ///   }
     /// }
     /// "#;
     /// assert_eq!(expected_left, left.to_string(), "LEFT");
     /// assert_eq!(expected_right, right.to_string(), "RIGHT");
     /// ```
-    fn partition_by_path(
-        self,
-        path: &str,
-    ) -> Result<Option<OperationDefinitionPartition<'a, &'a str>>> {
-        let elements = validate_path(path)?;
-
-        let partition = match self {
-            OperationDefinition::Query(mut query) => {
-                let mut selection_set = SelectionSet {
-                    span: (Pos::default(), Pos::default()),
-                    items: vec![],
-                };
-                std::mem::swap(&mut query.selection_set, &mut selection_set);
-
-                // NB: selection_set here is the selection set taken from the *query*
-                partition_selection_set_by_path(elements, selection_set).map(|(left, right)| {
-                    let mut q2 = query.clone();
-                    q2.selection_set = right;
-                    query.selection_set = left;
-                    (
-                        OperationDefinition::Query(query),
-                        OperationDefinition::Query(q2),
-                    )
-                })
+    fn partition_by_path(self, path: &str) -> Result<(Self, Self), PartitionError> {
+        self.partition_by_any_path(&[path])
+    }
+
+    fn split_by_any_path(self, paths: &[&str]) -> Result<(Self, Option<Self>), PartitionError> {
+        partition_operation_by_paths(self, paths, &[])
+    }
+}
+
+impl<'a, T: Text<'a> + Clone> Partition<'a, T> for Document<'a, T> {
+    /// Partition the operation in this document by path. The document must contain exactly one
+    /// operation. Each side of the partition is a self-contained document carrying exactly the
+    /// fragment definitions that its operation references.
+    ///
+    /// # Examples: Partition a document
+    /// ```
+    /// use partition_operation::Partition;
+    /// use graphql_parser::parse_query;
+    ///
+    /// let document = parse_query::<&str>(
+    ///     r#"query MyQuery { myQuery { alpha { ...A }, beta { ...B } } }
+    ///     fragment A on Alpha { one }
+    ///     fragment B on Beta { two }"#,
+    /// )
+    /// .unwrap();
+    /// let (left, right) = document.partition_by_path("myQuery.alpha").unwrap();
+    /// let expected_left = r#"query MyQuery {
+    ///   myQuery {
+    ///     alpha {
+    ///       ...A
+    ///     }
+    ///   }
+    /// }
+    ///
+    /// fragment A on Alpha {
+    ///   one
+    /// }
+    /// "#;
+    /// let expected_right = r#"query MyQuery {
+    ///   myQuery {
+    ///     beta {
+    ///       ...B
+    ///     }
+    ///   }
+    /// }
+    ///
+    /// fragment B on Beta {
+    ///   two
+    /// }
+    /// "#;
+    /// assert_eq!(expected_left, left.to_string(), "LEFT");
+    /// assert_eq!(expected_right, right.to_string(), "RIGHT");
+    /// ```
+    fn partition_by_path(self, path: &str) -> Result<(Self, Self), PartitionError> {
+        self.partition_by_any_path(&[path])
+    }
+
+    fn split_by_any_path(self, paths: &[&str]) -> Result<(Self, Option<Self>), PartitionError> {
+        let (operation, fragments) = into_operation_and_fragments(self)?;
+        let (left, right) = partition_operation_by_paths(operation, paths, &fragments)?;
+        Ok((
+            document_with_fragments(left, &fragments),
+            right.map(|right| document_with_fragments(right, &fragments)),
+        ))
+    }
+}
+
+/// Split a document into its operation and its fragment definitions. The document must contain
+/// exactly one operation.
+fn into_operation_and_fragments<'a, T: Text<'a>>(
+    document: Document<'a, T>,
+) -> Result<(OperationDefinition<'a, T>, Vec<FragmentDefinition<'a, T>>), PartitionError> {
+    let (operations, fragments): (Vec<_>, Vec<_>) =
+        document
+            .definitions
+            .into_iter()
+            .partition_map(|def| match def {
+                Definition::Operation(op_def) => Either::Left(op_def),
+                Definition::Fragment(fragment) => Either::Right(fragment),
+            });
+    match <[_; 1]>::try_from(operations) {
+        Ok([operation]) => Ok((operation, fragments)),
+        Err(operations) => Err(PartitionError::OperationCount {
+            count: operations.len(),
+        }),
+    }
+}
+
+/// Build a document from the given operation and those of the given fragment definitions that it
+/// references
+fn document_with_fragments<'a, T: Text<'a> + Clone>(
+    operation: OperationDefinition<'a, T>,
+    fragments: &[FragmentDefinition<'a, T>],
+) -> Document<'a, T> {
+    let fragments = referenced_fragments(operation_selection_set(&operation), fragments);
+    let mut definitions = vec![Definition::Operation(operation)];
+    definitions.extend(fragments.into_iter().map(Definition::Fragment));
+    Document { definitions }
+}
+
+/// Return the top-level selection set of an operation
+fn operation_selection_set<'o, 'a, T: Text<'a>>(
+    operation: &'o OperationDefinition<'a, T>,
+) -> &'o SelectionSet<'a, T> {
+    match operation {
+        OperationDefinition::SelectionSet(selection_set) => selection_set,
+        OperationDefinition::Query(query) => &query.selection_set,
+        OperationDefinition::Mutation(mutation) => &mutation.selection_set,
+        OperationDefinition::Subscription(subscription) => &subscription.selection_set,
+    }
+}
+
+/// Return the top-level selection set of an operation, for modification
+fn operation_selection_set_mut<'o, 'a, T: Text<'a>>(
+    operation: &'o mut OperationDefinition<'a, T>,
+) -> &'o mut SelectionSet<'a, T> {
+    match operation {
+        OperationDefinition::SelectionSet(selection_set) => selection_set,
+        OperationDefinition::Query(query) => &mut query.selection_set,
+        OperationDefinition::Mutation(mutation) => &mut mutation.selection_set,
+        OperationDefinition::Subscription(subscription) => &mut subscription.selection_set,
+    }
+}
+
+/// Partition an operation by any of the given paths. Fragment spreads are resolved against the
+/// given fragment definitions; spreads of fragments not found there are never traversed. Each side
+/// of the partition keeps only the variable definitions it uses. The right side is `None` if the
+/// paths matched everything.
+fn partition_operation_by_paths<'a, T: Text<'a> + Clone>(
+    operation: OperationDefinition<'a, T>,
+    paths: &[&str],
+    fragments: &[FragmentDefinition<'a, T>],
+) -> Result<
+    (
+        OperationDefinition<'a, T>,
+        Option<OperationDefinition<'a, T>>,
+    ),
+    PartitionError,
+> {
+    let path = paths.join(", ");
+    let elements = paths
+        .iter()
+        .map(|path| validate_path(path))
+        .collect::<Result<Vec<_>, _>>()?;
+    let paths = elements.iter().map(Vec::as_slice).collect_vec();
+
+    if let OperationDefinition::Mutation(_) | OperationDefinition::Subscription(_) = operation {
+        return Err(PartitionError::UnsupportedOperationType {
+            operation_type: match operation {
+                OperationDefinition::Mutation(_) => "mutation",
+                _ => "subscription",
             }
-            OperationDefinition::SelectionSet(selection_set) => {
-                partition_selection_set_by_path(elements, selection_set).map(|(left, right)| {
-                    (
-                        OperationDefinition::SelectionSet(left),
-                        OperationDefinition::SelectionSet(right),
-                    )
-                })
+            .to_string(),
+        });
+    }
+
+    // The right side is partitioned in place, with its fragment spreads expanded, while the left
+    // side is collected as it goes
+    let mut left = operation;
+    let mut right = left.clone();
+    let selection_set = operation_selection_set_mut(&mut right);
+    *selection_set = expand_fragment_spreads(selection_set, fragments);
+    if let Some(name) = self_spreading_fragment(selection_set, fragments) {
+        return Err(PartitionError::UnresolvedFragment { name });
+    }
+    let mut partitioner = Partitioner {
+        fragments,
+        paths: vec![paths],
+        outcomes: vec![],
+        originals: vec![&operation_selection_set(&left).items],
+        left: None,
+    };
+    walk_selection_set_mut(selection_set, &mut partitioner);
+    match partitioner.left {
+        Some(selection_set) if !selection_set.items.is_empty() => {
+            *operation_selection_set_mut(&mut left) = selection_set
+        }
+        _ => return Err(PartitionError::PathNotFound { path }),
+    }
+    prune_variable_definitions(&mut left, fragments);
+    if operation_selection_set(&right).items.is_empty() {
+        return Ok((left, None));
+    }
+    prune_variable_definitions(&mut right, fragments);
+    Ok((left, Some(right)))
+}
+
+/// How a single selection divides between the two sides of a partition
+enum Outcome<'a, T: Text<'a>> {
+    /// Nothing in the selection matched, so all of it stays on the right
+    Unmatched,
+    /// The whole selection matched, so all of it goes on the left
+    Matched,
+    /// Part of the selection matched. The matching part, given here, goes on the left; the rest
+    /// stays on the right, unless nothing is left of its selection set.
+    Split(Selection<'a, T>),
+}
+
+/// Partitions a selection set in place, leaving only what does not match the paths and collecting
+/// what does into [`Partitioner::left`]. The selection set must have had its fragment spreads
+/// expanded by [`expand_fragment_spreads`].
+///
+/// Fragments do not add a level to the response, so paths are not consumed by them. A fragment
+/// spread that contains a match is replaced on both sides by an inline fragment holding the
+/// respective part of the fragment's selection set, so that the named fragment itself is left
+/// untouched. A selection that does not divide is taken as it was before the expansion, so spreads
+/// in it are kept. Spreads of fragments not among the fragment definitions are never traversed.
+struct Partitioner<'p, 'e, 'f, 'a, T: Text<'a>> {
+    fragments: &'f [FragmentDefinition<'a, T>],
+    /// For each selection set being visited, what remains to be matched of each path, relative
+    /// to that selection set
+    paths: Vec<Vec<&'e [PathElement<'p>]>>,
+    /// For each selection set being visited, how each of its selections visited so far divides
+    outcomes: Vec<Vec<Outcome<'a, T>>>,
+    /// For each selection set being visited, its selections as they were before fragment spreads
+    /// were expanded, with the same indices
+    originals: Vec<&'f [Selection<'a, T>]>,
+    /// The left side of the selection set visited last, if anything in it matched
+    left: Option<SelectionSet<'a, T>>,
+}
+
+impl<'p, 'e, 'f, 'a, T: Text<'a> + Clone> Partitioner<'p, 'e, 'f, 'a, T> {
+    /// What remains to be matched of the paths after the given field, which is selected within
+    /// a fragment with the given type condition, if any
+    fn remaining_paths(
+        &self,
+        field: &Field<'a, T>,
+        type_condition: Option<&str>,
+    ) -> Vec<&'e [PathElement<'p>]> {
+        let mut remaining = vec![];
+        for path in expand_glob_stars(self.paths.last().map_or(&[], Vec::as_slice)) {
+            match path.first() {
+                // "**" may match any number of fields, so it stays at the front of the path
+                Some(PathElement::GlobStar) => remaining.push(path),
+                Some(element) if element.matches(field, type_condition) => {
+                    remaining.push(&path[1..])
+                }
+                _ => (),
             }
-            _ => unimplemented!(),
+        }
+        remaining
+    }
+
+    /// Record how a selection of the selection set being visited divides
+    fn record(&mut self, outcome: Outcome<'a, T>) {
+        if let Some(outcomes) = self.outcomes.last_mut() {
+            outcomes.push(outcome);
+        }
+    }
+
+    /// Enter the selection set of the selection being visited, whose index is that of the next
+    /// outcome to be recorded, by looking up the selections beneath it before the expansion
+    fn enter_original(&mut self) {
+        let index = self.outcomes.last().map_or(0, Vec::len);
+        let original = self.originals.last().and_then(|items| items.get(index));
+        let items: &'f [Selection<'a, T>] = match original {
+            Some(Selection::Field(field)) => &field.selection_set.items,
+            Some(Selection::InlineFragment(inline_fragment)) => {
+                &inline_fragment.selection_set.items
+            }
+            Some(Selection::FragmentSpread(spread)) => self
+                .fragments
+                .iter()
+                .find(|f| f.name == spread.fragment_name)
+                .map_or(&[], |fragment| &fragment.selection_set.items),
+            None => &[],
         };
-        Ok(partition)
+        self.originals.push(items);
+    }
+
+    /// Return the left side of the selection set visited last, if anything in it matched
+    fn take_left(&mut self) -> Option<SelectionSet<'a, T>> {
+        self.left.take().filter(|left| !left.items.is_empty())
     }
 }
 
-fn partition_selection_set_by_path<'a>(
-    mut path: Vec<&str>,
-    selection_set: graphql_parser::query::SelectionSet<'a, &'a str>,
-    // parent_field: Field<'a, &'a str>
-) -> Option<(SelectionSet<'a, &'a str>, SelectionSet<'a, &'a str>)> {
-    if path.is_empty() {
-        return None;
-    }
-    let field_name = path.remove(0);
-
-    let mut items = selection_set.items;
-    let span = selection_set.span;
-    match items.iter().position(|f| {
-        if let Selection::Field(field) = f {
-            field_name_or_alias_matches(field, field_name)
-        } else {
-            false
-        }
-    }) {
-        Some(index) => {
-            let mut field = cast!(items.remove(index), Selection::Field);
-
-            match path.len() {
-                0 => Some((
-                    SelectionSet {
-                        span: (Pos::default(), Pos::default()),
-                        items: vec![Selection::Field(field)],
-                    },
-                    SelectionSet { items, span },
-                )), // Create a new SelectionSet with the remaining items
-                _ => {
-                    if let Some((inner_selection_set, selection_set)) =
-                        partition_selection_set_by_path(path, field.selection_set)
-                    {
-                        field.selection_set = selection_set;
-                        let mut f2 = field.clone();
-                        f2.selection_set = inner_selection_set;
-                        items.push(Selection::Field(field));
-                        let right = SelectionSet { items, span };
-                        let left = SelectionSet {
-                            span: (Pos::default(), Pos::default()),
-                            items: vec![Selection::Field(f2)],
-                        };
-                        Some((left, right))
-                    } else {
-                        None
+/// Returns true if any of the paths ends here, so that the field they were matched against
+/// matches as a whole
+fn ends_here(paths: &[&[PathElement]]) -> bool {
+    expand_glob_stars(paths).iter().any(|path| path.is_empty())
+}
+
+impl<'p, 'e, 'f, 'a, T: Text<'a> + Clone> VisitorMut<'a, T> for Partitioner<'p, 'e, 'f, 'a, T> {
+    fn enter_selection_set(
+        &mut self,
+        _selection_set: &mut SelectionSet<'a, T>,
+        _context: &Context,
+    ) -> Control {
+        self.outcomes.push(vec![]);
+        Control::Continue
+    }
+
+    fn leave_selection_set(&mut self, selection_set: &mut SelectionSet<'a, T>, _context: &Context) {
+        let outcomes = self.outcomes.pop().unwrap_or_default();
+        let originals = self.originals.last().copied().unwrap_or_default();
+        let mut left = vec![];
+        let mut right = vec![];
+        for ((selection, outcome), original) in
+            selection_set.items.drain(..).zip(outcomes).zip(originals)
+        {
+            match outcome {
+                Outcome::Unmatched => right.push(original.clone()),
+                Outcome::Matched => left.push(original.clone()),
+                Outcome::Split(matched) => {
+                    left.push(matched);
+                    // A field or fragment cannot select nothing, so one whose every selection
+                    // matched is left out
+                    if !is_emptied(&selection) {
+                        right.push(selection);
                     }
                 }
             }
         }
-        None => None,
+        selection_set.items = right;
+        self.left = Some(SelectionSet {
+            span: selection_set.span,
+            items: left,
+        });
     }
-}
 
-#[derive(Debug)]
-pub(crate) struct InvalidElementError {
-    element: String,
-}
+    fn enter_field(&mut self, field: &mut Field<'a, T>, context: &Context) -> Control {
+        self.enter_original();
+        let remaining = self.remaining_paths(field, context.type_condition());
+        let control = if remaining.is_empty() || ends_here(&remaining) {
+            Control::Skip
+        } else {
+            Control::Continue
+        };
+        self.paths.push(remaining);
+        control
+    }
 
-impl std::fmt::Display for InvalidElementError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "Invalid element in path: \"{}\"", self.element)
+    fn leave_field(&mut self, field: &mut Field<'a, T>, _context: &Context) {
+        self.originals.pop();
+        let remaining = self.paths.pop().unwrap_or_default();
+        let outcome = if remaining.is_empty() {
+            Outcome::Unmatched
+        } else if ends_here(&remaining) {
+            Outcome::Matched
+        } else {
+            match self.take_left() {
+                Some(selection_set) => Outcome::Split(Selection::Field(Field {
+                    position: field.position,
+                    alias: field.alias.clone(),
+                    name: field.name.clone(),
+                    arguments: field.arguments.clone(),
+                    directives: field.directives.clone(),
+                    selection_set,
+                })),
+                None => Outcome::Unmatched,
+            }
+        };
+        self.record(outcome);
+    }
+
+    fn fragment_spread(&mut self, _spread: &mut FragmentSpread<'a, T>, _context: &Context) {
+        // Spreads of fragments that could be found were expanded before the walk
+        self.record(Outcome::Unmatched);
+    }
+
+    fn enter_inline_fragment(
+        &mut self,
+        _inline_fragment: &mut InlineFragment<'a, T>,
+        _context: &Context,
+    ) -> Control {
+        self.enter_original();
+        let paths = self.paths.last().cloned().unwrap_or_default();
+        self.paths.push(paths);
+        Control::Continue
+    }
+
+    fn leave_inline_fragment(
+        &mut self,
+        inline_fragment: &mut InlineFragment<'a, T>,
+        _context: &Context,
+    ) {
+        self.originals.pop();
+        self.paths.pop();
+        let outcome = match self.take_left() {
+            Some(selection_set) => Outcome::Split(Selection::InlineFragment(InlineFragment {
+                position: inline_fragment.position,
+                type_condition: inline_fragment.type_condition.clone(),
+                directives: inline_fragment.directives.clone(),
+                selection_set,
+            })),
+            None => Outcome::Unmatched,
+        };
+        self.record(outcome);
     }
 }
-impl std::error::Error for InvalidElementError {}
-
-/// Validate the given path string. A valid path consists of one or more valid field names separated by
-/// the dot (.) character. A valid field name is a string containing only the characters in the range
-/// [a-zA-Z0-9_] and beginning with a character in the range [A-Za-z_]
-fn validate_path(path: &str) -> Result<Vec<&str>> {
-    // http://spec.graphql.org/October2021/#sec-Names
-    let re = Regex::new("^[_A-Za-z][_0-9A-Za-z]*$").unwrap();
-    let (elements, invalid): (Vec<_>, Vec<_>) =
-        path.split('.').partition_map(|e| match re.is_match(e) {
-            true => Either::Left(e),
-            false => Either::Right(e),
-        });
 
-    if invalid.is_empty() {
-        Ok(elements)
-    } else {
-        Err(Error::from(InvalidElementError {
-            element: invalid.get(0).unwrap().to_string(),
-        }))
+/// Returns true if nothing is left in the selection set of a field or an inline fragment that was
+/// split. Fragment spreads are never split.
+fn is_emptied<'a, T: Text<'a>>(selection: &Selection<'a, T>) -> bool {
+    match selection {
+        Selection::Field(field) => field.selection_set.items.is_empty(),
+        Selection::InlineFragment(inline_fragment) => {
+            inline_fragment.selection_set.items.is_empty()
+        }
+        Selection::FragmentSpread(_) => false,
     }
 }
 
-/// Returns true if the field name or alias match the search string.
-fn field_name_or_alias_matches<'a, T: Text<'a, Value = &'a str>>(
-    field: &Field<'a, T>,
-    search_str: &str,
-) -> bool {
-    match field.alias {
-        Some(alias) => alias == search_str,
-        None => field.name == search_str,
+/// Expand the given paths so that, for every path beginning with "**", the path with that "**"
+/// matching nothing is included as well
+fn expand_glob_stars<'e, 'p>(paths: &[&'e [PathElement<'p>]]) -> Vec<&'e [PathElement<'p>]> {
+    let mut expanded = vec![];
+    for path in paths {
+        let mut path = *path;
+        expanded.push(path);
+        while path.first().is_some_and(PathElement::is_glob_star) {
+            path = &path[1..];
+            expanded.push(path);
+        }
     }
+    expanded
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::comparisions::{compare_queries, compare_selection_sets};
+    use crate::algebra::Algebra;
+    use crate::comparisions::{
+        compare_operations, compare_queries, compare_selection_sets, semantically_equal,
+    };
     use crate::fields_and_fragments::FieldsAndFragments;
-    use crate::{validate_path, Operations, Partition};
+    use crate::path::validate_path;
+    use crate::{Operations, Partition, PartitionError};
     use anyhow::{Error, Result};
     use graphql_parser::query::Field;
     use graphql_parser::schema::Text;
     use graphql_parser::{
         parse_query,
-        query::{Document, OperationDefinition, SelectionSet},
+        query::{Definition, Document, OperationDefinition, SelectionSet},
     };
     use lazy_static::lazy_static;
     use rand::Rng;
@@ -292,7 +687,7 @@ mod tests {
 
         let mut paths = valid_start_charset.bytes().map(|c| {
             let mut elements: Vec<String> = Vec::new();
-            for _ in 1..path_length {
+            for _ in 0..path_length {
                 let length: usize = rng.gen_range(1..=10);
                 let field_name = format!(
                     "{}{}",
@@ -316,7 +711,15 @@ mod tests {
         let invalid_start = "0abc.def.abc";
         let invalid_end = "abc.def.ghi=";
         let invalid_middle = "abc.d@f.ghi";
-        let paths = vec![invalid_start, invalid_end, invalid_middle];
+        let invalid_wildcard = "abc.***.ghi";
+        let invalid_partial_wildcard = "abc.d*.ghi";
+        let paths = vec![
+            invalid_start,
+            invalid_end,
+            invalid_middle,
+            invalid_wildcard,
+            invalid_partial_wildcard,
+        ];
         for path in paths {
             assert!(
                 validate_path(path).is_err(),
@@ -326,9 +729,20 @@ mod tests {
         }
     }
 
+    #[test]
+    fn validate_path_shows_wildcard_paths_as_valid() {
+        for path in ["*", "**", "abc.*.ghi", "**.ghi", "abc.**", "*.**.ghi"] {
+            assert!(
+                validate_path(path).is_ok(),
+                "Path \"{}\" tests as valid",
+                path
+            );
+        }
+    }
+
     #[test]
     fn remove_with_invalid_path_returns_err() {
-        let op = parse_query("{ myQuery { alpha } }")
+        let op = parse_query::<&str>("{ myQuery { alpha } }")
             .unwrap()
             .operations()
             .pop()
@@ -337,26 +751,83 @@ mod tests {
     }
 
     #[test]
-    fn partition_with_non_matching_path_returns_none() -> Result<(), anyhow::Error> {
-        let op = parse_query("{ myQuery { alpha } }")
+    fn partition_with_non_matching_path_returns_path_not_found() {
+        let op = parse_query::<&str>("{ myQuery { alpha } }")
+            .unwrap()
+            .operations()
+            .pop()
+            .unwrap();
+
+        assert_eq!(
+            op.partition_by_path("myQuery.foo"),
+            Err(PartitionError::PathNotFound {
+                path: "myQuery.foo".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn partition_by_top_level_field_leaves_nothing_on_the_right() {
+        let op = parse_query::<&str>("{ myQuery { alpha } }")
             .unwrap()
             .operations()
             .pop()
             .unwrap();
+        assert_eq!(
+            op.partition_by_path("myQuery"),
+            Err(PartitionError::EmptyResult {
+                path: "myQuery".to_string()
+            })
+        );
+    }
 
-        assert_eq!(op.partition_by_path("myQuery.foo")?, None);
+    #[test]
+    fn partition_preserves_selection_order() -> Result<()> {
+        let document = parse_query::<&str>(
+            "{ q { a, b { x, y, z }, ...F, c, ... on Q { e, f } } } fragment F on Q { d, g }",
+        )?;
+        let (left, right) = document.partition_by_any_path(&["q.b.y", "q.g", "q.f"])?;
+        let expected_left =
+            parse_query::<&str>("{ q { b { y }, ... on Q { g }, ... on Q { f } } }")?;
+        let expected_right =
+            parse_query::<&str>("{ q { a, b { x, z }, ... on Q { d }, c, ... on Q { e } } }")?;
+        assert_eq!(expected_left.to_string(), left.to_string());
+        assert_eq!(expected_right.to_string(), right.to_string());
         Ok(())
     }
 
     #[test]
-    fn partition_by_top_level_field_leaves_nothing_on_the_right() -> Result<(), anyhow::Error> {
-        let query = "{ myQuery { alpha } }";
-        let expected_left = "{ myQuery { alpha } }";
-        let expected_right = None;
-        partition_by_path_ok("myQuery", query, Some(expected_left), expected_right)?;
+    fn partition_never_leaves_an_empty_selection_set() -> Result<()> {
+        let op = parse_query::<&str>(
+            "{ myQuery { beta { one }, ... on MyQuery { gamma { two } } }, other { alpha } }",
+        )?
+        .operations()
+        .pop()
+        .unwrap();
+        let (left, right) = op.partition_by_any_path(&["myQuery.beta.one", "myQuery.gamma.two"])?;
+        assert_eq!(
+            "{\n  myQuery {\n    beta {\n      one\n    }\n    ... on MyQuery {\n      gamma {\n        two\n      }\n    }\n  }\n}\n",
+            left.to_string()
+        );
+        assert_eq!("{\n  other {\n    alpha\n  }\n}\n", right.to_string());
         Ok(())
     }
 
+    #[test]
+    fn partition_mutation_returns_unsupported_operation_type() {
+        let op = parse_query::<&str>("mutation { pick(id: 1) { id } }")
+            .unwrap()
+            .operations()
+            .pop()
+            .unwrap();
+        assert_eq!(
+            op.partition_by_path("pick.id"),
+            Err(PartitionError::UnsupportedOperationType {
+                operation_type: "mutation".to_string()
+            })
+        );
+    }
+
     #[test]
     fn partition_preserves_field_structure() -> Result<()> {
         let query = "{ myQuery { alpha { one, two, three } } }";
@@ -392,6 +863,19 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn partition_prunes_unused_variable_definitions() -> Result<()> {
+        let query = r#"query MyQuery($a: ID!, $b: ID!, $c: Boolean) {
+            alpha(id: $a) { one }
+            beta(id: $b) { two @include(if: $c) }
+        }"#;
+        let expected_left = "query MyQuery($a: ID!) { alpha(id: $a) { one } }";
+        let expected_right =
+            "query MyQuery($b: ID!, $c: Boolean) { beta(id: $b) { two @include(if: $c) } }";
+        partition_by_path_ok("alpha", query, Some(expected_left), Some(expected_right))?;
+        Ok(())
+    }
+
     #[test]
     fn partition_path_refers_to_alias() -> Result<()> {
         let query = "{ myQuery { foo, bar { baz: alpha, bak } } }";
@@ -416,6 +900,309 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn partition_by_paths_returns_one_operation_per_path_and_the_remainder() -> Result<()> {
+        let op = parse_query("{ myQuery { alpha, beta { one, two }, gamma } }")?
+            .operations()
+            .pop()
+            .unwrap();
+        let (partitions, remainder) =
+            op.partition_by_paths(&["myQuery.alpha", "myQuery.beta.one"])?;
+        assert_eq!(partitions.len(), 2, "One partition per path");
+        let expected = [
+            "{ myQuery { alpha } }",
+            "{ myQuery { beta { one } } }",
+            "{ myQuery { gamma, beta { two } } }",
+        ];
+        for (expected, got) in expected
+            .iter()
+            .zip(partitions.iter().chain(remainder.as_ref()))
+        {
+            let expected = parse_query::<&str>(expected)?.operations().pop().unwrap();
+            let (matches, failure_reason) = compare_operations(&expected, got);
+            assert!(
+                matches,
+                "{}",
+                failure_reason.unwrap_or_else(|| "".to_string())
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn partition_by_paths_with_non_matching_path_returns_path_not_found() -> Result<()> {
+        let op = parse_query::<&str>("{ myQuery { alpha, beta } }")?
+            .operations()
+            .pop()
+            .unwrap();
+        // The second path is already gone by the time it is applied
+        assert_eq!(
+            op.partition_by_paths(&["myQuery.alpha", "myQuery.alpha"]),
+            Err(PartitionError::PathNotFound {
+                path: "myQuery.alpha".to_string()
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn partition_by_paths_matching_everything_leaves_no_remainder() -> Result<()> {
+        let op = parse_query::<&str>("{ myQuery { alpha, beta { one } } }")?
+            .operations()
+            .pop()
+            .unwrap();
+        let (partitions, remainder) =
+            op.partition_by_paths(&["myQuery.beta.one", "myQuery.alpha"])?;
+        assert_eq!(
+            partitions
+                .iter()
+                .map(|partition| partition.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "{\n  myQuery {\n    beta {\n      one\n    }\n  }\n}\n",
+                "{\n  myQuery {\n    alpha\n  }\n}\n",
+            ]
+        );
+        assert!(remainder.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn partition_by_paths_with_no_paths_returns_the_operation() -> Result<()> {
+        let op = parse_query("{ myQuery { alpha, beta } }")?
+            .operations()
+            .pop()
+            .unwrap();
+        let (partitions, remainder) = op.partition_by_paths(&[])?;
+        assert!(partitions.is_empty());
+        let selection_set = match remainder {
+            Some(OperationDefinition::SelectionSet(selection_set)) => selection_set,
+            _ => panic!("Expected a selection set"),
+        };
+        assert!(selection_set.clone_field("myQuery").is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn partition_wildcard_collects_every_match() -> Result<()> {
+        let query = "{ myQuery { a { isSubscriber, x }, b { isSubscriber, y }, c } }";
+        let expected_left = "{ myQuery { a { isSubscriber }, b { isSubscriber } } }";
+        let expected_right = "{ myQuery { c, a { x }, b { y } } }";
+        partition_by_path_ok(
+            "myQuery.*.isSubscriber",
+            query,
+            Some(expected_left),
+            Some(expected_right),
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn partition_glob_star_matches_at_any_depth() -> Result<()> {
+        let query = "{ viewerState, a { viewerState, b { viewerState, c } }, d }";
+        let expected_left = "{ viewerState, a { viewerState, b { viewerState } } }";
+        let expected_right = "{ d, a { b { c } } }";
+        partition_by_path_ok(
+            "**.viewerState",
+            query,
+            Some(expected_left),
+            Some(expected_right),
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn partition_trailing_glob_star_matches_whole_field() -> Result<()> {
+        let query = "{ myQuery { alpha { one, two }, beta } }";
+        let expected_left = "{ myQuery { alpha { one, two } } }";
+        let expected_right = "{ myQuery { beta } }";
+        partition_by_path_ok(
+            "myQuery.alpha.**",
+            query,
+            Some(expected_left),
+            Some(expected_right),
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn partition_path_passes_through_inline_fragments() -> Result<()> {
+        let query = "{ search { id, ... on Team { name, abbrev } } }";
+        let expected_left = "{ search { ... on Team { name } } }";
+        let expected_right = "{ search { id, ... on Team { abbrev } } }";
+        partition_by_path_ok(
+            "search.name",
+            query,
+            Some(expected_left),
+            Some(expected_right),
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn partition_argument_qualifier_selects_among_aliases() -> Result<()> {
+        let query = r#"{ home: team(abbrev: "NYG") { name }, away: team(abbrev: "DAL") { name } }"#;
+        let expected_left = r#"{ away: team(abbrev: "DAL") { name } }"#;
+        let expected_right = r#"{ home: team(abbrev: "NYG") { name } }"#;
+        partition_by_path_ok(
+            r#"*(abbrev: "DAL")"#,
+            query,
+            Some(expected_left),
+            Some(expected_right),
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn partition_type_condition_qualifier_selects_branch() -> Result<()> {
+        let query = "{ search { ... on Team { name, abbrev }, ... on Player { name } } }";
+        let expected_left = "{ search { ... on Team { name } } }";
+        let expected_right = "{ search { ... on Team { abbrev }, ... on Player { name } } }";
+        partition_by_path_ok(
+            "search.name[on Team]",
+            query,
+            Some(expected_left),
+            Some(expected_right),
+        )?;
+        partition_by_path_ok("search.name[on Coach]", query, None, None)?;
+        Ok(())
+    }
+
+    #[test]
+    fn partition_ignores_unresolvable_fragment_spreads() -> Result<()> {
+        // Without the document there is no fragment definition to look inside
+        partition_by_path_ok(
+            "matchup.prediction",
+            "{ matchup { ...Fields } }",
+            None,
+            None,
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn partition_document_path_passes_through_fragment_spreads() -> Result<()> {
+        let document = parse_query::<&str>(
+            r#"query Q { matchup { id, ...PredictionFields } }
+            fragment PredictionFields on Matchup { prediction { pick, ...Pick }, teams { ...Team } }
+            fragment Pick on Prediction { confidence, edge }
+            fragment Team on Team { abbrev }"#,
+        )?;
+        let (left, right) = document.partition_by_path("matchup.prediction.confidence")?;
+        let expected_left = parse_query::<&str>(
+            r#"query Q {
+                matchup { ... on Matchup { prediction { ... on Prediction { confidence } } } }
+            }"#,
+        )?;
+        let expected_right = parse_query::<&str>(
+            r#"query Q {
+                matchup {
+                    id
+                    ... on Matchup { prediction { pick, ... on Prediction { edge } }, teams { ...Team } }
+                }
+            }
+            fragment Team on Team { abbrev }"#,
+        )?;
+        for (expected, got) in [(expected_left, left), (expected_right, right)] {
+            assert_eq!(expected.definitions.len(), got.definitions.len());
+            let (matches, failure_reason) = compare_operations(
+                &expected.operations().pop().unwrap(),
+                &got.operations().pop().unwrap(),
+            );
+            assert!(matches, "{}", failure_reason.unwrap_or_default());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn partition_document_with_self_spreading_fragment_returns_err() {
+        for path in ["a.b", "**.zzz"] {
+            let document =
+                parse_query::<&str>("query Q { a { ...A } } fragment A on T { b ...A }").unwrap();
+            assert_eq!(
+                document.partition_by_path(path),
+                Err(PartitionError::UnresolvedFragment {
+                    name: "A".to_string()
+                }),
+                "path {}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn partition_document_carries_only_referenced_fragments() -> Result<()> {
+        let document = parse_query::<&str>(include_str!("../fixtures/matchupAnalysis.graphql"))?;
+        let (left, right) = document.partition_by_path("matchupAnalysis.somePrediction")?;
+        fn fragment_names<'a>(document: &Document<'a, &'a str>) -> Vec<&'a str> {
+            document
+                .definitions
+                .iter()
+                .filter_map(|def| match def {
+                    Definition::Fragment(fragment) => Some(fragment.name),
+                    _ => None,
+                })
+                .collect()
+        }
+        assert!(fragment_names(&left).is_empty(), "LEFT has no fragments");
+        assert_eq!(fragment_names(&right), vec!["MaTeamInfo"], "RIGHT");
+        Ok(())
+    }
+
+    #[test]
+    fn partition_works_on_owned_documents() -> Result<()> {
+        let query = String::from(
+            "query Q($id: ID!) { matchup(id: $id) { id, ...Prediction } }
+            fragment Prediction on Matchup { prediction { confidence }, edge }",
+        );
+        let document = parse_query::<String>(&query)?.into_static();
+        drop(query);
+        // Owned documents do not borrow from the query text, so they can be sent to other threads
+        let (left, right) =
+            std::thread::spawn(move || document.partition_by_path("matchup.prediction"))
+                .join()
+                .unwrap()?;
+        let expected_left = parse_query::<&str>(
+            "query Q($id: ID!) { matchup(id: $id) { ... on Matchup { prediction { confidence } } } }",
+        )?;
+        let expected_right = parse_query::<&str>(
+            "query Q($id: ID!) { matchup(id: $id) { id, ... on Matchup { edge } } }",
+        )?;
+        assert_eq!(expected_left.to_string(), left.to_string());
+        assert_eq!(expected_right.to_string(), right.to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn partition_union_restores_the_original() -> Result<()> {
+        let query = r#"query MyQuery($a: ID!, $b: ID!, $c: Boolean) {
+            alpha(id: $a) { one, two { x, y } }
+            beta(id: $b) { three @include(if: $c), four: three }
+            gamma
+        }"#;
+        for path in ["alpha", "alpha.two.x", "beta.four", "**.x", "*.one"] {
+            let original = parse_query::<&str>(query)?.operations().pop().unwrap();
+            let (left, right) = original.clone().partition_by_path(path)?;
+            let union = left.union(&right);
+            assert!(
+                semantically_equal(&original, &union),
+                "LEFT \u{222a} RIGHT is the original for path {}:\n{}",
+                path,
+                union
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn partition_document_with_multiple_operations_returns_err() {
+        let document = parse_query::<&str>("query A { alpha } query B { beta }").unwrap();
+        assert_eq!(
+            document.partition_by_path("alpha"),
+            Err(PartitionError::OperationCount { count: 2 })
+        );
+    }
+
     fn partition_by_path_ok<'a>(
         path: &str,
         query: &'a str,
@@ -436,7 +1223,11 @@ mod tests {
             expected_right.map(|s| parse_query::<&str>(s).unwrap().operations().pop().unwrap());
 
         let operation = operations.pop().unwrap();
-        let res = operation.partition_by_path(path)?;
+        let res = match operation.partition_by_path(path) {
+            Ok(partition) => Some(partition),
+            Err(PartitionError::PathNotFound { .. }) => None,
+            Err(why) => return Err(Error::from(why)),
+        };
         match (res, expected_left) {
             (None, None) => {
                 if let Some(operation_definition) = expected_right {
@@ -0,0 +1,612 @@
+// Copyright 2024 Aurelia Peters
+//
+// This file is part of GraphQL Operation Partitioner.
+// 
+// GraphQL Operation Partitioner is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// 
+// GraphQL Operation Partitioner is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License along with GraphQL Cacher. If not, see <https://www.gnu.org/licenses/>. 
+//! Transforms that bring an operation into a simpler but equivalent form before it is partitioned
+use crate::{
+    algebra::{signature, variable_definitions},
+    fragments::{expand_fragment_spreads, referenced_fragments},
+    operation_selection_set, operation_selection_set_mut,
+    variables::prune_variable_definitions,
+    visit::{walk_operation_mut, walk_selection_set_mut, Context, Control, VisitorMut},
+    PartitionError,
+};
+use graphql_parser::query::{
+    Definition, Directive, Document, FragmentDefinition, InlineFragment, OperationDefinition,
+    Selection, SelectionSet, Text, TypeCondition, Value,
+};
+use itertools::{Either, Itertools};
+use std::collections::{HashMap, HashSet};
+
+/// Replace every fragment spread in the document's operations with an inline fragment holding the
+/// fragment's selections, and drop the fragment definitions, so that each operation stands on its
+/// own. Inline fragments that add nothing are then collapsed into the selection set they are in;
+/// see [`collapse_inline_fragments`]. Returns an error if a spread cannot be inlined because its
+/// fragment is not defined or spreads itself.
+///
+/// # Examples
+/// ```
+/// use graphql_parser::parse_query;
+/// use partition_operation::normalize::inline_fragments;
+///
+/// let document = parse_query::<&str>(
+///     "{ team { ...Names } } fragment Names on Team { name, ... on Team { abbrev } }",
+/// )
+/// .unwrap();
+/// let document = inline_fragments(document).unwrap();
+/// assert_eq!(
+///     "{\n  team {\n    ... on Team {\n      name\n      abbrev\n    }\n  }\n}\n",
+///     document.to_string()
+/// );
+/// ```
+pub fn inline_fragments<'a, T: Text<'a> + Clone>(
+    document: Document<'a, T>,
+) -> Result<Document<'a, T>, PartitionError> {
+    let (operations, fragments): (Vec<_>, Vec<FragmentDefinition<'a, T>>) = document
+        .definitions
+        .into_iter()
+        .partition_map(|definition| match definition {
+            Definition::Operation(operation) => Either::Left(operation),
+            Definition::Fragment(fragment) => Either::Right(fragment),
+        });
+    let operations = operations
+        .into_iter()
+        .map(|mut operation| {
+            let selection_set = operation_selection_set_mut(&mut operation);
+            *selection_set = expand_fragment_spreads(selection_set, &fragments);
+            if let Some(name) = unresolved_fragment(selection_set) {
+                return Err(PartitionError::UnresolvedFragment { name });
+            }
+            collapse_inline_fragments(selection_set);
+            Ok(Definition::Operation(operation))
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Document {
+        definitions: operations,
+    })
+}
+
+/// Replace every inline fragment whose type condition is redundant with the selections it holds.
+/// A type condition is redundant if there is none, or if it is the same as that of the fragment
+/// enclosing the inline fragment within its field's selection set. Inline fragments with
+/// directives are kept, since the directives apply to all of their selections.
+///
+/// # Examples
+/// ```
+/// use graphql_parser::{parse_query, query::{Definition, OperationDefinition}};
+/// use partition_operation::normalize::collapse_inline_fragments;
+///
+/// let document =
+///     parse_query::<&str>("{ team { ... { name }, ... on Team { ... on Team { abbrev } } } }")
+///         .unwrap();
+/// let mut selection_set = match document.definitions.into_iter().next() {
+///     Some(Definition::Operation(OperationDefinition::SelectionSet(selection_set))) => {
+///         selection_set
+///     }
+///     _ => unreachable!(),
+/// };
+/// collapse_inline_fragments(&mut selection_set);
+/// assert_eq!(
+///     "{\n  team {\n    name\n    ... on Team {\n      abbrev\n    }\n  }\n}\n",
+///     selection_set.to_string()
+/// );
+/// ```
+pub fn collapse_inline_fragments<'a, T: Text<'a> + Clone>(selection_set: &mut SelectionSet<'a, T>) {
+    walk_selection_set_mut(selection_set, &mut CollapseInlineFragments);
+}
+
+/// Evaluate the `@skip` and `@include` directives in the document's operations and fragments,
+/// removing the selections they exclude and the directives themselves from the selections they
+/// keep. `variable` gives the value of a Boolean variable of the request; variables it has no
+/// value for take their default value, if they have one. Directives whose condition cannot be
+/// determined this way are left in place. Selections are not removed if that would leave a
+/// selection set empty, since that cannot be expressed in a query. The definitions of variables
+/// and fragments that are no longer used are removed as well.
+///
+/// # Examples
+/// ```
+/// use graphql_parser::parse_query;
+/// use partition_operation::normalize::evaluate_conditions;
+///
+/// let document = parse_query::<&str>(
+///     "query Q($logos: Boolean, $names: Boolean = true, $x: Boolean) {
+///         team { logo @include(if: $logos), name @skip(if: $names), abbrev @skip(if: $x) }
+///     }",
+/// )
+/// .unwrap();
+/// let document = evaluate_conditions(document, |name| match name {
+///     "logos" => Some(true),
+///     _ => None,
+/// });
+/// assert_eq!(
+///     "query Q($x: Boolean) {\n  team {\n    logo\n    abbrev @skip(if: $x)\n  }\n}\n",
+///     document.to_string()
+/// );
+/// ```
+pub fn evaluate_conditions<'a, T, F>(document: Document<'a, T>, variable: F) -> Document<'a, T>
+where
+    T: Text<'a> + Clone,
+    F: Fn(&str) -> Option<bool>,
+{
+    let (mut operations, mut fragments): (Vec<_>, Vec<_>) = document
+        .definitions
+        .into_iter()
+        .partition_map(|definition| match definition {
+            Definition::Operation(operation) => Either::Left(operation),
+            Definition::Fragment(fragment) => Either::Right(fragment),
+        });
+    for operation in &mut operations {
+        let mut visitor = EvaluateConditions {
+            variable: &variable,
+            defaults: default_values(operation),
+        };
+        walk_operation_mut(operation, &mut visitor);
+    }
+    // The variables a fragment uses are those of the operation it is spread in, so their default
+    // values are only known if there is just one
+    let mut visitor = EvaluateConditions {
+        variable: &variable,
+        defaults: match operations.as_slice() {
+            [operation] => default_values(operation),
+            _ => HashMap::new(),
+        },
+    };
+    for fragment in &mut fragments {
+        walk_selection_set_mut(&mut fragment.selection_set, &mut visitor);
+    }
+
+    let used_fragments = operations
+        .iter()
+        .flat_map(|operation| referenced_fragments(operation_selection_set(operation), &fragments))
+        .map(|fragment| fragment.name.as_ref().to_string())
+        .collect::<HashSet<_>>();
+    fragments.retain(|fragment| used_fragments.contains(fragment.name.as_ref()));
+    for operation in &mut operations {
+        prune_variable_definitions(operation, &fragments);
+    }
+    Document {
+        definitions: operations
+            .into_iter()
+            .map(Definition::Operation)
+            .chain(fragments.into_iter().map(Definition::Fragment))
+            .collect(),
+    }
+}
+
+/// Return the Boolean default values of the operation's variables, by name
+fn default_values<'a, T: Text<'a>>(
+    operation: &OperationDefinition<'a, T>,
+) -> HashMap<String, bool> {
+    variable_definitions(operation)
+        .into_iter()
+        .flatten()
+        .filter_map(|definition| match definition.default_value {
+            Some(Value::Boolean(value)) => Some((definition.name.as_ref().to_string(), value)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Merge the selections that share a response key within each selection set of the document's
+/// operations and fragments, as GraphQL does when it collects the fields of a selection set: the
+/// selection sets of fields with the same response key, name, arguments and directives are merged
+/// into the first of them, recursively, and so are those of inline fragments with the same type
+/// condition and directives. Fields that share a response key but differ otherwise are left
+/// apart, since they are either conditional or cannot be merged at all.
+///
+/// # Examples
+/// ```
+/// use graphql_parser::parse_query;
+/// use partition_operation::normalize::merge_fields;
+///
+/// let document =
+///     parse_query::<&str>("{ team { name, logo }, season, team { abbrev, name } }").unwrap();
+/// assert_eq!(
+///     "{\n  team {\n    name\n    logo\n    abbrev\n  }\n  season\n}\n",
+///     merge_fields(document).to_string()
+/// );
+/// ```
+pub fn merge_fields<'a, T: Text<'a> + Clone>(mut document: Document<'a, T>) -> Document<'a, T> {
+    for definition in &mut document.definitions {
+        let selection_set = match definition {
+            Definition::Operation(operation) => operation_selection_set_mut(operation),
+            Definition::Fragment(fragment) => &mut fragment.selection_set,
+        };
+        merge_selection_set(selection_set);
+    }
+    document
+}
+
+/// Bring a document into the form in which it is partitioned: fragments are inlined, so that every
+/// partition stands on its own, the selections that `variable` says to skip are removed, and
+/// selections of the same response key are merged, so that they all end up in the same
+/// partition. Returns an error if a fragment cannot be inlined; see [`inline_fragments`].
+///
+/// # Examples
+/// ```
+/// use graphql_parser::parse_query;
+/// use partition_operation::normalize::normalize;
+///
+/// let document = parse_query::<&str>(
+///     "query Q($x: Boolean) { team { ...T, logo @skip(if: $x) } } fragment T on Team { name }",
+/// )
+/// .unwrap();
+/// let document = normalize(document, |name| match name {
+///     "x" => Some(true),
+///     _ => None,
+/// })
+/// .unwrap();
+/// assert_eq!(
+///     "query Q {\n  team {\n    ... on Team {\n      name\n    }\n  }\n}\n",
+///     document.to_string()
+/// );
+/// ```
+pub fn normalize<'a, T, F>(
+    document: Document<'a, T>,
+    variable: F,
+) -> Result<Document<'a, T>, PartitionError>
+where
+    T: Text<'a> + Clone,
+    F: Fn(&str) -> Option<bool>,
+{
+    inline_fragments(document).map(|document| merge_fields(evaluate_conditions(document, variable)))
+}
+
+/// Merge the selections that share a response key within the selection set, and then within the
+/// selection sets of what is left, in a single pass over each. Selections are grouped by response
+/// key as they come, so only those with the same key are compared to see whether they are the same
+/// selection.
+fn merge_selection_set<'a, T: Text<'a> + Clone>(selection_set: &mut SelectionSet<'a, T>) {
+    let mut items: Vec<Selection<'a, T>> = Vec::with_capacity(selection_set.items.len());
+    let mut by_key: HashMap<String, Vec<usize>> = HashMap::new();
+    for selection in selection_set.items.drain(..) {
+        let indices = by_key.entry(response_key(&selection)).or_default();
+        let same = match indices.as_slice() {
+            [] => None,
+            _ => {
+                let signature = signature(&selection);
+                indices
+                    .iter()
+                    .copied()
+                    .find(|&i| self::signature(&items[i]) == signature)
+            }
+        };
+        match (same.map(|i| &mut items[i]), selection) {
+            (Some(Selection::Field(field)), Selection::Field(other)) => {
+                field.selection_set.items.extend(other.selection_set.items)
+            }
+            (
+                Some(Selection::InlineFragment(inline_fragment)),
+                Selection::InlineFragment(other),
+            ) => inline_fragment
+                .selection_set
+                .items
+                .extend(other.selection_set.items),
+            // A spread of the same fragment with the same directives adds nothing
+            (Some(_), _) => (),
+            (None, selection) => {
+                indices.push(items.len());
+                items.push(selection);
+            }
+        }
+    }
+    for selection in &mut items {
+        match selection {
+            Selection::Field(field) => merge_selection_set(&mut field.selection_set),
+            Selection::InlineFragment(inline_fragment) => {
+                merge_selection_set(&mut inline_fragment.selection_set)
+            }
+            Selection::FragmentSpread(_) => (),
+        }
+    }
+    selection_set.items = items;
+}
+
+/// Return the key under which a selection is grouped for merging: the response key of a field,
+/// or the type condition of an inline fragment or the name of a spread fragment, which GraphQL
+/// collects by the response keys within them
+fn response_key<'a, T: Text<'a>>(selection: &Selection<'a, T>) -> String {
+    match selection {
+        Selection::Field(field) => field
+            .alias
+            .as_ref()
+            .unwrap_or(&field.name)
+            .as_ref()
+            .to_string(),
+        Selection::InlineFragment(inline_fragment) => match &inline_fragment.type_condition {
+            Some(TypeCondition::On(type_name)) => format!("... on {}", type_name.as_ref()),
+            None => "...".to_string(),
+        },
+        Selection::FragmentSpread(spread) => format!("...{}", spread.fragment_name.as_ref()),
+    }
+}
+
+/// Return the name of a fragment spread left in the selection set, if any
+fn unresolved_fragment<'a, T: Text<'a>>(selection_set: &SelectionSet<'a, T>) -> Option<String> {
+    selection_set
+        .items
+        .iter()
+        .find_map(|selection| match selection {
+            Selection::Field(field) => unresolved_fragment(&field.selection_set),
+            Selection::InlineFragment(inline_fragment) => {
+                unresolved_fragment(&inline_fragment.selection_set)
+            }
+            Selection::FragmentSpread(spread) => Some(spread.fragment_name.as_ref().to_string()),
+        })
+}
+
+/// Collapses redundant inline fragments. Selection sets are left after everything in them, so
+/// nested fragments have already been collapsed into the fragment enclosing them by the time it is
+/// itself collapsed.
+struct CollapseInlineFragments;
+
+impl<'a, T: Text<'a> + Clone> VisitorMut<'a, T> for CollapseInlineFragments {
+    fn leave_selection_set(&mut self, selection_set: &mut SelectionSet<'a, T>, context: &Context) {
+        if !selection_set.items.iter().any(|selection| {
+            matches!(selection, Selection::InlineFragment(inline_fragment)
+                if is_redundant(inline_fragment, context.type_condition()))
+        }) {
+            return;
+        }
+        for selection in std::mem::take(&mut selection_set.items) {
+            match selection {
+                Selection::InlineFragment(inline_fragment)
+                    if is_redundant(&inline_fragment, context.type_condition()) =>
+                {
+                    selection_set
+                        .items
+                        .extend(inline_fragment.selection_set.items)
+                }
+                selection => selection_set.items.push(selection),
+            }
+        }
+    }
+}
+
+/// Evaluates `@skip` and `@include` directives. Selections are removed on entering the selection
+/// set they are in, so nothing beneath them is visited.
+struct EvaluateConditions<'v, F> {
+    variable: &'v F,
+    defaults: HashMap<String, bool>,
+}
+
+impl<'v, F: Fn(&str) -> Option<bool>> EvaluateConditions<'v, F> {
+    /// Return the value of the condition of a `@skip` or `@include` directive, if it is one and
+    /// the value is known
+    fn condition<'a, T: Text<'a>>(&self, directive: &Directive<'a, T>) -> Option<bool> {
+        if directive.name.as_ref() != "skip" && directive.name.as_ref() != "include" {
+            return None;
+        }
+        match directive
+            .arguments
+            .iter()
+            .find(|(name, _)| name.as_ref() == "if")
+            .map(|(_, value)| value)?
+        {
+            Value::Boolean(value) => Some(*value),
+            Value::Variable(name) => {
+                (self.variable)(name.as_ref()).or_else(|| self.defaults.get(name.as_ref()).copied())
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns true if the directives exclude their selection
+    fn excludes<'a, T: Text<'a>>(&self, directives: &[Directive<'a, T>]) -> bool {
+        directives.iter().any(|directive| {
+            matches!(
+                (directive.name.as_ref(), self.condition(directive)),
+                ("skip", Some(true)) | ("include", Some(false))
+            )
+        })
+    }
+}
+
+impl<'a, 'v, T: Text<'a>, F: Fn(&str) -> Option<bool>> VisitorMut<'a, T>
+    for EvaluateConditions<'v, F>
+{
+    fn enter_selection_set(
+        &mut self,
+        selection_set: &mut SelectionSet<'a, T>,
+        _context: &Context,
+    ) -> Control {
+        if selection_set
+            .items
+            .iter()
+            .all(|selection| self.excludes(selection_directives(selection)))
+        {
+            return Control::Continue;
+        }
+        selection_set
+            .items
+            .retain(|selection| !self.excludes(selection_directives(selection)));
+        for selection in &mut selection_set.items {
+            let directives = match selection {
+                Selection::Field(field) => &mut field.directives,
+                Selection::FragmentSpread(spread) => &mut spread.directives,
+                Selection::InlineFragment(inline_fragment) => &mut inline_fragment.directives,
+            };
+            directives.retain(|directive| self.condition(directive).is_none());
+        }
+        Control::Continue
+    }
+}
+
+/// Return the directives of a selection
+fn selection_directives<'s, 'a, T: Text<'a>>(
+    selection: &'s Selection<'a, T>,
+) -> &'s [Directive<'a, T>] {
+    match selection {
+        Selection::Field(field) => &field.directives,
+        Selection::FragmentSpread(spread) => &spread.directives,
+        Selection::InlineFragment(inline_fragment) => &inline_fragment.directives,
+    }
+}
+
+/// Returns true if an inline fragment within a fragment on `type_condition` (if any) adds nothing
+/// to its selections
+fn is_redundant<'a, T: Text<'a>>(
+    inline_fragment: &InlineFragment<'a, T>,
+    type_condition: Option<&str>,
+) -> bool {
+    inline_fragment.directives.is_empty()
+        && match &inline_fragment.type_condition {
+            Some(TypeCondition::On(type_name)) => type_condition == Some(type_name.as_ref()),
+            None => true,
+        }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{evaluate_conditions, inline_fragments, merge_fields};
+    use crate::{Partition, PartitionError};
+    use graphql_parser::parse_query;
+
+    #[test]
+    fn inline_fragments_leaves_no_fragment_definitions() {
+        let document = parse_query::<&str>(
+            r#"query A { team { ...Team, ... on Team @include(if: true) { ...Names } } }
+            query B { ...Viewer }
+            fragment Team on Team { id, ...Names }
+            fragment Names on Team { name, abbrev }
+            fragment Viewer on Query { viewer { id } }"#,
+        )
+        .unwrap();
+        let expected = parse_query::<&str>(
+            r#"query A { team { ... on Team { id, name, abbrev }, ... on Team @include(if: true) { name, abbrev } } }
+            query B { ... on Query { viewer { id } } }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            expected.to_string(),
+            inline_fragments(document).unwrap().to_string()
+        );
+    }
+
+    #[test]
+    fn inline_fragments_keeps_fragments_that_are_not_redundant() {
+        let document = parse_query::<&str>(
+            r#"{ node { ... on Team { name, ... { abbrev }, ... on Named { name } }, ... @skip(if: true) { id } } }"#,
+        )
+        .unwrap();
+        let expected = parse_query::<&str>(
+            r#"{ node { ... on Team { name, abbrev, ... on Named { name } }, ... @skip(if: true) { id } } }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            expected.to_string(),
+            inline_fragments(document).unwrap().to_string()
+        );
+    }
+
+    #[test]
+    fn inline_fragments_with_unresolved_spread_returns_err() {
+        for (query, name) in [
+            ("{ team { ...Missing } }", "Missing"),
+            (
+                "{ team { ...One } } fragment One on Team { id, ...Two } fragment Two on Team { ...One }",
+                "One",
+            ),
+        ] {
+            let document = parse_query::<&str>(query).unwrap();
+            assert_eq!(
+                Err(PartitionError::UnresolvedFragment {
+                    name: name.to_string()
+                }),
+                inline_fragments(document).map(|document| document.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn evaluate_conditions_removes_excluded_selections_and_unused_definitions() {
+        let document = parse_query::<&str>(
+            r#"query Q($on: Boolean!, $off: Boolean!, $unknown: Boolean) {
+                team {
+                    name @include(if: $on) @skip(if: $unknown)
+                    ...Logo @include(if: $off)
+                    ... on Team @skip(if: $on) { abbrev }
+                    ... @skip(if: false) { id @include(if: true) }
+                }
+            }
+            fragment Logo on Team { logo }"#,
+        )
+        .unwrap();
+        let expected = parse_query::<&str>(
+            r#"query Q($unknown: Boolean) { team { name @skip(if: $unknown), ... { id } } }"#,
+        )
+        .unwrap();
+        let document = evaluate_conditions(document, |name| match name {
+            "on" => Some(true),
+            "off" => Some(false),
+            _ => None,
+        });
+        assert_eq!(expected.to_string(), document.to_string());
+    }
+
+    #[test]
+    fn evaluate_conditions_does_not_empty_a_selection_set() {
+        let query = "{\n  team {\n    name @skip(if: true)\n    ... @include(if: false) {\n      id\n    }\n  }\n  season\n}\n";
+        let document = parse_query::<&str>(query).unwrap();
+        assert_eq!(query, evaluate_conditions(document, |_| None).to_string());
+    }
+
+    #[test]
+    fn merge_fields_partitions_every_occurrence_of_a_path_together() {
+        let document = parse_query::<&str>(
+            r#"query Q {
+                matchupAnalysis {
+                    somePrediction { winner }
+                    lastUpdated
+                    somePrediction { margin, winner }
+                    ... on MatchupAnalysis { lastUpdated }
+                    ... on MatchupAnalysis { somePrediction { confidence } }
+                }
+            }"#,
+        )
+        .unwrap();
+        let (left, right) = merge_fields(document)
+            .partition_by_path("matchupAnalysis.somePrediction")
+            .unwrap();
+        let expected_left = parse_query::<&str>(
+            r#"query Q {
+                matchupAnalysis {
+                    somePrediction { winner, margin }
+                    ... on MatchupAnalysis { somePrediction { confidence } }
+                }
+            }"#,
+        )
+        .unwrap();
+        let expected_right = parse_query::<&str>(
+            r#"query Q {
+                matchupAnalysis { lastUpdated, ... on MatchupAnalysis { lastUpdated } }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(expected_left.to_string(), left.to_string());
+        assert_eq!(expected_right.to_string(), right.to_string());
+    }
+
+    #[test]
+    fn merge_fields_merges_nested_duplicates_in_order() {
+        let document = parse_query::<&str>(
+            "{ a { b { x } }, ... on Q { d }, a { b { y }, c }, a { b { x, z } }, ... on Q { e } }",
+        )
+        .unwrap();
+        let expected =
+            parse_query::<&str>("{ a { b { x, y, z }, c }, ... on Q { d, e } }").unwrap();
+        assert_eq!(expected.to_string(), merge_fields(document).to_string());
+    }
+
+    #[test]
+    fn merge_fields_keeps_fields_that_differ_apart() {
+        let query = "{\n  team(id: 1) {\n    name\n  }\n  team(id: 2) {\n    abbrev\n  }\n  season @include(if: $x) {\n    year\n  }\n  season {\n    week\n  }\n}\n";
+        let document = parse_query::<&str>(query).unwrap();
+        assert_eq!(query, merge_fields(document).to_string());
+    }
+}
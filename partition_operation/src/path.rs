@@ -0,0 +1,283 @@
+// Copyright 2024 Aurelia Peters
+//
+// This file is part of GraphQL Operation Partitioner.
+// 
+// GraphQL Operation Partitioner is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// 
+// GraphQL Operation Partitioner is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License along with GraphQL Cacher. If not, see <https://www.gnu.org/licenses/>. 
+//! Parsing and matching of partition paths. See "Query Path Syntax" in README.md
+use crate::PartitionError;
+use graphql_parser::{
+    parse_query,
+    query::{Definition, Field, OperationDefinition, Selection, Text},
+};
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    // http://spec.graphql.org/October2021/#sec-Names
+    static ref NAME: Regex = Regex::new("^[_A-Za-z][_0-9A-Za-z]*$").unwrap();
+}
+
+/// A single element of a path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum PathElement<'p> {
+    /// Matches a field with the given alias or, if the field has no alias, name
+    Name(&'p str, Qualifiers<'p>),
+    /// "*": matches any single field
+    Wildcard(Qualifiers<'p>),
+    /// "**": matches any number of fields, including none
+    GlobStar,
+}
+
+/// Optional restrictions on the fields matched by a path element
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Qualifiers<'p> {
+    /// Arguments the field must have, as (name, printed value) pairs
+    arguments: Vec<(String, String)>,
+    /// Type condition of the innermost fragment the field must be selected in
+    type_condition: Option<&'p str>,
+}
+
+impl<'p> PathElement<'p> {
+    /// Returns true if this element matches the given field. `type_condition` is the type
+    /// condition of the innermost fragment enclosing the field within its parent's selection set,
+    /// if any. A glob star matches every field.
+    pub(crate) fn matches<'a, T: Text<'a>>(
+        &self,
+        field: &Field<'a, T>,
+        type_condition: Option<&str>,
+    ) -> bool {
+        match self {
+            PathElement::Name(search_str, qualifiers) => {
+                field.alias.as_ref().unwrap_or(&field.name).as_ref() == *search_str
+                    && qualifiers.matches(field, type_condition)
+            }
+            PathElement::Wildcard(qualifiers) => qualifiers.matches(field, type_condition),
+            PathElement::GlobStar => true,
+        }
+    }
+
+    pub(crate) fn is_glob_star(&self) -> bool {
+        matches!(self, PathElement::GlobStar)
+    }
+}
+
+impl<'p> Qualifiers<'p> {
+    /// Returns true if the field has every qualifying argument with an equal value and, if a type
+    /// condition is given, is selected within a fragment on that type. Arguments that are not
+    /// qualified are ignored.
+    fn matches<'a, T: Text<'a>>(&self, field: &Field<'a, T>, type_condition: Option<&str>) -> bool {
+        if self.type_condition.is_some() && self.type_condition != type_condition {
+            return false;
+        }
+        self.arguments.iter().all(|(name, value)| {
+            field
+                .arguments
+                .iter()
+                .any(|(n, v)| n.as_ref() == name && v.to_string() == *value)
+        })
+    }
+}
+
+/// Validate the given path string. A valid path consists of one or more valid elements separated by
+/// the dot (.) character. A valid element is either "**" (any number of fields, including none) or
+/// a field name or "*" (any single field), optionally followed by an argument qualifier such as
+/// `(abbrev: "NYG")` and then by a type condition qualifier such as `[on Team]`. A valid field
+/// name is a string containing only the characters in the range [a-zA-Z0-9_] and beginning with a
+/// character in the range [A-Za-z_]
+pub(crate) fn validate_path(path: &str) -> Result<Vec<PathElement<'_>>, PartitionError> {
+    split_path(path)
+        .into_iter()
+        .map(|element| {
+            parse_element(element).ok_or_else(|| PartitionError::InvalidPathElement {
+                element: element.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Split a path on the dots that separate its elements, ignoring dots within qualifiers
+fn split_path(path: &str) -> Vec<&str> {
+    let mut elements = vec![];
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in path.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            _ if in_string => (),
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            '.' if depth == 0 => {
+                elements.push(&path[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    elements.push(&path[start..]);
+    elements
+}
+
+/// Return the index of the bracket closing the one that `s` starts with, if any
+fn closing_bracket(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            _ if in_string => (),
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Parse a single path element, returning `None` if it is invalid
+fn parse_element(element: &str) -> Option<PathElement<'_>> {
+    if element == "**" {
+        return Some(PathElement::GlobStar);
+    }
+
+    let name_end = element.find(['(', '[']).unwrap_or(element.len());
+    let (name, mut rest) = element.split_at(name_end);
+    let mut qualifiers = Qualifiers::default();
+    if rest.starts_with('(') {
+        let end = closing_bracket(rest)?;
+        qualifiers.arguments = parse_arguments(&rest[1..end])?;
+        rest = &rest[end + 1..];
+    }
+    if rest.starts_with('[') {
+        let end = closing_bracket(rest)?;
+        let type_name = rest[1..end].trim().strip_prefix("on")?;
+        if !type_name.starts_with(char::is_whitespace) {
+            return None;
+        }
+        let type_name = type_name.trim_start();
+        if !NAME.is_match(type_name) {
+            return None;
+        }
+        qualifiers.type_condition = Some(type_name);
+        rest = &rest[end + 1..];
+    }
+    if !rest.is_empty() {
+        return None;
+    }
+
+    match name {
+        "*" => Some(PathElement::Wildcard(qualifiers)),
+        name if NAME.is_match(name) => Some(PathElement::Name(name, qualifiers)),
+        _ => None,
+    }
+}
+
+/// Parse the arguments of an argument qualifier into (name, printed value) pairs. Values are
+/// printed the same way as the values in the operation, so they can be compared as strings.
+fn parse_arguments(arguments: &str) -> Option<Vec<(String, String)>> {
+    let query = format!("{{ f({}) }}", arguments);
+    let document = parse_query::<&str>(&query).ok()?;
+    let field = match document.definitions.first()? {
+        Definition::Operation(OperationDefinition::SelectionSet(selection_set)) => {
+            match selection_set.items.as_slice() {
+                [Selection::Field(field)] => field,
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    if field.arguments.is_empty() {
+        return None;
+    }
+    Some(
+        field
+            .arguments
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate_path, PathElement};
+    use graphql_parser::{
+        parse_query,
+        query::{Definition, OperationDefinition, Selection},
+    };
+
+    #[test]
+    fn validate_path_parses_qualifiers() {
+        let elements =
+            validate_path(r#"matchup.team(abbrev: "N.Y.G")[on Team].name[on Named]"#).unwrap();
+        assert_eq!(elements.len(), 3);
+        match &elements[1] {
+            PathElement::Name(name, qualifiers) => {
+                assert_eq!(*name, "team");
+                assert_eq!(
+                    qualifiers.arguments,
+                    vec![("abbrev".to_string(), "\"N.Y.G\"".to_string())]
+                );
+                assert_eq!(qualifiers.type_condition, Some("Team"));
+            }
+            element => panic!("Expected a name, got {:?}", element),
+        }
+    }
+
+    #[test]
+    fn validate_path_shows_invalid_qualifiers_as_invalid() {
+        for path in [
+            "team()",
+            "team(abbrev)",
+            "team(abbrev: \"NYG\"",
+            "team[Team]",
+            "team[on 0Team]",
+            "team[on Team](abbrev: \"NYG\")",
+            "team[on Team]x",
+            "**[on Team]",
+        ] {
+            assert!(
+                validate_path(path).is_err(),
+                "Path \"{}\" tests as invalid",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn argument_qualifiers_ignore_formatting() {
+        let document =
+            parse_query::<&str>("{ team(abbrev:   \"NYG\", filter: {b: 2, a: [1,2]}) }").unwrap();
+        let field = match &document.definitions[0] {
+            Definition::Operation(OperationDefinition::SelectionSet(selection_set)) => {
+                match &selection_set.items[0] {
+                    Selection::Field(field) => field.clone(),
+                    _ => unreachable!(),
+                }
+            }
+            _ => unreachable!(),
+        };
+        let path = validate_path("team(filter: { a: [1, 2], b: 2 })").unwrap();
+        assert!(path[0].matches(&field, None));
+        let path = validate_path("team(abbrev: \"DAL\")").unwrap();
+        assert!(!path[0].matches(&field, None));
+        let path = validate_path("team[on Team]").unwrap();
+        assert!(!path[0].matches(&field, None));
+        assert!(path[0].matches(&field, Some("Team")));
+    }
+}
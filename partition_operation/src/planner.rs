@@ -0,0 +1,607 @@
+// Copyright 2024 Aurelia Peters
+//
+// This file is part of GraphQL Operation Partitioner.
+// 
+// GraphQL Operation Partitioner is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// 
+// GraphQL Operation Partitioner is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License along with GraphQL Cacher. If not, see <https://www.gnu.org/licenses/>. 
+//! Automatic partitioning of operations according to the cache policies of their fields
+use crate::{
+    client_directives::{directive_policy, operation_parts},
+    Partition, PartitionError,
+};
+use graphql_parser::query::{
+    Definition, Directive, Document, FragmentDefinition, OperationDefinition, Selection,
+    SelectionSet, Text, TypeCondition,
+};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::{cmp::Reverse, collections::HashMap};
+
+/// How the data selected by a field may be cached, and thus how a partition holding it should be
+/// fetched. Serializes as e.g. `{"type":"public","ttl":300}` or `{"type":"private"}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Policy {
+    /// The same for every user. May be fetched via GET and cached at the edge, for `ttl` seconds
+    /// if given or for as long as the backend allows otherwise.
+    Public { ttl: Option<u32> },
+    /// Specific to the user making the request. Must be fetched for each user separately and not
+    /// cached at the edge.
+    Private,
+    /// Must never be cached
+    Uncacheable,
+}
+
+impl Policy {
+    /// Order policies from least to most restrictive. Public data with a shorter TTL is more
+    /// restrictive than public data with a longer TTL, or with none.
+    fn restrictiveness(&self) -> (u8, Reverse<u32>) {
+        match self {
+            Policy::Public { ttl } => (0, Reverse(ttl.unwrap_or(u32::MAX))),
+            Policy::Private => (1, Reverse(0)),
+            Policy::Uncacheable => (2, Reverse(0)),
+        }
+    }
+
+    /// Return the more restrictive of this policy and the given one
+    fn strictest(self, other: Policy) -> Policy {
+        if other.restrictiveness() > self.restrictiveness() {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy::Public { ttl: None }
+    }
+}
+
+/// A source of cache policies for the fields of a schema
+pub trait Policies {
+    /// The policy of the field with the given name on the given type, if it has one. Fields
+    /// without a policy inherit the policy of the field they are selected on.
+    fn field_policy(&self, type_name: &str, field_name: &str) -> Option<Policy>;
+
+    /// The name of the type of the given field, without any list or non-null wrappers, if known.
+    /// Policies can only be looked up for fields whose parent type is known: fields of the query
+    /// type, fields selected within a fragment that has a type condition, and fields of fields
+    /// whose type is known.
+    fn field_type(&self, type_name: &str, field_name: &str) -> Option<&str>;
+
+    /// The name of the query type
+    fn query_type(&self) -> &str {
+        "Query"
+    }
+
+    /// The policy of fields of the query type that have none of their own
+    fn default_policy(&self) -> Policy {
+        Policy::default()
+    }
+}
+
+/// Policies and field types held in maps keyed by `Type.field`
+///
+/// # Examples
+/// ```
+/// use partition_operation::planner::{Policies, Policy, PolicyMap};
+///
+/// let policies = PolicyMap::new(Policy::Public { ttl: Some(300) })
+///     .with_field_type("Query.matchupAnalysis", "MatchupAnalysis")
+///     .with_policy("MatchupAnalysis.somePrediction", Policy::Private);
+/// assert_eq!(
+///     Some("MatchupAnalysis"),
+///     policies.field_type("Query", "matchupAnalysis")
+/// );
+/// assert_eq!(
+///     Some(Policy::Private),
+///     policies.field_policy("MatchupAnalysis", "somePrediction")
+/// );
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct PolicyMap {
+    policies: HashMap<String, Policy>,
+    field_types: HashMap<String, String>,
+    default_policy: Policy,
+}
+
+impl PolicyMap {
+    pub fn new(default_policy: Policy) -> Self {
+        PolicyMap {
+            default_policy,
+            ..Default::default()
+        }
+    }
+
+    /// Set the policy of a field, given as `Type.field`
+    pub fn with_policy(mut self, field: &str, policy: Policy) -> Self {
+        self.policies.insert(field.to_string(), policy);
+        self
+    }
+
+    /// Set the type of a field, given as `Type.field`
+    pub fn with_field_type(mut self, field: &str, type_name: &str) -> Self {
+        self.field_types
+            .insert(field.to_string(), type_name.to_string());
+        self
+    }
+}
+
+impl Policies for PolicyMap {
+    fn field_policy(&self, type_name: &str, field_name: &str) -> Option<Policy> {
+        self.policies
+            .get(&format!("{}.{}", type_name, field_name))
+            .copied()
+    }
+
+    fn field_type(&self, type_name: &str, field_name: &str) -> Option<&str> {
+        self.field_types
+            .get(&format!("{}.{}", type_name, field_name))
+            .map(String::as_str)
+    }
+
+    fn default_policy(&self) -> Policy {
+        self.default_policy
+    }
+}
+
+/// A partition of an operation, together with the policy of everything in it
+#[derive(Debug, Clone)]
+pub struct PlannedPartition<P> {
+    pub policy: Policy,
+    pub partition: P,
+}
+
+/// Trait used to partition GraphQL operations according to the cache policies of their fields
+pub trait Plan<'a, T: Text<'a>>: Partition<'a, T> {
+    /// Partition an operation so that each partition holds the fields of one policy, using as few
+    /// partitions as possible. The policy of a field comes from `policies`, made more restrictive
+    /// by the client directives of the field and of the fields, fragments and operation it is
+    /// selected in, if they have any (see `client_directives`). Client directives can shorten a
+    /// TTL or make a field private, but never make it less restrictive than `policies` has it.
+    /// The least restrictive policy gets what is left over and comes last; the other partitions
+    /// come in the order their policies first appear in the operation. If a field and all of its
+    /// subfields have the same policy, the field is moved as a whole.
+    ///
+    /// Mutations and subscriptions are never partitioned, so they are planned as a single
+    /// uncacheable partition.
+    ///
+    /// # Examples: Plan a query
+    /// ```
+    /// use graphql_parser::parse_query;
+    /// use partition_operation::planner::{Plan, Policy, PolicyMap};
+    ///
+    /// let document = parse_query::<&str>(
+    ///     "{ matchupAnalysis { teams { name }, somePrediction { score } } }",
+    /// )
+    /// .unwrap();
+    /// let policies = PolicyMap::new(Policy::Public { ttl: Some(300) })
+    ///     .with_field_type("Query.matchupAnalysis", "MatchupAnalysis")
+    ///     .with_policy("MatchupAnalysis.somePrediction", Policy::Private);
+    /// let plan = document.plan(&policies).unwrap();
+    /// assert_eq!(plan.len(), 2);
+    /// assert_eq!(Policy::Private, plan[0].policy);
+    /// assert_eq!(
+    ///     "{\n  matchupAnalysis {\n    somePrediction {\n      score\n    }\n  }\n}\n",
+    ///     plan[0].partition.to_string()
+    /// );
+    /// assert_eq!(Policy::Public { ttl: Some(300) }, plan[1].policy);
+    /// ```
+    fn plan<P: Policies + ?Sized>(
+        self,
+        policies: &P,
+    ) -> Result<Vec<PlannedPartition<Self>>, PartitionError>;
+}
+
+impl<'a, T: Text<'a> + Clone> Plan<'a, T> for Document<'a, T> {
+    fn plan<P: Policies + ?Sized>(
+        self,
+        policies: &P,
+    ) -> Result<Vec<PlannedPartition<Self>>, PartitionError> {
+        let operations = self
+            .definitions
+            .iter()
+            .filter_map(|def| match def {
+                Definition::Operation(op_def) => Some(op_def),
+                _ => None,
+            })
+            .collect_vec();
+        let operation = match operations.as_slice() {
+            [operation] => *operation,
+            operations => {
+                return Err(PartitionError::OperationCount {
+                    count: operations.len(),
+                })
+            }
+        };
+        let fragments = self
+            .definitions
+            .iter()
+            .filter_map(|def| match def {
+                Definition::Fragment(fragment) => Some((fragment.name.as_ref(), fragment)),
+                _ => None,
+            })
+            .collect();
+        match policy_groups(operation, &fragments, policies) {
+            Some(groups) => plan_partitions(self, groups, policies),
+            None => Ok(vec![PlannedPartition {
+                policy: Policy::Uncacheable,
+                partition: self,
+            }]),
+        }
+    }
+}
+
+impl<'a, T: Text<'a> + Clone> Plan<'a, T> for OperationDefinition<'a, T> {
+    fn plan<P: Policies + ?Sized>(
+        self,
+        policies: &P,
+    ) -> Result<Vec<PlannedPartition<Self>>, PartitionError> {
+        match policy_groups(&self, &HashMap::new(), policies) {
+            Some(groups) => plan_partitions(self, groups, policies),
+            None => Ok(vec![PlannedPartition {
+                policy: Policy::Uncacheable,
+                partition: self,
+            }]),
+        }
+    }
+}
+
+/// Carve one partition per group of paths out of an operation. The least restrictive group is
+/// not carved out, but is left as the remainder, if there is anything left.
+fn plan_partitions<'a, T: Text<'a>, O: Partition<'a, T>, P: Policies + ?Sized>(
+    operation: O,
+    mut groups: Vec<(Policy, Vec<String>)>,
+    policies: &P,
+) -> Result<Vec<PlannedPartition<O>>, PartitionError> {
+    let remainder_policy = match groups
+        .iter()
+        .position_min_by_key(|(policy, _)| policy.restrictiveness())
+    {
+        Some(index) => groups.remove(index).0,
+        None => policies.default_policy(),
+    };
+    let mut planned = Vec::with_capacity(groups.len() + 1);
+    let mut remainder = Some(operation);
+    for (policy, paths) in groups {
+        let operation = remainder.ok_or_else(|| PartitionError::PathNotFound {
+            path: paths.join(", "),
+        })?;
+        let paths = paths.iter().map(String::as_str).collect_vec();
+        let (partition, right) = operation.split_by_any_path(&paths)?;
+        planned.push(PlannedPartition { policy, partition });
+        remainder = right;
+    }
+    // Nothing is left over if the groups carved out between them select everything
+    if let Some(remainder) = remainder {
+        planned.push(PlannedPartition {
+            policy: remainder_policy,
+            partition: remainder,
+        });
+    }
+    Ok(planned)
+}
+
+/// Group the paths of an operation's fields by policy, in the order the policies first appear.
+/// Returns `None` for operations other than queries.
+fn policy_groups<'a, T: Text<'a>, P: Policies + ?Sized>(
+    operation: &OperationDefinition<'a, T>,
+    fragments: &HashMap<&str, &FragmentDefinition<'a, T>>,
+    policies: &P,
+) -> Option<Vec<(Policy, Vec<String>)>> {
+    if let OperationDefinition::Mutation(_) | OperationDefinition::Subscription(_) = operation {
+        return None;
+    }
+    let (directives, selection_set) = operation_parts(operation);
+    let client_policy = directive_policy(directives);
+    let walk = Walk {
+        fragments,
+        policies,
+    };
+    let mut entries = vec![];
+    walk.selection_set(
+        selection_set,
+        &Scope {
+            parent_type: Some(policies.query_type()),
+            type_condition: None,
+            policy: policies.default_policy(),
+            client_policy,
+            prefix: "",
+        },
+        &mut vec![],
+        &mut entries,
+    );
+
+    // The same response key may be selected in several places. Its path matches all of them, so
+    // it goes with the most restrictive of their policies.
+    let mut paths: Vec<(String, Policy)> = vec![];
+    for (path, policy) in entries {
+        match paths.iter_mut().find(|(p, _)| *p == path) {
+            Some((_, existing)) => {
+                if policy.restrictiveness() > existing.restrictiveness() {
+                    *existing = policy;
+                }
+            }
+            None => paths.push((path, policy)),
+        }
+    }
+    let mut groups: Vec<(Policy, Vec<String>)> = vec![];
+    for (path, policy) in paths {
+        match groups.iter_mut().find(|(p, _)| *p == policy) {
+            Some((_, group)) => group.push(path),
+            None => groups.push((policy, vec![path])),
+        }
+    }
+    Some(groups)
+}
+
+/// What is known about the selection set being walked
+#[derive(Clone, Copy)]
+struct Scope<'s> {
+    /// The type the selections are selected on, if known
+    parent_type: Option<&'s str>,
+    /// The type condition of the innermost fragment enclosing the selections within their
+    /// parent's selection set
+    type_condition: Option<&'s str>,
+    /// The policy inherited from the parent field
+    policy: Policy,
+    /// The most restrictive of the policies set by the client directives of the selections
+    /// enclosing these, if any. Every policy within is made at least as restrictive.
+    client_policy: Option<Policy>,
+    /// The path of the parent field, followed by a dot, or nothing at the top level
+    prefix: &'s str,
+}
+
+impl<'s> Scope<'s> {
+    /// Return this scope, made at least as restrictive as the client directives among the given
+    /// directives, if there are any
+    fn with_directives<'a, T: Text<'a>>(&self, directives: &[Directive<'a, T>]) -> Self {
+        let client_policy = match (self.client_policy, directive_policy(directives)) {
+            (Some(policy), Some(other)) => Some(policy.strictest(other)),
+            (policy, other) => policy.or(other),
+        };
+        Scope {
+            policy: client_policy.map_or(self.policy, |policy| self.policy.strictest(policy)),
+            client_policy,
+            ..*self
+        }
+    }
+}
+
+struct Walk<'w, 'a, T: Text<'a>, P: ?Sized> {
+    fragments: &'w HashMap<&'w str, &'w FragmentDefinition<'a, T>>,
+    policies: &'w P,
+}
+
+impl<'w, 'a, T: Text<'a>, P: Policies + ?Sized> Walk<'w, 'a, T, P> {
+    /// Collect the paths and policies of a selection set's fields into `entries`. A field whose
+    /// subfields all have the same policy is collected as a whole. `spreads` holds the names of the
+    /// fragments being walked, so that cycles are not followed.
+    fn selection_set(
+        &self,
+        selection_set: &SelectionSet<'a, T>,
+        scope: &Scope,
+        spreads: &mut Vec<&'w str>,
+        entries: &mut Vec<(String, Policy)>,
+    ) {
+        for selection in &selection_set.items {
+            match selection {
+                Selection::Field(field) => {
+                    let name = field.name.as_ref();
+                    let key = field.alias.as_ref().unwrap_or(&field.name).as_ref();
+                    let path = match scope.type_condition {
+                        Some(type_name) => format!("{}{}[on {}]", scope.prefix, key, type_name),
+                        None => format!("{}{}", scope.prefix, key),
+                    };
+                    let field_scope = scope.with_directives(&field.directives);
+                    let schema_policy = scope
+                        .parent_type
+                        .and_then(|type_name| self.policies.field_policy(type_name, name))
+                        .unwrap_or(scope.policy);
+                    let policy = field_scope
+                        .client_policy
+                        .map_or(schema_policy, |policy| schema_policy.strictest(policy));
+                    let prefix = format!("{}.", path);
+                    let mut children = vec![];
+                    self.selection_set(
+                        &field.selection_set,
+                        &Scope {
+                            parent_type: scope
+                                .parent_type
+                                .and_then(|type_name| self.policies.field_type(type_name, name)),
+                            type_condition: None,
+                            policy,
+                            client_policy: field_scope.client_policy,
+                            prefix: &prefix,
+                        },
+                        spreads,
+                        &mut children,
+                    );
+                    if children.iter().map(|(_, policy)| policy).all_equal() {
+                        let policy = children.first().map_or(policy, |(_, policy)| *policy);
+                        entries.push((path, policy));
+                    } else {
+                        entries.extend(children);
+                    }
+                }
+                Selection::InlineFragment(inline_fragment) => {
+                    let type_condition = match &inline_fragment.type_condition {
+                        Some(TypeCondition::On(type_name)) => Some(type_name.as_ref()),
+                        None => scope.type_condition,
+                    };
+                    self.selection_set(
+                        &inline_fragment.selection_set,
+                        &Scope {
+                            parent_type: type_condition.or(scope.parent_type),
+                            type_condition,
+                            ..scope.with_directives(&inline_fragment.directives)
+                        },
+                        spreads,
+                        entries,
+                    );
+                }
+                Selection::FragmentSpread(spread) => {
+                    let fragment = match self.fragments.get(spread.fragment_name.as_ref()) {
+                        Some(fragment) if !spreads.contains(&fragment.name.as_ref()) => *fragment,
+                        _ => continue,
+                    };
+                    let TypeCondition::On(type_name) = &fragment.type_condition;
+                    spreads.push(fragment.name.as_ref());
+                    self.selection_set(
+                        &fragment.selection_set,
+                        &Scope {
+                            parent_type: Some(type_name.as_ref()),
+                            type_condition: Some(type_name.as_ref()),
+                            ..scope
+                                .with_directives(&spread.directives)
+                                .with_directives(&fragment.directives)
+                        },
+                        spreads,
+                        entries,
+                    );
+                    spreads.pop();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Plan, Policy, PolicyMap};
+    use crate::normalize::{inline_fragments, merge_fields};
+    use graphql_parser::parse_query;
+
+    fn policies() -> PolicyMap {
+        PolicyMap::new(Policy::Public { ttl: None })
+            .with_field_type("Query.matchupAnalysis", "MatchupAnalysis")
+            .with_field_type("MatchupAnalysis.viewer", "Viewer")
+            .with_field_type("Query.search", "SearchResult")
+            .with_policy("MatchupAnalysis.somePrediction", Policy::Private)
+            .with_policy("MatchupAnalysis.teams", Policy::Public { ttl: Some(60) })
+            .with_policy("Viewer.id", Policy::Private)
+            .with_policy("Viewer.picks", Policy::Uncacheable)
+            .with_policy("User.email", Policy::Private)
+    }
+
+    #[test]
+    fn plan_groups_fields_by_policy() {
+        let document = parse_query::<&str>(
+            r#"query Q {
+                matchupAnalysis {
+                    teams { name }
+                    somePrediction { score }
+                    viewer { id, picks { id }, avatar }
+                    lastUpdated
+                }
+            }"#,
+        )
+        .unwrap();
+        let plan = document.plan(&policies()).unwrap();
+        let plan = plan
+            .into_iter()
+            .map(|planned| (planned.policy, planned.partition.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (
+                    Policy::Public { ttl: Some(60) },
+                    "query Q {\n  matchupAnalysis {\n    teams {\n      name\n    }\n  }\n}\n"
+                        .to_string()
+                ),
+                (
+                    Policy::Private,
+                    "query Q {\n  matchupAnalysis {\n    somePrediction {\n      score\n    }\n    viewer {\n      id\n    }\n  }\n}\n"
+                        .to_string()
+                ),
+                (
+                    Policy::Uncacheable,
+                    "query Q {\n  matchupAnalysis {\n    viewer {\n      picks {\n        id\n      }\n    }\n  }\n}\n"
+                        .to_string()
+                ),
+                (
+                    Policy::Public { ttl: None },
+                    "query Q {\n  matchupAnalysis {\n    viewer {\n      avatar\n    }\n    lastUpdated\n  }\n}\n"
+                        .to_string()
+                ),
+            ],
+            plan
+        );
+    }
+
+    #[test]
+    fn plan_uses_type_conditions() {
+        let document = parse_query::<&str>(
+            r#"{ search { ... on Team { name }, ...U } }
+            fragment U on User { name, email }"#,
+        )
+        .unwrap();
+        let plan = document.plan(&policies()).unwrap();
+        assert_eq!(plan.len(), 2);
+        assert_eq!(Policy::Private, plan[0].policy);
+        assert_eq!(
+            "{\n  search {\n    ... on User {\n      email\n    }\n  }\n}\n",
+            plan[0].partition.to_string()
+        );
+        assert_eq!(Policy::Public { ttl: None }, plan[1].policy);
+        assert_eq!(
+            "{\n  search {\n    ... on Team {\n      name\n    }\n    ... on User {\n      name\n    }\n  }\n}\n",
+            plan[1].partition.to_string()
+        );
+    }
+
+    #[test]
+    fn plan_with_a_single_policy_does_not_partition() {
+        let query = "{ matchupAnalysis { lastUpdated, viewer { avatar } } }";
+        let plan = parse_query::<&str>(query)
+            .unwrap()
+            .plan(&policies())
+            .unwrap();
+        assert_eq!(plan.len(), 1);
+        assert_eq!(Policy::Public { ttl: None }, plan[0].policy);
+        assert_eq!(
+            parse_query::<&str>(query).unwrap().to_string(),
+            plan[0].partition.to_string()
+        );
+
+        let plan = parse_query::<&str>("mutation { matchupAnalysis { somePrediction } }")
+            .unwrap()
+            .plan(&policies())
+            .unwrap();
+        assert_eq!(plan.len(), 1);
+        assert_eq!(Policy::Uncacheable, plan[0].policy);
+    }
+
+    #[test]
+    fn plan_follows_client_directives_of_inlined_fragment_definitions() {
+        let document = parse_query::<&str>(
+            "query Q { me { ...F } teams { name } } fragment F on User @edgePrivate { email }",
+        )
+        .unwrap();
+        let normalized = merge_fields(inline_fragments(document).unwrap());
+        let plan = normalized.plan(&policies()).unwrap();
+        let plan = plan
+            .into_iter()
+            .map(|planned| (planned.policy, planned.partition.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (
+                    Policy::Private,
+                    "query Q {\n  me {\n    ... on User @edgePrivate {\n      email\n    }\n  }\n}\n"
+                        .to_string()
+                ),
+                (
+                    Policy::Public { ttl: None },
+                    "query Q {\n  teams {\n    name\n  }\n}\n".to_string()
+                ),
+            ],
+            plan
+        );
+    }
+}
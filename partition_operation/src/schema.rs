@@ -0,0 +1,278 @@
+// Copyright 2024 Aurelia Peters
+//
+// This file is part of GraphQL Operation Partitioner.
+// 
+// GraphQL Operation Partitioner is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// 
+// GraphQL Operation Partitioner is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License along with GraphQL Cacher. If not, see <https://www.gnu.org/licenses/>. 
+//! Types and cache policies of fields, read from a schema in GraphQL SDL
+use crate::planner::{Policies, Policy};
+use graphql_parser::{
+    parse_schema,
+    query::{Type, Value},
+    schema::{Definition, Directive, Field, ParseError, TypeDefinition, TypeExtension},
+};
+use std::{collections::HashMap, convert::TryFrom};
+
+/// What the partitioner needs to know about a schema: the type of each field of each object and
+/// interface type, and the cache policies given by `@cacheControl` directives. These are read as
+/// follows:
+///
+/// | Directive | Policy |
+/// | --------- | ------ |
+/// | `@cacheControl(maxAge: 0)` | `Uncacheable` |
+/// | `@cacheControl(scope: PRIVATE)` | `Private`, or `Uncacheable` if `maxAge` is 0 |
+/// | `@cacheControl(maxAge: 60)` | `Public { ttl: Some(60) }` |
+/// | `@cacheControl(inheritMaxAge: true)`, or neither `maxAge` nor `scope: PRIVATE` | None, so the policy is inherited |
+///
+/// A field without a policy of its own takes the policy of its type, if its type has one.
+#[derive(Debug, Clone, Default)]
+pub struct Schema {
+    query_type: Option<String>,
+    types: HashMap<String, ObjectInfo>,
+}
+
+/// What is known about an object, interface or union type
+#[derive(Debug, Clone, Default)]
+struct ObjectInfo {
+    policy: Option<Policy>,
+    fields: HashMap<String, FieldInfo>,
+}
+
+#[derive(Debug, Clone)]
+struct FieldInfo {
+    type_name: String,
+    policy: Option<Policy>,
+}
+
+impl Schema {
+    /// Read a schema from SDL. Type extensions are merged into the types they extend.
+    ///
+    /// # Examples
+    /// ```
+    /// use partition_operation::{
+    ///     planner::{Policies, Policy},
+    ///     schema::Schema,
+    /// };
+    ///
+    /// let schema = Schema::parse(
+    ///     r#"type Query { matchupAnalysis: MatchupAnalysis @cacheControl(maxAge: 300) }
+    ///     type MatchupAnalysis {
+    ///         somePrediction: [Prediction!] @cacheControl(scope: PRIVATE)
+    ///     }
+    ///     type Prediction { score: Int }"#,
+    /// )
+    /// .unwrap();
+    /// assert_eq!(
+    ///     Some("MatchupAnalysis"),
+    ///     schema.field_type("Query", "matchupAnalysis")
+    /// );
+    /// assert_eq!(
+    ///     Some(Policy::Public { ttl: Some(300) }),
+    ///     schema.field_policy("Query", "matchupAnalysis")
+    /// );
+    /// assert_eq!(
+    ///     Some(Policy::Private),
+    ///     schema.field_policy("MatchupAnalysis", "somePrediction")
+    /// );
+    /// ```
+    pub fn parse(sdl: &str) -> Result<Self, ParseError> {
+        let document = parse_schema::<String>(sdl)?;
+        let mut schema = Schema::default();
+        for definition in &document.definitions {
+            match definition {
+                Definition::SchemaDefinition(schema_definition) => {
+                    schema.query_type = schema_definition.query.clone()
+                }
+                Definition::TypeDefinition(TypeDefinition::Object(object)) => {
+                    schema.add_type(&object.name, &object.directives, &object.fields)
+                }
+                Definition::TypeDefinition(TypeDefinition::Interface(interface)) => {
+                    schema.add_type(&interface.name, &interface.directives, &interface.fields)
+                }
+                Definition::TypeDefinition(TypeDefinition::Union(union)) => {
+                    schema.add_type(&union.name, &union.directives, &[])
+                }
+                Definition::TypeExtension(TypeExtension::Object(object)) => {
+                    schema.add_type(&object.name, &object.directives, &object.fields)
+                }
+                Definition::TypeExtension(TypeExtension::Interface(interface)) => {
+                    schema.add_type(&interface.name, &interface.directives, &interface.fields)
+                }
+                Definition::TypeExtension(TypeExtension::Union(union)) => {
+                    schema.add_type(&union.name, &union.directives, &[])
+                }
+                _ => (),
+            }
+        }
+        Ok(schema)
+    }
+
+    fn add_type(
+        &mut self,
+        name: &str,
+        directives: &[Directive<'_, String>],
+        fields: &[Field<'_, String>],
+    ) {
+        let info = self.types.entry(name.to_string()).or_default();
+        if let Some(policy) = cache_control_policy(directives) {
+            info.policy = Some(policy);
+        }
+        for field in fields {
+            info.fields.insert(
+                field.name.clone(),
+                FieldInfo {
+                    type_name: named_type(&field.field_type).to_string(),
+                    policy: cache_control_policy(&field.directives),
+                },
+            );
+        }
+    }
+}
+
+impl Policies for Schema {
+    fn field_policy(&self, type_name: &str, field_name: &str) -> Option<Policy> {
+        let field = self.types.get(type_name)?.fields.get(field_name)?;
+        field.policy.or_else(|| {
+            self.types
+                .get(&field.type_name)
+                .and_then(|field_type| field_type.policy)
+        })
+    }
+
+    fn field_type(&self, type_name: &str, field_name: &str) -> Option<&str> {
+        self.types
+            .get(type_name)?
+            .fields
+            .get(field_name)
+            .map(|field| field.type_name.as_str())
+    }
+
+    fn query_type(&self) -> &str {
+        self.query_type.as_deref().unwrap_or("Query")
+    }
+}
+
+/// Return the name of a type, without any list or non-null wrappers
+fn named_type<'t>(field_type: &'t Type<'_, String>) -> &'t str {
+    match field_type {
+        Type::NamedType(name) => name,
+        Type::ListType(inner) | Type::NonNullType(inner) => named_type(inner),
+    }
+}
+
+/// Read the policy given by the `@cacheControl` directive among the given directives, if any
+fn cache_control_policy(directives: &[Directive<'_, String>]) -> Option<Policy> {
+    let directive = directives.iter().find(|d| d.name == "cacheControl")?;
+    let argument = |name: &str| {
+        directive
+            .arguments
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value)
+    };
+    let max_age = match argument("maxAge") {
+        Some(Value::Int(max_age)) => max_age.as_i64(),
+        _ => None,
+    };
+    let private = matches!(argument("scope"), Some(Value::Enum(scope)) if scope == "PRIVATE");
+    let inherit = matches!(argument("inheritMaxAge"), Some(Value::Boolean(true)));
+    match max_age {
+        Some(0) => Some(Policy::Uncacheable),
+        _ if private => Some(Policy::Private),
+        _ if inherit => None,
+        Some(max_age) => Some(Policy::Public {
+            ttl: u32::try_from(max_age).ok(),
+        }),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Schema;
+    use crate::planner::{Plan, Policies, Policy};
+    use graphql_parser::parse_query;
+
+    const SDL: &str = r#"
+        schema { query: Root }
+        type Root {
+            matchupAnalysis(id: ID!): MatchupAnalysis
+            search: [SearchResult!]!
+        }
+        type MatchupAnalysis @cacheControl(maxAge: 300) {
+            teams: [Team!]!
+            somePrediction: Prediction
+            lastUpdated: String @cacheControl(inheritMaxAge: true)
+        }
+        type Prediction @cacheControl(maxAge: 60, scope: PRIVATE) { score: Int }
+        type Team { name: String }
+        union SearchResult = Team | User
+        interface Node { id: ID! }
+        type User implements Node { id: ID!, name: String }
+        extend type User { email: String @cacheControl(maxAge: 0) }
+    "#;
+
+    #[test]
+    fn schema_reads_types_and_policies() {
+        let schema = Schema::parse(SDL).unwrap();
+        assert_eq!("Root", schema.query_type());
+        assert_eq!(Some("SearchResult"), schema.field_type("Root", "search"));
+        assert_eq!(Some("String"), schema.field_type("User", "email"));
+        assert_eq!(None, schema.field_type("User", "picks"));
+        assert_eq!(
+            Some(Policy::Public { ttl: Some(300) }),
+            schema.field_policy("Root", "matchupAnalysis")
+        );
+        // Taken from the type, since the field has no policy of its own
+        assert_eq!(
+            Some(Policy::Private),
+            schema.field_policy("MatchupAnalysis", "somePrediction")
+        );
+        assert_eq!(None, schema.field_policy("MatchupAnalysis", "lastUpdated"));
+        assert_eq!(None, schema.field_policy("Team", "name"));
+        assert_eq!(
+            Some(Policy::Uncacheable),
+            schema.field_policy("User", "email")
+        );
+    }
+
+    #[test]
+    fn schema_drives_planning() {
+        let schema = Schema::parse(SDL).unwrap();
+        let document = parse_query::<&str>(
+            r#"{
+                matchupAnalysis(id: 1) { teams { name }, somePrediction { score } }
+                search { ... on User { email } }
+            }"#,
+        )
+        .unwrap();
+        let plan = document
+            .plan(&schema)
+            .unwrap()
+            .into_iter()
+            .map(|planned| (planned.policy, planned.partition.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (
+                    Policy::Private,
+                    "{\n  matchupAnalysis(id: 1) {\n    somePrediction {\n      score\n    }\n  }\n}\n"
+                        .to_string()
+                ),
+                (
+                    Policy::Uncacheable,
+                    "{\n  search {\n    ... on User {\n      email\n    }\n  }\n}\n".to_string()
+                ),
+                (
+                    Policy::Public { ttl: Some(300) },
+                    "{\n  matchupAnalysis(id: 1) {\n    teams {\n      name\n    }\n  }\n}\n"
+                        .to_string()
+                ),
+            ],
+            plan
+        );
+    }
+}
@@ -0,0 +1,314 @@
+// Copyright 2024 Aurelia Peters
+//
+// This file is part of GraphQL Operation Partitioner.
+// 
+// GraphQL Operation Partitioner is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// 
+// GraphQL Operation Partitioner is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License along with GraphQL Cacher. If not, see <https://www.gnu.org/licenses/>. 
+//! Mapping of positions in printed queries back to the documents they were printed from
+use crate::comparisions::directive_signatures;
+use graphql_parser::{
+    parse_query,
+    query::{
+        Definition, Document, OperationDefinition, ParseError, Selection, SelectionSet, Text,
+        TypeCondition, VariableDefinition,
+    },
+    Pos,
+};
+use std::collections::HashMap;
+
+/// Maps positions in the text of a query back to the document the query was printed from, e.g. so
+/// that the locations of errors reported for a partition point into the client's original query.
+/// Partitioning keeps the positions of the nodes it copies, so those positions still refer to the
+/// original query text.
+///
+/// Nodes are matched by where they are in the document (their operation and the response keys and
+/// fragments leading to them) rather than by where they are in the text, so the query may have
+/// been reformatted or canonicalized after it was printed.
+///
+/// # Examples
+/// ```
+/// use graphql_parser::{parse_query, Pos};
+/// use partition_operation::{canonical::Canonicalize, source_map::SourceMap, Partition};
+///
+/// let document = parse_query::<&str>("query Q {\n  alpha {\n    one\n  }\n  beta\n}").unwrap();
+/// let (left, _) = document.partition_by_path("alpha.one").unwrap();
+/// let query = left.canonical_string();
+/// assert_eq!("query Q{alpha{one}}", query);
+/// let source_map = SourceMap::new(&left, &query).unwrap();
+/// assert_eq!(
+///     Some(Pos { line: 3, column: 5 }),
+///     source_map.original_position(Pos { line: 1, column: 15 })
+/// );
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    positions: HashMap<Pos, Pos>,
+}
+
+impl SourceMap {
+    /// Map the positions of the operations, variable definitions, selections and fragment
+    /// definitions in `query` to those of the same nodes in `document`. Nodes that `document`
+    /// does not have, or has only without a position, are not mapped. Returns an error if `query`
+    /// does not parse.
+    pub fn new<'a, T: Text<'a>>(
+        document: &Document<'a, T>,
+        query: &str,
+    ) -> Result<Self, ParseError> {
+        let mut original = HashMap::new();
+        visit_document(document, &mut |key, position| {
+            if position != Pos::default() {
+                original.entry(key).or_insert(position);
+            }
+        });
+
+        let mut positions = HashMap::new();
+        visit_document(&parse_query::<&str>(query)?, &mut |key, position| {
+            if let Some(original) = original.get(&key) {
+                positions.entry(position).or_insert(*original);
+            }
+        });
+        Ok(Self { positions })
+    }
+
+    /// Return the position in the original document of the node at the given position in the
+    /// query, if there is a node there and it could be mapped
+    pub fn original_position(&self, position: Pos) -> Option<Pos> {
+        self.positions.get(&position).copied()
+    }
+}
+
+/// Call `visit` with the key and position of every node of the document that has a position. The
+/// key identifies the node by where it is in the document.
+fn visit_document<'a, T: Text<'a>>(document: &Document<'a, T>, visit: &mut dyn FnMut(String, Pos)) {
+    for definition in &document.definitions {
+        match definition {
+            Definition::Operation(operation) => {
+                let (key, position, variable_definitions, selection_set) =
+                    operation_parts(operation);
+                visit(key.clone(), position);
+                for variable_definition in variable_definitions {
+                    visit(
+                        format!("{}${}", key, variable_definition.name.as_ref()),
+                        variable_definition.position,
+                    );
+                }
+                visit_selection_set(selection_set, &key, visit);
+            }
+            Definition::Fragment(fragment) => {
+                let key = format!("fragment {}", fragment.name.as_ref());
+                visit(key.clone(), fragment.position);
+                visit_selection_set(&fragment.selection_set, &key, visit);
+            }
+        }
+    }
+}
+
+/// Return the key, position, variable definitions and selection set of an operation. An
+/// operation is keyed by its name; an anonymous query is keyed the same whether or not it is
+/// written in the shorthand form.
+fn operation_parts<'o, 'a, T: Text<'a>>(
+    operation: &'o OperationDefinition<'a, T>,
+) -> (
+    String,
+    Pos,
+    &'o [VariableDefinition<'a, T>],
+    &'o SelectionSet<'a, T>,
+) {
+    fn key<'a, T: Text<'a>>(operation_type: &str, name: &Option<T::Value>) -> String {
+        match name {
+            Some(name) => format!("{} {}", operation_type, name.as_ref()),
+            None => operation_type.to_string(),
+        }
+    }
+    match operation {
+        OperationDefinition::SelectionSet(selection_set) => (
+            "query".to_string(),
+            selection_set.span.0,
+            &[],
+            selection_set,
+        ),
+        OperationDefinition::Query(query) => (
+            key::<T>("query", &query.name),
+            query.position,
+            &query.variable_definitions,
+            &query.selection_set,
+        ),
+        OperationDefinition::Mutation(mutation) => (
+            key::<T>("mutation", &mutation.name),
+            mutation.position,
+            &mutation.variable_definitions,
+            &mutation.selection_set,
+        ),
+        OperationDefinition::Subscription(subscription) => (
+            key::<T>("subscription", &subscription.name),
+            subscription.position,
+            &subscription.variable_definitions,
+            &subscription.selection_set,
+        ),
+    }
+}
+
+/// Call `visit` with the key and position of every selection in the selection set, at any depth.
+/// Inline fragments have no response key, so they are keyed by their type condition and their
+/// directives, and told apart from siblings that share those by their index among them. Siblings
+/// are put in order when a query is canonicalized, but those that share a type condition and
+/// directives have been merged by then, so only the fragments of queries that are not normalized
+/// need the index.
+fn visit_selection_set<'a, T: Text<'a>>(
+    selection_set: &SelectionSet<'a, T>,
+    prefix: &str,
+    visit: &mut dyn FnMut(String, Pos),
+) {
+    let mut inline_fragments = HashMap::<String, usize>::new();
+    for selection in &selection_set.items {
+        let (key, position, selection_set) = match selection {
+            Selection::Field(field) => (
+                format!(
+                    "{}.{}",
+                    prefix,
+                    field.alias.as_ref().unwrap_or(&field.name).as_ref()
+                ),
+                field.position,
+                Some(&field.selection_set),
+            ),
+            Selection::InlineFragment(inline_fragment) => {
+                let condition = format!(
+                    "on {} {}",
+                    match &inline_fragment.type_condition {
+                        Some(TypeCondition::On(type_name)) => type_name.as_ref(),
+                        None => "",
+                    },
+                    directive_signatures(inline_fragment.directives.iter()).join(" ")
+                );
+                let index = inline_fragments.entry(condition.clone()).or_default();
+                let key = format!("{}.[{} #{}]", prefix, condition, index);
+                *index += 1;
+                (
+                    key,
+                    inline_fragment.position,
+                    Some(&inline_fragment.selection_set),
+                )
+            }
+            Selection::FragmentSpread(spread) => (
+                format!("{}.[...{}]", prefix, spread.fragment_name.as_ref()),
+                spread.position,
+                None,
+            ),
+        };
+        visit(key.clone(), position);
+        if let Some(selection_set) = selection_set {
+            visit_selection_set(selection_set, &key, visit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SourceMap;
+    use crate::Partition;
+    use anyhow::Result;
+    use graphql_parser::{parse_query, Pos};
+
+    #[test]
+    fn positions_in_fragments_map_to_the_fragment_definitions() -> Result<()> {
+        let query = "query Q($id: ID!) {
+  matchup(id: $id) {
+    id
+    ...Prediction
+  }
+}
+
+fragment Prediction on Matchup {
+  prediction {
+    confidence
+  }
+  edge
+}";
+        let document = parse_query::<&str>(query)?;
+        let (left, right) = document.partition_by_path("matchup.prediction")?;
+
+        let printed = left.to_string();
+        let source_map = SourceMap::new(&left, &printed)?;
+        // "confidence" is printed on line 5 within the inline fragment replacing the spread
+        assert_eq!(
+            "        confidence",
+            printed.lines().nth(4).unwrap(),
+            "Printed LEFT:\n{}",
+            printed
+        );
+        assert_eq!(
+            Some(Pos {
+                line: 10,
+                column: 5
+            }),
+            source_map.original_position(Pos { line: 5, column: 9 })
+        );
+
+        let printed = right.to_string();
+        let source_map = SourceMap::new(&right, &printed)?;
+        assert_eq!(
+            Some(Pos { line: 1, column: 9 }),
+            source_map.original_position(Pos { line: 1, column: 9 }),
+            "Variable definition"
+        );
+        assert_eq!(
+            Some(Pos { line: 3, column: 5 }),
+            source_map.original_position(Pos { line: 3, column: 5 }),
+            "Field selected directly"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn sibling_inline_fragments_on_the_same_type_map_apart() -> Result<()> {
+        let document =
+            parse_query::<&str>("{\n  node {\n    ... on T { a }\n    ... on T { b }\n  }\n}")?;
+        let source_map = SourceMap::new(&document, "{node{...on T{a}...on T{b}}}")?;
+        assert_eq!(
+            Some(Pos {
+                line: 3,
+                column: 16
+            }),
+            source_map.original_position(Pos {
+                line: 1,
+                column: 15
+            })
+        );
+        assert_eq!(
+            Some(Pos {
+                line: 4,
+                column: 16
+            }),
+            source_map.original_position(Pos {
+                line: 1,
+                column: 25
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_positions_are_not_mapped() -> Result<()> {
+        let document = parse_query::<&str>("{ alpha }")?;
+        let source_map = SourceMap::new(&document, "{ alpha, beta }")?;
+        assert_eq!(
+            Some(Pos { line: 1, column: 3 }),
+            source_map.original_position(Pos { line: 1, column: 3 })
+        );
+        assert_eq!(
+            None,
+            source_map.original_position(Pos {
+                line: 1,
+                column: 10
+            })
+        );
+        assert_eq!(
+            None,
+            source_map.original_position(Pos { line: 1, column: 4 })
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,207 @@
+// Copyright 2024 Aurelia Peters
+//
+// This file is part of GraphQL Operation Partitioner.
+// 
+// GraphQL Operation Partitioner is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// 
+// GraphQL Operation Partitioner is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License along with GraphQL Cacher. If not, see <https://www.gnu.org/licenses/>. 
+//! Helpers for working with the variables used by a GraphQL operation
+use std::collections::HashSet;
+
+use graphql_parser::query::{
+    Directive, FragmentDefinition, OperationDefinition, Selection, SelectionSet, Text, Value,
+};
+
+/// Return the names of all variables referenced by the given operation, in its own directives and
+/// in the arguments and directives of its selection set, including those of any fragments it
+/// spreads. Returns `None` if the operation spreads a fragment that is not among the given
+/// fragment definitions, since the variables that fragment uses cannot be known.
+pub(crate) fn used_variables<'o, 'a, T: Text<'a>>(
+    operation: &'o OperationDefinition<'a, T>,
+    fragments: &'o [FragmentDefinition<'a, T>],
+) -> Option<HashSet<&'o str>> {
+    let mut used = HashSet::new();
+    let mut visited_fragments = HashSet::new();
+    let selection_set = match operation {
+        OperationDefinition::SelectionSet(selection_set) => selection_set,
+        OperationDefinition::Query(query) => {
+            collect_directive_variables(&query.directives, &mut used);
+            &query.selection_set
+        }
+        OperationDefinition::Mutation(mutation) => {
+            collect_directive_variables(&mutation.directives, &mut used);
+            &mutation.selection_set
+        }
+        OperationDefinition::Subscription(subscription) => {
+            collect_directive_variables(&subscription.directives, &mut used);
+            &subscription.selection_set
+        }
+    };
+    collect_selection_set_variables(selection_set, fragments, &mut visited_fragments, &mut used)?;
+    Some(used)
+}
+
+/// Remove the definitions of any variables that the given operation does not use. If the
+/// variables the operation uses cannot be determined, the definitions are left untouched.
+pub(crate) fn prune_variable_definitions<'a, T: Text<'a>>(
+    operation: &mut OperationDefinition<'a, T>,
+    fragments: &[FragmentDefinition<'a, T>],
+) {
+    let used: HashSet<String> = match used_variables(operation, fragments) {
+        Some(used) => used.into_iter().map(String::from).collect(),
+        None => return,
+    };
+    let variable_definitions = match operation {
+        OperationDefinition::SelectionSet(_) => return,
+        OperationDefinition::Query(query) => &mut query.variable_definitions,
+        OperationDefinition::Mutation(mutation) => &mut mutation.variable_definitions,
+        OperationDefinition::Subscription(subscription) => &mut subscription.variable_definitions,
+    };
+    variable_definitions.retain(|definition| used.contains(definition.name.as_ref()));
+}
+
+fn collect_selection_set_variables<'o, 'a, T: Text<'a>>(
+    selection_set: &'o SelectionSet<'a, T>,
+    fragments: &'o [FragmentDefinition<'a, T>],
+    visited_fragments: &mut HashSet<&'o str>,
+    used: &mut HashSet<&'o str>,
+) -> Option<()> {
+    for selection in &selection_set.items {
+        match selection {
+            Selection::Field(field) => {
+                for (_, value) in &field.arguments {
+                    collect_value_variables(value, used);
+                }
+                collect_directive_variables(&field.directives, used);
+                collect_selection_set_variables(
+                    &field.selection_set,
+                    fragments,
+                    visited_fragments,
+                    used,
+                )?;
+            }
+            Selection::InlineFragment(inline_fragment) => {
+                collect_directive_variables(&inline_fragment.directives, used);
+                collect_selection_set_variables(
+                    &inline_fragment.selection_set,
+                    fragments,
+                    visited_fragments,
+                    used,
+                )?;
+            }
+            Selection::FragmentSpread(spread) => {
+                collect_directive_variables(&spread.directives, used);
+                if visited_fragments.insert(spread.fragment_name.as_ref()) {
+                    let fragment = fragments.iter().find(|f| f.name == spread.fragment_name)?;
+                    collect_directive_variables(&fragment.directives, used);
+                    collect_selection_set_variables(
+                        &fragment.selection_set,
+                        fragments,
+                        visited_fragments,
+                        used,
+                    )?;
+                }
+            }
+        }
+    }
+    Some(())
+}
+
+fn collect_directive_variables<'o, 'a, T: Text<'a>>(
+    directives: &'o [Directive<'a, T>],
+    used: &mut HashSet<&'o str>,
+) {
+    for directive in directives {
+        for (_, value) in &directive.arguments {
+            collect_value_variables(value, used);
+        }
+    }
+}
+
+fn collect_value_variables<'o, 'a, T: Text<'a>>(
+    value: &'o Value<'a, T>,
+    used: &mut HashSet<&'o str>,
+) {
+    match value {
+        Value::Variable(name) => {
+            used.insert(name.as_ref());
+        }
+        Value::List(values) => {
+            for value in values {
+                collect_value_variables(value, used);
+            }
+        }
+        Value::Object(fields) => {
+            for value in fields.values() {
+                collect_value_variables(value, used);
+            }
+        }
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{prune_variable_definitions, used_variables};
+    use graphql_parser::query::{parse_query, Definition, OperationDefinition};
+    use std::collections::HashSet;
+
+    #[test]
+    fn used_variables_looks_in_arguments_directives_and_fragments() {
+        let doc = parse_query::<&str>(
+            r#"query Q($a: ID, $b: [ID], $c: Boolean, $d: Int, $unused: Int) {
+                alpha(id: $a) { ...Beta }
+                gamma @include(if: $c)
+            }
+            fragment Beta on Alpha { beta(ids: $b, filter: { limit: $d }) }"#,
+        )
+        .unwrap();
+        let (operation, fragments) = split(doc);
+        let used = used_variables(&operation, &fragments).unwrap();
+        assert_eq!(used, HashSet::from(["a", "b", "c", "d"]));
+    }
+
+    #[test]
+    fn used_variables_is_unknown_for_undefined_fragments() {
+        let doc = parse_query::<&str>("query Q($a: ID) { alpha(id: $a) { ...Beta } }").unwrap();
+        let (operation, fragments) = split(doc);
+        assert_eq!(used_variables(&operation, &fragments), None);
+    }
+
+    #[test]
+    fn prune_variable_definitions_removes_unused_definitions() {
+        let doc = parse_query::<&str>("query Q($a: ID, $unused: Int) { alpha(id: $a) }").unwrap();
+        let (mut operation, fragments) = split(doc);
+        prune_variable_definitions(&mut operation, &fragments);
+        match operation {
+            OperationDefinition::Query(query) => {
+                let names = query
+                    .variable_definitions
+                    .iter()
+                    .map(|v| v.name)
+                    .collect::<Vec<_>>();
+                assert_eq!(names, vec!["a"]);
+            }
+            _ => panic!("Expected a query"),
+        }
+    }
+
+    fn split<'a>(
+        doc: graphql_parser::query::Document<'a, &'a str>,
+    ) -> (
+        OperationDefinition<'a, &'a str>,
+        Vec<graphql_parser::query::FragmentDefinition<'a, &'a str>>,
+    ) {
+        let mut operation = None;
+        let mut fragments = vec![];
+        for def in doc.definitions {
+            match def {
+                Definition::Operation(op) => operation = Some(op),
+                Definition::Fragment(fragment) => fragments.push(fragment),
+            }
+        }
+        (operation.unwrap(), fragments)
+    }
+}
@@ -0,0 +1,487 @@
+// Copyright 2024 Aurelia Peters
+//
+// This file is part of GraphQL Operation Partitioner.
+// 
+// GraphQL Operation Partitioner is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// 
+// GraphQL Operation Partitioner is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License along with GraphQL Cacher. If not, see <https://www.gnu.org/licenses/>. 
+//! Walking and transforming operations with visitors. A walk calls a visitor on entering and
+//! leaving each node of an operation, keeping track of where in the operation it is.
+use crate::{operation_selection_set, operation_selection_set_mut};
+use graphql_parser::query::{
+    Definition, Document, Field, FragmentSpread, InlineFragment, OperationDefinition, Selection,
+    SelectionSet, Text, TypeCondition,
+};
+
+/// Whether a walk should go on into the node just entered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    /// Visit what is beneath the node
+    Continue,
+    /// Skip what is beneath the node. It is still left as usual.
+    Skip,
+}
+
+/// Where a walk is in an operation
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Context {
+    path: Vec<String>,
+    type_condition: Option<String>,
+}
+
+impl Context {
+    /// The elements of the path to the node, one for each field leading to it, in the syntax
+    /// described under "Query Path Syntax" in README.md: the field's response key, followed by
+    /// a `[on Type]` qualifier if it is selected within a fragment with a type condition. The
+    /// path to a field includes the field itself; the path to a selection set is the path to its
+    /// field.
+    pub fn path(&self) -> &[String] {
+        &self.path
+    }
+
+    /// The type condition of the innermost fragment the node is in, within the selection set of
+    /// its field or operation. A field's selection set starts over without one. An inline
+    /// fragment without a type condition keeps the enclosing one.
+    pub fn type_condition(&self) -> Option<&str> {
+        self.type_condition.as_deref()
+    }
+
+    fn enter_field<'a, T: Text<'a>>(&mut self, field: &Field<'a, T>) {
+        let key = field.alias.as_ref().unwrap_or(&field.name).as_ref();
+        self.path.push(match &self.type_condition {
+            Some(type_name) => format!("{}[on {}]", key, type_name),
+            None => key.to_string(),
+        });
+    }
+
+    /// Enter a fragment, returning the type condition to restore on leaving it
+    fn enter_fragment<'a, T: Text<'a>>(
+        &mut self,
+        type_condition: &Option<TypeCondition<'a, T>>,
+    ) -> Option<String> {
+        match type_condition {
+            Some(TypeCondition::On(type_name)) => {
+                self.type_condition.replace(type_name.as_ref().to_string())
+            }
+            None => self.type_condition.clone(),
+        }
+    }
+}
+
+/// A visitor of the nodes of an operation. Every method does nothing by default, so a visitor
+/// implements only those for the nodes it is interested in. Each `enter_` method is matched by a
+/// `leave_` method, which is called even if the walk skipped what is beneath the node. The empty
+/// selection sets of leaf fields are not visited.
+///
+/// # Examples
+/// ```
+/// use graphql_parser::{parse_query, query::{Field, Text}};
+/// use partition_operation::visit::{walk_document, Context, Control, Visitor};
+///
+/// /// Collects the path of every leaf field
+/// struct Leaves(Vec<String>);
+///
+/// impl<'a, T: Text<'a>> Visitor<'a, T> for Leaves {
+///     fn enter_field(&mut self, field: &Field<'a, T>, context: &Context) -> Control {
+///         if field.selection_set.items.is_empty() {
+///             self.0.push(context.path().join("."));
+///         }
+///         Control::Continue
+///     }
+/// }
+///
+/// let document = parse_query::<&str>("{ team { name, ... on Team { logo: image } } }").unwrap();
+/// let mut leaves = Leaves(vec![]);
+/// walk_document(&document, &mut leaves);
+/// assert_eq!(leaves.0, vec!["team.name", "team.logo[on Team]"]);
+/// ```
+pub trait Visitor<'a, T: Text<'a>> {
+    fn enter_operation(
+        &mut self,
+        _operation: &OperationDefinition<'a, T>,
+        _context: &Context,
+    ) -> Control {
+        Control::Continue
+    }
+
+    fn leave_operation(&mut self, _operation: &OperationDefinition<'a, T>, _context: &Context) {}
+
+    fn enter_selection_set(
+        &mut self,
+        _selection_set: &SelectionSet<'a, T>,
+        _context: &Context,
+    ) -> Control {
+        Control::Continue
+    }
+
+    fn leave_selection_set(&mut self, _selection_set: &SelectionSet<'a, T>, _context: &Context) {}
+
+    fn enter_field(&mut self, _field: &Field<'a, T>, _context: &Context) -> Control {
+        Control::Continue
+    }
+
+    fn leave_field(&mut self, _field: &Field<'a, T>, _context: &Context) {}
+
+    /// Fragment spreads are not followed into their fragment definitions, so there is nothing
+    /// beneath them to enter
+    fn fragment_spread(&mut self, _spread: &FragmentSpread<'a, T>, _context: &Context) {}
+
+    fn enter_inline_fragment(
+        &mut self,
+        _inline_fragment: &InlineFragment<'a, T>,
+        _context: &Context,
+    ) -> Control {
+        Control::Continue
+    }
+
+    fn leave_inline_fragment(
+        &mut self,
+        _inline_fragment: &InlineFragment<'a, T>,
+        _context: &Context,
+    ) {
+    }
+}
+
+/// A visitor that may change the nodes of an operation as it goes. Changes made on entering a
+/// node are seen by the rest of the walk: e.g. selections added to a selection set on entering
+/// it are visited in turn. See [`Visitor`].
+///
+/// # Examples
+/// ```
+/// use graphql_parser::{parse_query, query::{Selection, SelectionSet, Text}};
+/// use partition_operation::visit::{walk_document_mut, Context, Control, VisitorMut};
+///
+/// /// Removes every field named "secret"
+/// struct RemoveSecrets;
+///
+/// impl<'a, T: Text<'a>> VisitorMut<'a, T> for RemoveSecrets {
+///     fn enter_selection_set(
+///         &mut self,
+///         selection_set: &mut SelectionSet<'a, T>,
+///         _context: &Context,
+///     ) -> Control {
+///         selection_set.items.retain(|selection| match selection {
+///             Selection::Field(field) => field.name.as_ref() != "secret",
+///             _ => true,
+///         });
+///         Control::Continue
+///     }
+/// }
+///
+/// let mut document = parse_query::<&str>("{ team { name, secret }, secret }").unwrap();
+/// walk_document_mut(&mut document, &mut RemoveSecrets);
+/// assert_eq!(document.to_string(), "{\n  team {\n    name\n  }\n}\n");
+/// ```
+pub trait VisitorMut<'a, T: Text<'a>> {
+    fn enter_operation(
+        &mut self,
+        _operation: &mut OperationDefinition<'a, T>,
+        _context: &Context,
+    ) -> Control {
+        Control::Continue
+    }
+
+    fn leave_operation(&mut self, _operation: &mut OperationDefinition<'a, T>, _context: &Context) {
+    }
+
+    fn enter_selection_set(
+        &mut self,
+        _selection_set: &mut SelectionSet<'a, T>,
+        _context: &Context,
+    ) -> Control {
+        Control::Continue
+    }
+
+    fn leave_selection_set(
+        &mut self,
+        _selection_set: &mut SelectionSet<'a, T>,
+        _context: &Context,
+    ) {
+    }
+
+    fn enter_field(&mut self, _field: &mut Field<'a, T>, _context: &Context) -> Control {
+        Control::Continue
+    }
+
+    fn leave_field(&mut self, _field: &mut Field<'a, T>, _context: &Context) {}
+
+    /// Fragment spreads are not followed into their fragment definitions, so there is nothing
+    /// beneath them to enter
+    fn fragment_spread(&mut self, _spread: &mut FragmentSpread<'a, T>, _context: &Context) {}
+
+    fn enter_inline_fragment(
+        &mut self,
+        _inline_fragment: &mut InlineFragment<'a, T>,
+        _context: &Context,
+    ) -> Control {
+        Control::Continue
+    }
+
+    fn leave_inline_fragment(
+        &mut self,
+        _inline_fragment: &mut InlineFragment<'a, T>,
+        _context: &Context,
+    ) {
+    }
+}
+
+/// Walk every operation of a document. Fragment definitions are not walked on their own; see
+/// [`Visitor::fragment_spread`].
+pub fn walk_document<'a, T: Text<'a>, V: Visitor<'a, T> + ?Sized>(
+    document: &Document<'a, T>,
+    visitor: &mut V,
+) {
+    for definition in &document.definitions {
+        if let Definition::Operation(operation) = definition {
+            walk_operation(operation, visitor);
+        }
+    }
+}
+
+/// Walk an operation
+pub fn walk_operation<'a, T: Text<'a>, V: Visitor<'a, T> + ?Sized>(
+    operation: &OperationDefinition<'a, T>,
+    visitor: &mut V,
+) {
+    let context = Context::default();
+    if visitor.enter_operation(operation, &context) == Control::Continue {
+        walk_selection_set(operation_selection_set(operation), visitor);
+    }
+    visitor.leave_operation(operation, &context);
+}
+
+/// Walk a selection set, as if it were the selection set of an operation
+pub fn walk_selection_set<'a, T: Text<'a>, V: Visitor<'a, T> + ?Sized>(
+    selection_set: &SelectionSet<'a, T>,
+    visitor: &mut V,
+) {
+    visit_selection_set(selection_set, visitor, &mut Context::default());
+}
+
+fn visit_selection_set<'a, T: Text<'a>, V: Visitor<'a, T> + ?Sized>(
+    selection_set: &SelectionSet<'a, T>,
+    visitor: &mut V,
+    context: &mut Context,
+) {
+    if visitor.enter_selection_set(selection_set, context) == Control::Continue {
+        for selection in &selection_set.items {
+            match selection {
+                Selection::Field(field) => {
+                    context.enter_field(field);
+                    if visitor.enter_field(field, context) == Control::Continue
+                        && !field.selection_set.items.is_empty()
+                    {
+                        let type_condition = context.type_condition.take();
+                        visit_selection_set(&field.selection_set, visitor, context);
+                        context.type_condition = type_condition;
+                    }
+                    visitor.leave_field(field, context);
+                    context.path.pop();
+                }
+                Selection::FragmentSpread(spread) => visitor.fragment_spread(spread, context),
+                Selection::InlineFragment(inline_fragment) => {
+                    if visitor.enter_inline_fragment(inline_fragment, context) == Control::Continue
+                    {
+                        let type_condition =
+                            context.enter_fragment(&inline_fragment.type_condition);
+                        visit_selection_set(&inline_fragment.selection_set, visitor, context);
+                        context.type_condition = type_condition;
+                    }
+                    visitor.leave_inline_fragment(inline_fragment, context);
+                }
+            }
+        }
+    }
+    visitor.leave_selection_set(selection_set, context);
+}
+
+/// Walk every operation of a document, allowing the visitor to change it. Fragment definitions
+/// are not walked on their own; see [`VisitorMut::fragment_spread`].
+pub fn walk_document_mut<'a, T: Text<'a>, V: VisitorMut<'a, T> + ?Sized>(
+    document: &mut Document<'a, T>,
+    visitor: &mut V,
+) {
+    for definition in &mut document.definitions {
+        if let Definition::Operation(operation) = definition {
+            walk_operation_mut(operation, visitor);
+        }
+    }
+}
+
+/// Walk an operation, allowing the visitor to change it
+pub fn walk_operation_mut<'a, T: Text<'a>, V: VisitorMut<'a, T> + ?Sized>(
+    operation: &mut OperationDefinition<'a, T>,
+    visitor: &mut V,
+) {
+    let context = Context::default();
+    if visitor.enter_operation(operation, &context) == Control::Continue {
+        walk_selection_set_mut(operation_selection_set_mut(operation), visitor);
+    }
+    visitor.leave_operation(operation, &context);
+}
+
+/// Walk a selection set as if it were the selection set of an operation, allowing the visitor to
+/// change it
+pub fn walk_selection_set_mut<'a, T: Text<'a>, V: VisitorMut<'a, T> + ?Sized>(
+    selection_set: &mut SelectionSet<'a, T>,
+    visitor: &mut V,
+) {
+    visit_selection_set_mut(selection_set, visitor, &mut Context::default());
+}
+
+fn visit_selection_set_mut<'a, T: Text<'a>, V: VisitorMut<'a, T> + ?Sized>(
+    selection_set: &mut SelectionSet<'a, T>,
+    visitor: &mut V,
+    context: &mut Context,
+) {
+    if visitor.enter_selection_set(selection_set, context) == Control::Continue {
+        for selection in &mut selection_set.items {
+            match selection {
+                Selection::Field(field) => {
+                    context.enter_field(field);
+                    if visitor.enter_field(field, context) == Control::Continue
+                        && !field.selection_set.items.is_empty()
+                    {
+                        let type_condition = context.type_condition.take();
+                        visit_selection_set_mut(&mut field.selection_set, visitor, context);
+                        context.type_condition = type_condition;
+                    }
+                    visitor.leave_field(field, context);
+                    context.path.pop();
+                }
+                Selection::FragmentSpread(spread) => visitor.fragment_spread(spread, context),
+                Selection::InlineFragment(inline_fragment) => {
+                    if visitor.enter_inline_fragment(inline_fragment, context) == Control::Continue
+                    {
+                        let type_condition =
+                            context.enter_fragment(&inline_fragment.type_condition);
+                        visit_selection_set_mut(
+                            &mut inline_fragment.selection_set,
+                            visitor,
+                            context,
+                        );
+                        context.type_condition = type_condition;
+                    }
+                    visitor.leave_inline_fragment(inline_fragment, context);
+                }
+            }
+        }
+    }
+    visitor.leave_selection_set(selection_set, context);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{walk_operation, Context, Control, Visitor};
+    use crate::Operations;
+    use graphql_parser::{
+        parse_query,
+        query::{Field, FragmentSpread, InlineFragment, SelectionSet, Text},
+    };
+
+    /// Records every call, with the path and type condition at the time
+    #[derive(Default)]
+    struct Recorder {
+        calls: Vec<String>,
+        skip: Option<&'static str>,
+    }
+
+    impl Recorder {
+        fn record(&mut self, call: &str, context: &Context) {
+            self.calls.push(format!(
+                "{} {} {}",
+                call,
+                context.path().join("."),
+                context.type_condition().unwrap_or("-")
+            ));
+        }
+    }
+
+    impl<'a, T: Text<'a>> Visitor<'a, T> for Recorder {
+        fn enter_selection_set(&mut self, _: &SelectionSet<'a, T>, context: &Context) -> Control {
+            self.record("{", context);
+            Control::Continue
+        }
+
+        fn leave_selection_set(&mut self, _: &SelectionSet<'a, T>, context: &Context) {
+            self.record("}", context);
+        }
+
+        fn enter_field(&mut self, field: &Field<'a, T>, context: &Context) -> Control {
+            self.record("field", context);
+            if Some(field.name.as_ref()) == self.skip {
+                Control::Skip
+            } else {
+                Control::Continue
+            }
+        }
+
+        fn fragment_spread(&mut self, spread: &FragmentSpread<'a, T>, context: &Context) {
+            self.record(&format!("...{}", spread.fragment_name.as_ref()), context);
+        }
+
+        fn enter_inline_fragment(
+            &mut self,
+            _: &InlineFragment<'a, T>,
+            context: &Context,
+        ) -> Control {
+            self.record("...", context);
+            Control::Continue
+        }
+    }
+
+    #[test]
+    fn walk_tracks_path_and_type_condition() {
+        let operation = parse_query::<&str>(
+            "{ team { ... on Team { logo { url }, ... @include(if: true) { name } }, ...F } }",
+        )
+        .unwrap()
+        .operations()
+        .pop()
+        .unwrap();
+        let mut recorder = Recorder::default();
+        walk_operation(&operation, &mut recorder);
+        assert_eq!(
+            recorder.calls,
+            vec![
+                "{  -",
+                "field team -",
+                "{ team -",
+                "... team -",
+                "{ team Team",
+                "field team.logo[on Team] Team",
+                "{ team.logo[on Team] -",
+                "field team.logo[on Team].url -",
+                "} team.logo[on Team] -",
+                "... team Team",
+                "{ team Team",
+                "field team.name[on Team] Team",
+                "} team Team",
+                "} team Team",
+                "...F team -",
+                "} team -",
+                "}  -",
+            ]
+        );
+    }
+
+    #[test]
+    fn skip_does_not_visit_beneath_a_node() {
+        let operation = parse_query::<&str>("{ team { name }, season }")
+            .unwrap()
+            .operations()
+            .pop()
+            .unwrap();
+        let mut recorder = Recorder {
+            skip: Some("team"),
+            ..Recorder::default()
+        };
+        walk_operation(&operation, &mut recorder);
+        assert_eq!(
+            recorder.calls,
+            vec!["{  -", "field team -", "field season -", "}  -"]
+        );
+    }
+}
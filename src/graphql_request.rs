@@ -11,13 +11,17 @@ use std::collections::BTreeMap;
 
 use crate::headers::Headers;
 use fastly::{http::HeaderValue, Error, Request};
-use graphql_parser::query::{Definition, Document, FragmentDefinition, OperationDefinition};
+use graphql_parser::{
+    parse_query,
+    query::{Definition, Document, OperationDefinition},
+};
+use partition_operation::{canonical::Canonicalize, client_directives::strip_client_directives};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{Map, Value};
 // use tracing::debug;
 // use tracing::instrument;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct GraphqlRequest {
     pub query: Option<String>,
@@ -27,21 +31,29 @@ pub struct GraphqlRequest {
     pub extensions: Option<Value>,
 }
 impl GraphqlRequest {
+    /// Create a request for the given document. Only those of the given variables that the
+    /// document's operation defines are included in the request. Client directives such as
+    /// `@edgePrivate` are meant for this application only, so they are stripped from the document.
     // #[instrument (level="trace")]
-    pub fn from_operation_definition<'a>(
-        op_def: OperationDefinition<'a, &'a str>,
-        fragments: Vec<FragmentDefinition<'a, &'a str>>,
+    pub fn from_document<'a>(
+        mut document: Document<'a, &'a str>,
         variables: Option<Value>,
     ) -> Self {
-        //        println!("In GraphqlRequest::from_operation_definition");
-        let operation_name = match op_def {
-            OperationDefinition::Query(ref query) => query.name.map(|s| s.to_string()),
+        //        println!("In GraphqlRequest::from_document");
+        strip_client_directives(&mut document);
+        let operation = document.definitions.iter().find_map(|def| match def {
+            Definition::Operation(operation) => Some(operation),
+            _ => None,
+        });
+        let operation_name = match operation {
+            Some(OperationDefinition::Query(query)) => query.name.map(|s| s.to_string()),
             _ => None,
         };
         //        println!("Operation name: {:?}", &operation_name);
-        let mut definitions = vec![Definition::Operation(op_def)];
-        definitions.extend(fragments.into_iter().map(Definition::Fragment));
-        let document = Document { definitions };
+        let variables = variables.and_then(|variables| match operation {
+            Some(operation) => defined_variables(operation, variables),
+            None => Some(variables),
+        });
         Self {
             query: Some(document.to_string()),
             variables,
@@ -67,8 +79,20 @@ impl GraphqlRequest {
     ) -> Result<Request, Error> {
         // println!("*** In GraphqlRequest::get. Headers: {:?}\nSubscriber? {:?}", &headers, &is_subscriber);
         let mut query_params = BTreeMap::new();
+        // Send the canonical form of the query so that requests for the same data share a URL,
+        // and thus a cache entry. A query that does not parse is sent as is for the backend to
+        // reject.
+        let mut fingerprint = None;
         if let Some(query) = self.query {
-            query_params.insert("query", query);
+            match parse_query::<&str>(&query) {
+                Ok(document) => {
+                    query_params.insert("query", document.canonical_string());
+                    fingerprint = Some(document.fingerprint().to_string());
+                }
+                Err(_) => {
+                    query_params.insert("query", query);
+                }
+            }
         }
         if let Some(variables) = self.variables {
             query_params.insert("variables", variables.to_string());
@@ -81,13 +105,25 @@ impl GraphqlRequest {
         }
 
         let operation_name = self.operation_name.unwrap_or_else(|| "".to_string());
+        // Surrogate keys are space separated, so cached responses can be purged by operation name
+        // or by fingerprint
+        let surrogate_keys = [Some(operation_name.as_str()), fingerprint.as_deref()]
+            .iter()
+            .flatten()
+            .filter(|key| !key.is_empty())
+            .copied()
+            .collect::<Vec<_>>()
+            .join(" ");
         // println!("Set surrogate key {}", operation_name);
         // debug!("Set surrogate key {}", operation_name);
         // use a dummy URL here; the real URL will be supplied on sending by the backend
         let mut request = Request::get("https://localhost/graphql")
             .with_query(&query_params)?
-            .with_surrogate_key(HeaderValue::from_str(operation_name.as_str())?)
+            .with_surrogate_key(HeaderValue::from_str(surrogate_keys.as_str())?)
             .with_header("X-Operation-Name", operation_name.as_str());
+        if let Some(fingerprint) = fingerprint {
+            request.set_header("X-Operation-Fingerprint", fingerprint);
+        }
 
         for header in headers.get_headers() {
             let values = headers.get_header(*header).unwrap();
@@ -118,6 +154,34 @@ impl GraphqlRequest {
     }
 }
 
+/// Filter the given variables down to those defined by the given operation. Returns `None` if
+/// none of the variables are defined.
+fn defined_variables<'a>(
+    operation: &OperationDefinition<'a, &'a str>,
+    variables: Value,
+) -> Option<Value> {
+    let variable_definitions = match operation {
+        OperationDefinition::SelectionSet(_) => return None,
+        OperationDefinition::Query(query) => &query.variable_definitions,
+        OperationDefinition::Mutation(mutation) => &mutation.variable_definitions,
+        OperationDefinition::Subscription(subscription) => &subscription.variable_definitions,
+    };
+    match variables {
+        Value::Object(variables) => {
+            let defined = variables
+                .into_iter()
+                .filter(|(name, _)| variable_definitions.iter().any(|v| v.name == name))
+                .collect::<Map<String, Value>>();
+            if defined.is_empty() {
+                None
+            } else {
+                Some(Value::Object(defined))
+            }
+        }
+        variables => Some(variables),
+    }
+}
+
 impl std::fmt::Display for GraphqlRequest {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", serde_json::to_string(self).unwrap())
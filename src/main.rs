@@ -13,14 +13,20 @@ use backend_response::{BackendResponse, GraphqlErrors};
 use fastly::http::{Method, StatusCode};
 use fastly::limits::RequestLimits;
 use fastly::{Error, Request, Response};
-use graphql_parser::query::{Definition, FragmentDefinition};
+use graphql_parser::query::Definition;
 use graphql_parser::{
     parse_query,
     query::{Document, OperationDefinition},
 };
 use graphql_request::GraphqlRequest;
-use itertools::{Either, Itertools};
+use itertools::Itertools;
 use lazy_static::lazy_static;
+use partition_operation::{
+    analysis::leaf_paths,
+    canonical::Canonicalize,
+    client_directives::{has_client_directives, strip_client_directives},
+    schema::Schema,
+};
 use serde_json::{json, Value};
 use std::collections::HashMap;
 use tempus_fugit::{measure, Duration};
@@ -33,9 +39,13 @@ mod backend_response;
 mod graphql_request;
 mod headers;
 mod json_merge;
+mod partition_plan;
+mod response_order;
+mod verification;
 mod worker;
 use headers::Headers;
-use worker::Worker;
+use response_order::order_response_keys;
+use worker::{Partitioning, Worker};
 
 use crate::backend::BackendType;
 
@@ -44,6 +54,10 @@ const SUBSCRIBER_STATUS_QUERY: &str = "{ currentUser { isSportslineSubscriber }
 const LOGGING_ENDPOINT: &str = "New Relic";
 const LOG_LEVEL: LevelFilter = LevelFilter::INFO;
 const LONG_QUERY_TIME_MS: i64 = 500; // Queries (that we process) exceeding this length will be logged as "long" queries
+const VERIFY_PARTITIONS: bool = false; // Verify every partitioned request, not just those with the header below
+const VERIFY_HEADER: &str = "X-GraphQL-Cacher-Verify"; // Requests with this header have their partitions verified
+const FIELD_PATH_SAMPLE_RATE: f64 = 0.0; // Fraction of operations whose leaf field paths are logged, from 0 (none) to 1 (all)
+const PLAN_CACHE_TTL_SECONDS: u64 = 3600; // How long partition plans are cached for, by operation fingerprint
 
 pub trait HeaderMap {
     fn headers_as_hash_map(&self) -> HashMap<&str, String>;
@@ -74,6 +88,7 @@ enum HowToProcess {
     DoNotProcess,
     Partition,
     DoNotPartition,
+    Plan,
 }
 impl std::fmt::Display for HowToProcess {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -81,25 +96,21 @@ impl std::fmt::Display for HowToProcess {
             HowToProcess::DoNotProcess => "Do Not Process",
             HowToProcess::Partition => "Partition",
             HowToProcess::DoNotPartition => "Do Not Partition",
+            HowToProcess::Plan => "Plan",
         };
         write!(f, "{}", stringval)
     }
 }
 
-type OperationsAndFragments<'a> = (
-    Vec<OperationDefinition<'a, &'a str>>,
-    Vec<FragmentDefinition<'a, &'a str>>,
-);
-
 #[derive(Copy, Clone)]
 struct ProcessingInstruction<'a> {
-    path: Option<&'a str>,
+    paths: Option<&'a [&'a str]>,
     how_to_process: HowToProcess,
 }
 impl Default for ProcessingInstruction<'_> {
     fn default() -> Self {
         Self {
-            path: None,
+            paths: None,
             how_to_process: HowToProcess::DoNotProcess,
         }
     }
@@ -109,19 +120,37 @@ impl<'b> ProcessingInstruction<'b> {
     fn do_not_partition() -> Self {
         Self {
             how_to_process: HowToProcess::DoNotPartition,
-            path: None,
+            paths: None,
         }
     }
-    fn partition(do_not_cache: &'b str) -> Self {
+    /// Partition the operation, carving out one subrequest for each of the given paths. Each
+    /// path names a user-specific subtree that should not be cached.
+    fn partition(do_not_cache: &'b [&'b str]) -> Self {
         Self {
             how_to_process: HowToProcess::Partition,
-            path: Some(do_not_cache),
+            paths: Some(do_not_cache),
+        }
+    }
+    /// Partition the operation according to its client directives and the cache policies in the
+    /// bundled schema, if there is one
+    fn plan() -> Self {
+        Self {
+            how_to_process: HowToProcess::Plan,
+            paths: None,
+        }
+    }
+    /// The instruction for an operation that has no entry in PROCESSING_INSTRUCTIONS: plan it if
+    /// it is a query and a schema is bundled, otherwise do not process it
+    fn unlisted<'a>(operation: Option<&OperationDefinition<'a, &'a str>>) -> Self {
+        match (SCHEMA.as_ref(), operation) {
+            (Some(_), Some(OperationDefinition::Query(_))) => Self::plan(),
+            _ => Self::default(),
         }
     }
 
     /// Get the appropriate processing instruction for the given GraphQL request. If the
-    /// query string contained in the request has been parsed, the operation and fragment
-    /// definitions extracted from the parsed document will also be returned.
+    /// query string contained in the request has been parsed, the parsed document will
+    /// also be returned.
     ///
     /// This method will first look at the query parameter passed in the GraphQL request.
     /// If this parameter is empty or not present, the "Do Not Process" instruction will
@@ -132,47 +161,86 @@ impl<'b> ProcessingInstruction<'b> {
     /// from the operation definition. Regardless of the source of this value, the operation
     /// name will be checked against the PROCESSING_INSTRUCTIONS lookup. If the operation
     /// name is present, the associated processing instruction will be returned. Otherwise
-    /// the "Do Not Process" instruction will be returned.
+    /// the "Plan" instruction will be returned if a schema is bundled and the operation is a
+    /// query, or the "Do Not Process" instruction if not. A query that uses client directives such as
+    /// `@edgePrivate` is always planned, as long as it contains exactly one operation.
     ///
     /// Processing instruction rules:
     /// 1) GraphQL request has query string? If yes, proceed to #2. If no, instruction
     ///    is "Do Not Process"
-    /// 2) GraphQL request has operation name parameter? If yes, proceed to #4. If no,  
-    ///    proceed to #3.
-    /// 3) Operation name present in parsed query? If yes, Proceed to #4. If no,  
+    /// 2) Query uses client directives and contains exactly one operation? If yes,
+    ///    instruction is "Plan". If no, proceed to #3.
+    /// 3) GraphQL request has operation name parameter? If yes, proceed to #5. If no,  
+    ///    proceed to #4.
+    /// 4) Operation name present in parsed query? If yes, Proceed to #5. If no,  
     ///    instruction is "Do Not Process"
-    /// 4) Operation name present in PROCESSING_INSTRUCTIONS? If yes, instruction is the
-    ///    value associated with the operation name. If no, instruction is "Do Not Process"
+    /// 5) Operation name present in PROCESSING_INSTRUCTIONS? If yes, instruction is the
+    ///    value associated with the operation name. If no, instruction is "Plan" if a schema
+    ///    is bundled and the operation is a query, or "Do Not Process" otherwise
     fn from_graphql_request(
         graphql_request: &GraphqlRequest,
-    ) -> Result<(Self, Option<OperationsAndFragments>)> {
-        let mut operations_and_fragments = None;
+    ) -> Result<(Self, Option<Document<'_, &str>>)> {
+        let mut parsed_document = None;
         let processing_instruction = match graphql_request.query.as_ref() {
             Some(query) => {
                 if graphql_request.is_persisted_query() {
                     debug!(graphql_request = ?graphql_request, "Request is a persisted query. Do not process");
                     Self::default()
                 } else {
-                    match graphql_request.operation_name.as_ref() {
-                        Some(operation_name) => PROCESSING_INSTRUCTIONS
-                            .get(operation_name.as_str())
-                            .map_or_else(Self::default, |x| x.to_owned()),
-                        None => {
+                    // The query only needs parsing up front if it may use client directives
+                    if query.contains("@edge") {
+                        parsed_document = Some(parse_query::<&str>(query.as_str())?);
+                    }
+                    match (&parsed_document, graphql_request.operation_name.as_ref()) {
+                        // Client directives take precedence over PROCESSING_INSTRUCTIONS. Only
+                        // documents with a single operation can be planned.
+                        (Some(document), _)
+                            if has_client_directives(document)
+                                && operations(document).len() == 1 =>
+                        {
+                            Self::plan()
+                        }
+                        (_, Some(operation_name)) => {
+                            match PROCESSING_INSTRUCTIONS.get(operation_name.as_str()) {
+                                Some(instruction) => *instruction,
+                                None if SCHEMA.is_none() => Self::default(),
+                                // Only queries can be planned, so find out what the operation is
+                                None => {
+                                    let document = match parsed_document.take() {
+                                        Some(document) => document,
+                                        None => parse_query::<&str>(query.as_str())?,
+                                    };
+                                    let operation = operations(&document).into_iter().find(
+                                        |operation| match operation {
+                                            OperationDefinition::Query(query) => {
+                                                query.name == Some(operation_name.as_str())
+                                            }
+                                            _ => false,
+                                        },
+                                    );
+                                    let processing_instruction = Self::unlisted(operation);
+                                    parsed_document = Some(document);
+                                    processing_instruction
+                                }
+                            }
+                        }
+                        (Some(document), None) => Self::from_document(document),
+                        (None, None) => {
                             let document = parse_query::<&str>(query.as_str())?;
-
-                            operations_and_fragments =
-                                Some(into_operations_and_fragments(document));
-                            Self::from_operations(&operations_and_fragments.as_ref().unwrap().0[..])
+                            let processing_instruction = Self::from_document(&document);
+                            parsed_document = Some(document);
+                            processing_instruction
                         }
                     }
                 }
             }
             None => Self::default(),
         };
-        Ok((processing_instruction, operations_and_fragments))
+        Ok((processing_instruction, parsed_document))
     }
 
-    fn from_operations<'a>(operations: &[OperationDefinition<'a, &'a str>]) -> Self {
+    fn from_document<'a>(document: &Document<'a, &'a str>) -> Self {
+        let operations = operations(document);
         if operations.len() != 1 {
             info!(
                 "Multiple operations ({}) found in query. Do not process.",
@@ -181,14 +249,14 @@ impl<'b> ProcessingInstruction<'b> {
             return Self::default();
         }
 
-        match &operations[0] {
+        match operations[0] {
             OperationDefinition::SelectionSet(_) => Self::default(),
             OperationDefinition::Query(query) => {
                 match query.name {
                     Some(name) => match PROCESSING_INSTRUCTIONS.get(name) {
                         // cloning instruction is inefficient, but it's pretty cheap
                         Some(instruction) => *instruction,
-                        None => Self::default(),
+                        None => Self::unlisted(Some(operations[0])),
                     },
                     None => Self::default(),
                 }
@@ -204,7 +272,7 @@ lazy_static! {
         let mut map = HashMap::new();
         map.insert(
             "MatchupAnalysisQuery",
-            ProcessingInstruction::partition("matchupAnalysis.somePrediction"),
+            ProcessingInstruction::partition(&["matchupAnalysis.somePrediction"]),
         );
         map.insert(
             "PushNotificationSubscriptions",
@@ -238,6 +306,28 @@ lazy_static! {
     };
 }
 
+lazy_static! {
+    /// The backend's schema, bundled at build time from schema.graphql. Queries that have no
+    /// entry in PROCESSING_INSTRUCTIONS are partitioned according to the `@cacheControl`
+    /// directives in it. The build script refuses to build for Compute@Edge with an empty file,
+    /// unless told otherwise. If the file is empty or cannot be parsed, such queries are not
+    /// processed unless they use client directives.
+    static ref SCHEMA: Option<Schema> = {
+        let sdl = include_str!("../schema.graphql");
+        if sdl.trim().is_empty() {
+            None
+        } else {
+            match Schema::parse(sdl) {
+                Ok(schema) => Some(schema),
+                Err(why) => {
+                    error!("Could not parse bundled schema: {}", why);
+                    None
+                }
+            }
+        }
+    };
+}
+
 lazy_static! {
     static ref VERSION: String =
         std::env::var("FASTLY_SERVICE_VERSION").unwrap_or_else(|_| String::new());
@@ -351,24 +441,38 @@ fn handle_post(mut req: Request) -> Result<Response> {
     debug_assert!(req.get_method() == Method::POST, "Got a POST request");
     // let body_json: Value = req.clone_with_body().take_body_json()?;
     // println!("JSON: {}", body_json.to_string());
-    let graphql_request: GraphqlRequest = req.take_body_json()?;
+    let mut graphql_request: GraphqlRequest = req.take_body_json()?;
     let request_clone = graphql_request.clone();
 
-    let (processing_instruction, mut operations_and_fragments) =
+    let (processing_instruction, mut document) =
         ProcessingInstruction::from_graphql_request(&request_clone)?;
 
-    let operation_name = match operations_and_fragments {
-        Some(ref operations_and_fragments) => {
-            let operations = &operations_and_fragments.0;
-            match operations[0] {
-                OperationDefinition::Query(ref query) => query
+    let operation_name = match document {
+        Some(ref document) => {
+            let operations = operations(document);
+            match operations.first() {
+                Some(OperationDefinition::Query(ref query)) => query
                     .name
                     .map_or_else(|| "None".to_string(), |n| n.to_string()),
-                _ => "Not a Query".to_string(),
+                Some(_) => "Not a Query".to_string(),
+                None => {
+                    warn!("No operations found in query. Reject request");
+                    return Ok(Response::from_status(StatusCode::BAD_REQUEST)
+                        .with_header("X-Came-From", "edge")
+                        .with_header("X-GraphQL-Cacher-Version", VERSION.as_str())
+                        .with_body_text_plain("The query contains no operations"));
+                }
             }
         }
         _ => "None".to_string(),
     };
+    let fingerprint = match document {
+        Some(ref document) => document.fingerprint().to_string(),
+        _ => "None".to_string(),
+    };
+    if rand::random::<f64>() < FIELD_PATH_SAMPLE_RATE {
+        log_field_paths(&request_clone, document.as_ref(), &operation_name);
+    }
 
     // println!(
     //     "Operation: {}. Processing instruction: {}",
@@ -380,9 +484,27 @@ fn handle_post(mut req: Request) -> Result<Response> {
     //     processing_instruction.how_to_process.to_string().as_str()
     // );
 
+    // The backend does not know the client directives, so strip them from queries that are
+    // forwarded as they are. Partitioned queries are stripped when their subrequests are built.
+    if let Some(ref mut document) = document {
+        if matches!(
+            processing_instruction.how_to_process,
+            HowToProcess::DoNotProcess | HowToProcess::DoNotPartition
+        ) && has_client_directives(document)
+        {
+            strip_client_directives(document);
+            graphql_request.query = Some(document.to_string());
+        }
+    }
+
     let (res, measurement) = measure!(match processing_instruction.how_to_process {
         HowToProcess::DoNotProcess => {
-            let _span = info_span!("send_unmodified", operation = operation_name).entered();
+            let _span = info_span!(
+                "send_unmodified",
+                operation = operation_name,
+                fingerprint = fingerprint
+            )
+            .entered();
             // let _span2 = debug_span!(
             //     "Process request",
             //     processing_instruction = "Do Not Process",
@@ -413,12 +535,17 @@ fn handle_post(mut req: Request) -> Result<Response> {
             }
             send_unmodified(req)
         }
-        HowToProcess::Partition => {
+        HowToProcess::Partition | HowToProcess::Plan => {
             debug_assert!(
                 graphql_request.query.is_some(),
                 "GraphQL request has a query"
             );
-            let _span = info_span!("partition", operation = operation_name).entered();
+            let _span = info_span!(
+                "partition",
+                operation = operation_name,
+                fingerprint = fingerprint
+            )
+            .entered();
             let backend = Backend::from_request(&req, BackendType::Main)?;
             // let _span = debug_span!(
             //     "Process request",
@@ -427,11 +554,10 @@ fn handle_post(mut req: Request) -> Result<Response> {
             // )
             // .entered();
 
-            if operations_and_fragments.is_none() {
-                let document =
-                    parse_query::<&str>(graphql_request.query.as_ref().unwrap().as_str())?;
-
-                operations_and_fragments = Some(into_operations_and_fragments(document));
+            if document.is_none() {
+                document = Some(parse_query::<&str>(
+                    graphql_request.query.as_ref().unwrap().as_str(),
+                )?);
             }
 
             // debug!(
@@ -443,10 +569,11 @@ fn handle_post(mut req: Request) -> Result<Response> {
                 request.url = req.get_url_str(),
                 behavior = "partition",
                 operation_name = operation_name,
+                fingerprint = fingerprint,
                 "Partition request"
             );
 
-            let (mut operations, fragments) = operations_and_fragments.unwrap();
+            let document = document.unwrap();
             let headers = Headers::from_request(&req, &PASS_HEADERS);
             // debug!("Headers from request (partition): {:?}", &headers);
             let (is_subscriber, measurement) =
@@ -468,32 +595,36 @@ fn handle_post(mut req: Request) -> Result<Response> {
             //     &is_subscriber
             // );
             let _span = info_span!("process document").entered();
+            let no_schema = Schema::default();
+            let partitioning = match processing_instruction.how_to_process {
+                HowToProcess::Plan => Partitioning::Schema(SCHEMA.as_ref().unwrap_or(&no_schema)),
+                _ => Partitioning::Paths(processing_instruction.paths.unwrap()),
+            };
+            let verify = VERIFY_PARTITIONS || req.contains_header(VERIFY_HEADER);
             let worker = Worker::new(
                 &backend,
-                processing_instruction.path.unwrap(),
+                partitioning,
                 &headers,
+                &graphql_request.operation_name,
                 &graphql_request.variables,
                 is_subscriber,
-                fragments,
+                verify,
             );
             // debug!("Processing request");
 
-            debug_assert_eq!(operations.len(), 1, "Exactly one operation present");
-
-            let (mut res, measurement) = measure!(worker
-                .process_operation(operations.pop().unwrap())
-                .map_err(|why| {
+            let (mut res, measurement) =
+                measure!(worker.process_document(document).map_err(|why| {
                     error!("Process query failed: {}", why);
                     why
                 })?);
             let dur = Duration::from(measurement.clone()).num_nanoseconds();
             info!(
                 timing = "true",
-                method = "process_operation",
+                method = "process_document",
                 durationNs = dur,
                 operation = operation_name,
                 instruction = processing_instruction.how_to_process.to_string(),
-                "Elapsed in process_operation: {}",
+                "Elapsed in process_document: {}",
                 measurement
             );
             // debug!("Request processed successfully");
@@ -506,10 +637,19 @@ fn handle_post(mut req: Request) -> Result<Response> {
             Ok(res)
         }
         HowToProcess::DoNotPartition => {
-            let _span = info_span!("partition", operation = operation_name).entered();
+            let _span = info_span!(
+                "partition",
+                operation = operation_name,
+                fingerprint = fingerprint
+            )
+            .entered();
             let headers = Headers::from_request(&req, &PASS_HEADERS);
+            let query = graphql_request.query.clone();
+            let requested_operation = graphql_request.operation_name.clone();
             let req = graphql_request.get(&headers, None)?;
-            let (res, measurement) = measure!(flat_cache(req));
+            let (res, measurement) = measure!(flat_cache(req).and_then(|res| {
+                order_flat_cached_response(res, query.as_deref(), requested_operation.as_deref())
+            }));
             let dur = Duration::from(measurement.clone()).num_nanoseconds();
             info!(
                 timing = "true",
@@ -630,19 +770,54 @@ fn get_subscriber_status(backend: &Backend, headers: &Headers) -> Result<bool> {
     }
 }
 
-fn into_operations_and_fragments<'a>(
-    document: Document<'a, &'a str>,
-) -> (
-    Vec<OperationDefinition<'a, &'a str>>,
-    Vec<FragmentDefinition<'a, &'a str>>,
-) {
+/// Return the operation definitions in the given document
+fn operations<'a, 'b>(
+    document: &'b Document<'a, &'a str>,
+) -> Vec<&'b OperationDefinition<'a, &'a str>> {
     document
         .definitions
-        .into_iter()
-        .partition_map(|def| match def {
-            Definition::Operation(x) => Either::Left(x),
-            Definition::Fragment(x) => Either::Right(x),
+        .iter()
+        .filter_map(|def| match def {
+            Definition::Operation(x) => Some(x),
+            Definition::Fragment(_) => None,
         })
+        .collect_vec()
+}
+
+/// Log the path of every leaf field that the request's query selects. The query is parsed here if
+/// it was not already.
+fn log_field_paths<'a>(
+    graphql_request: &'a GraphqlRequest,
+    document: Option<&Document<'a, &'a str>>,
+    operation_name: &str,
+) {
+    let parsed;
+    let document = match (document, graphql_request.query.as_ref()) {
+        (Some(document), _) => document,
+        (None, Some(query)) if !graphql_request.is_persisted_query() => {
+            match parse_query::<&str>(query.as_str()) {
+                Ok(document) => {
+                    parsed = document;
+                    &parsed
+                }
+                Err(why) => {
+                    warn!(
+                        "Cannot log field paths of operation {}: {}",
+                        operation_name, why
+                    );
+                    return;
+                }
+            }
+        }
+        _ => return,
+    };
+    info!(
+        operation = operation_name,
+        fingerprint = document.fingerprint().to_string().as_str(),
+        field_paths = leaf_paths(document).join(" ").as_str(),
+        "Field paths of operation {}",
+        operation_name
+    );
 }
 
 // Flat cache a GraphQL GET request. This will send a request unmodified *except* for
@@ -701,6 +876,32 @@ fn flat_cache(mut req: Request) -> Result<Response> {
     Ok(res)
 }
 
+/// Put the keys of a flat cached response in the order the requested operation of the given query
+/// selects them. The query is sent to the backend in canonical form, so the response follows that
+/// form's order instead. A response whose body is not JSON, or a query that does not parse, is
+/// left as it is.
+fn order_flat_cached_response(
+    mut res: Response,
+    query: Option<&str>,
+    operation_name: Option<&str>,
+) -> Result<Response> {
+    let document = match query.map(parse_query::<&str>) {
+        Some(Ok(document)) => document,
+        _ => return Ok(res),
+    };
+    let body = res.take_body_bytes();
+    match serde_json::from_slice::<Value>(&body) {
+        Ok(mut container) => {
+            if let Some(data) = container.get_mut("data") {
+                order_response_keys(&document, operation_name, data);
+            }
+            res.set_body_json(&container)?;
+        }
+        Err(_) => res.set_body(body),
+    }
+    Ok(res)
+}
+
 fn send_unmodified(req: Request) -> Result<Response> {
     let backend = Backend::from_request(&req, BackendType::Bypass)?;
     info!(
@@ -0,0 +1,261 @@
+// Copyright 2024 Aurelia Peters
+//
+// This file is part of GraphQL Cacher.
+// 
+// GraphQL Cacher is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// 
+// GraphQL Cacher is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License along with GraphQL Cacher. If not, see <https://www.gnu.org/licenses/>. 
+//! Partition plans: the subqueries a document is split into, how each of them is fetched and how
+//! their responses are merged. Plans serialize to JSON, so they can be logged and cached.
+use crate::graphql_request::GraphqlRequest;
+use graphql_parser::query::Document;
+use itertools::Itertools;
+use partition_operation::{
+    analysis::leaf_paths,
+    canonical::Canonicalize,
+    planner::{PlannedPartition, Policy},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// How a subquery is sent to the backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum FetchMethod {
+    /// Canonicalized in the query string, so that the response can be served from cache
+    Get,
+    /// In the request body, uncached
+    Post,
+}
+
+/// A subquery of a plan
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Subquery {
+    /// The printed query, with the variables it uses and its operation name
+    #[serde(flatten)]
+    pub request: GraphqlRequest,
+    pub method: FetchMethod,
+    pub cache_policy: Policy,
+    /// The paths of the leaf fields the data of the response fills in, in the same syntax as
+    /// partition paths
+    pub merge_paths: Vec<String>,
+}
+
+/// The subqueries a document is split into. The data of their responses is merged into one
+/// composite response, whose keys are then put in the order the document selects them, and the
+/// errors of every response are collected into it.
+///
+/// # Examples
+/// ```json
+/// {"subqueries":[{"query":"{\n  currentUser {\n    name\n  }\n}\n","variables":null,
+///   "operationName":null,"method":"POST","cachePolicy":{"type":"private"},
+///   "mergePaths":["currentUser.name"]}]}
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PartitionPlan {
+    pub subqueries: Vec<Subquery>,
+}
+
+impl PartitionPlan {
+    /// Plan a subquery for each partition, with those of the variables that it defines. Public
+    /// partitions are fetched via GET; anything else via POST.
+    pub fn new<'a>(
+        partitions: Vec<PlannedPartition<Document<'a, &'a str>>>,
+        variables: &Option<Value>,
+    ) -> Self {
+        let subqueries = partitions
+            .into_iter()
+            .map(|planned| {
+                let merge_paths = leaf_paths(&planned.partition);
+                let method = match planned.policy {
+                    Policy::Public { .. } => FetchMethod::Get,
+                    Policy::Private | Policy::Uncacheable => FetchMethod::Post,
+                };
+                Subquery {
+                    request: GraphqlRequest::from_document(planned.partition, variables.clone()),
+                    method,
+                    cache_policy: planned.policy,
+                    merge_paths,
+                }
+            })
+            .collect();
+        Self { subqueries }
+    }
+
+    /// Replace the values of the variables in the plan with those given, e.g. to reuse the plan
+    /// made for another request for the same operation. Variables the plan does not have are not
+    /// added, which is why the cache key covers which variables are given.
+    pub fn with_variables(mut self, variables: &Option<Value>) -> Self {
+        for subquery in &mut self.subqueries {
+            if let Some(Value::Object(planned)) = subquery.request.variables.as_mut() {
+                for (name, value) in planned.iter_mut() {
+                    if let Some(given) = variables.as_ref().and_then(|v| v.get(name)) {
+                        *value = given.clone();
+                    }
+                }
+            }
+        }
+        self
+    }
+
+    /// Clear the values of the variables in the plan, keeping their names for `with_variables`.
+    /// Plans are cached for every request for the same operation, and the values given with one
+    /// request, such as IDs or tokens, are no business of the others.
+    pub fn without_variable_values(mut self) -> Self {
+        for subquery in &mut self.subqueries {
+            match subquery.request.variables.as_mut() {
+                Some(Value::Object(planned)) => {
+                    for value in planned.values_mut() {
+                        *value = Value::Null;
+                    }
+                }
+                Some(_) => subquery.request.variables = None,
+                None => (),
+            }
+        }
+        self
+    }
+
+    /// Send every subquery with the given operation name and all of the given variables, as the
+    /// client requested them. This is for a document sent unpartitioned, which may contain several
+    /// operations, only one of which the client asked for.
+    pub fn with_requested_operation(
+        mut self,
+        operation_name: &Option<String>,
+        variables: &Option<Value>,
+    ) -> Self {
+        for subquery in &mut self.subqueries {
+            subquery.request.operation_name = operation_name.clone();
+            subquery.request.variables = variables.clone();
+        }
+        self
+    }
+}
+
+/// Return the key under which the plan for the document is cached. Plans are cached by service
+/// version, since the processing instructions and the schema are built into it, and by operation
+/// fingerprint. Which variables are given and the values of the boolean ones also go into the
+/// key, since they decide which variables each subquery is sent and, through `@skip` and
+/// `@include`, what it selects. Other values are filled in from each request.
+pub fn cache_key<'a>(
+    version: &str,
+    document: &Document<'a, &'a str>,
+    variables: &Option<Value>,
+) -> String {
+    let variables = match variables {
+        Some(Value::Object(variables)) => variables
+            .iter()
+            .map(|(name, value)| match value {
+                Value::Bool(value) => format!("{}={}", name, value),
+                _ => name.to_string(),
+            })
+            .sorted()
+            .join(","),
+        _ => String::new(),
+    };
+    format!(
+        "partition-plan:{}:{}:{}",
+        version,
+        document.fingerprint(),
+        variables
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cache_key, FetchMethod, PartitionPlan};
+    use graphql_parser::parse_query;
+    use partition_operation::planner::{PlannedPartition, Policy};
+    use serde_json::json;
+
+    fn plan(variables: &Option<serde_json::Value>) -> PartitionPlan {
+        let public = parse_query::<&str>("query Q($size: Int) { teams(size: $size) { name } }");
+        let private = parse_query::<&str>("query Q { currentUser { name } }");
+        PartitionPlan::new(
+            vec![
+                PlannedPartition {
+                    policy: Policy::Public { ttl: Some(300) },
+                    partition: public.unwrap(),
+                },
+                PlannedPartition {
+                    policy: Policy::Private,
+                    partition: private.unwrap(),
+                },
+            ],
+            variables,
+        )
+    }
+
+    #[test]
+    fn plan_serializes_and_deserializes() {
+        let plan = plan(&Some(json!({ "size": 2 })));
+        assert_eq!(FetchMethod::Get, plan.subqueries[0].method);
+        assert_eq!(FetchMethod::Post, plan.subqueries[1].method);
+        assert_eq!(vec!["teams.name"], plan.subqueries[0].merge_paths);
+        assert_eq!(None, plan.subqueries[1].request.variables);
+
+        let serialized = serde_json::to_value(&plan).unwrap();
+        let subquery = &serialized["subqueries"][0];
+        assert_eq!(json!({ "size": 2 }), subquery["variables"]);
+        assert_eq!("GET", subquery["method"]);
+        assert_eq!(
+            json!({ "type": "public", "ttl": 300 }),
+            subquery["cachePolicy"]
+        );
+        assert_eq!(plan, serde_json::from_value(serialized).unwrap());
+    }
+
+    #[test]
+    fn cached_plan_takes_the_values_of_the_request() {
+        let cached = plan(&Some(json!({ "size": 2 })));
+        let variables = Some(json!({ "size": 3 }));
+        assert_eq!(plan(&variables), cached.with_variables(&variables));
+    }
+
+    #[test]
+    fn plan_without_variable_values_takes_those_of_the_request() {
+        let cached = plan(&Some(json!({ "size": 2 }))).without_variable_values();
+        assert_eq!(
+            Some(json!({ "size": null })),
+            cached.subqueries[0].request.variables
+        );
+        let variables = Some(json!({ "size": 3 }));
+        assert_eq!(plan(&variables), cached.with_variables(&variables));
+    }
+
+    #[test]
+    fn unpartitioned_plan_keeps_the_requested_operation() {
+        let document =
+            parse_query::<&str>("query A($id: ID) { a(id: $id) } query B($n: Int) { b(n: $n) }");
+        let variables = Some(json!({ "n": 2 }));
+        let plan = PartitionPlan::new(
+            vec![PlannedPartition {
+                policy: Policy::Private,
+                partition: document.unwrap(),
+            }],
+            &variables,
+        )
+        .with_requested_operation(&Some("B".to_string()), &variables);
+        let request = &plan.subqueries[0].request;
+        assert_eq!(Some("B".to_string()), request.operation_name);
+        assert_eq!(variables, request.variables);
+    }
+
+    #[test]
+    fn cache_key_varies_by_boolean_values_only() {
+        let document = parse_query::<&str>("query Q($size: Int, $all: Boolean) { teams }").unwrap();
+        let key = |variables| cache_key("1", &document, &Some(variables));
+        assert_eq!(
+            key(json!({ "size": 2, "all": true })),
+            key(json!({ "all": true, "size": 3 }))
+        );
+        assert_ne!(
+            key(json!({ "size": 2, "all": true })),
+            key(json!({ "size": 2, "all": false }))
+        );
+        assert_ne!(key(json!({ "size": 2 })), key(json!({})));
+    }
+}
@@ -0,0 +1,204 @@
+// Copyright 2024 Aurelia Peters
+//
+// This file is part of GraphQL Cacher.
+// 
+// GraphQL Cacher is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// 
+// GraphQL Cacher is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License along with GraphQL Cacher. If not, see <https://www.gnu.org/licenses/>. 
+//! Puts the keys of a composite response in the order the document selects them. Subqueries are
+//! canonicalized and their responses merged as they arrive, so neither keeps the document's order.
+use graphql_parser::query::{
+    Definition, Document, FragmentDefinition, OperationDefinition, Selection, SelectionSet,
+};
+use serde_json::Value;
+use std::collections::HashMap;
+
+type Fragments<'f, 'a> = HashMap<&'a str, &'f FragmentDefinition<'a, &'a str>>;
+
+/// The response keys a selection set selects, in order, each with the selection sets of all the
+/// fields that select it
+type Fields<'f, 'a> = Vec<(&'a str, Vec<&'f SelectionSet<'a, &'a str>>)>;
+
+/// Reorder the keys of the data of a response to follow the order in which the requested
+/// operation selects them, as the GraphQL spec requires. The fields of fragments take their place
+/// where the fragment is spread, except for fields already selected before it. Keys the
+/// operation does not select are kept, after those it does. The data is left as it is if the
+/// document has no operation named `operation_name`.
+pub fn order_response_keys<'a>(
+    document: &Document<'a, &'a str>,
+    operation_name: Option<&str>,
+    data: &mut Value,
+) {
+    let fragments = document
+        .definitions
+        .iter()
+        .filter_map(|definition| match definition {
+            Definition::Fragment(fragment) => Some((fragment.name, fragment)),
+            Definition::Operation(_) => None,
+        })
+        .collect::<Fragments>();
+    if let Some(selection_set) = requested_selection_set(document, operation_name) {
+        order_value(data, &[selection_set], &fragments);
+    }
+}
+
+/// Return the selection set of the operation with the given name, or of the first operation if
+/// no name is given
+pub(crate) fn requested_selection_set<'f, 'a>(
+    document: &'f Document<'a, &'a str>,
+    operation_name: Option<&str>,
+) -> Option<&'f SelectionSet<'a, &'a str>> {
+    let operation_name = operation_name.filter(|name| !name.is_empty());
+    document
+        .definitions
+        .iter()
+        .find_map(|definition| {
+            let (name, selection_set) = match definition {
+                Definition::Operation(OperationDefinition::SelectionSet(selection_set)) => {
+                    (None, selection_set)
+                }
+                Definition::Operation(OperationDefinition::Query(query)) => {
+                    (query.name, &query.selection_set)
+                }
+                Definition::Operation(OperationDefinition::Mutation(mutation)) => {
+                    (mutation.name, &mutation.selection_set)
+                }
+                Definition::Operation(OperationDefinition::Subscription(subscription)) => {
+                    (subscription.name, &subscription.selection_set)
+                }
+                Definition::Fragment(_) => return None,
+            };
+            match operation_name {
+                Some(operation_name) if name != Some(operation_name) => None,
+                _ => Some(selection_set),
+            }
+        })
+}
+
+fn order_value<'f, 'a>(
+    value: &mut Value,
+    selection_sets: &[&'f SelectionSet<'a, &'a str>],
+    fragments: &Fragments<'f, 'a>,
+) {
+    let object = match value {
+        Value::Array(items) => {
+            for item in items {
+                order_value(item, selection_sets, fragments);
+            }
+            return;
+        }
+        Value::Object(object) => object,
+        // Null and leaf values have no keys to order
+        _ => return,
+    };
+    let mut fields = vec![];
+    for selection_set in selection_sets {
+        collect_fields(selection_set, fragments, &mut vec![], &mut fields);
+    }
+    let keys = object.keys().cloned().collect::<Vec<_>>();
+    let mut entries = std::mem::take(object)
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+    for (key, selection_sets) in fields {
+        if let Some(mut value) = entries.remove(key) {
+            order_value(&mut value, &selection_sets, fragments);
+            object.insert(key.to_string(), value);
+        }
+    }
+    for key in keys {
+        if let Some(value) = entries.remove(&key) {
+            object.insert(key, value);
+        }
+    }
+}
+
+/// Collect the fields of a selection set, looking through fragments. `expanding` holds the names
+/// of the fragments the selection set is within, so that a fragment that spreads itself is not
+/// followed forever.
+fn collect_fields<'f, 'a>(
+    selection_set: &'f SelectionSet<'a, &'a str>,
+    fragments: &Fragments<'f, 'a>,
+    expanding: &mut Vec<&'a str>,
+    fields: &mut Fields<'f, 'a>,
+) {
+    for selection in &selection_set.items {
+        match selection {
+            Selection::Field(field) => {
+                let key = field.alias.unwrap_or(field.name);
+                match fields.iter_mut().find(|(k, _)| *k == key) {
+                    Some((_, selection_sets)) => selection_sets.push(&field.selection_set),
+                    None => fields.push((key, vec![&field.selection_set])),
+                }
+            }
+            Selection::InlineFragment(inline_fragment) => {
+                collect_fields(&inline_fragment.selection_set, fragments, expanding, fields)
+            }
+            Selection::FragmentSpread(spread) => {
+                if let Some(fragment) = fragments.get(spread.fragment_name) {
+                    if !expanding.contains(&spread.fragment_name) {
+                        expanding.push(spread.fragment_name);
+                        collect_fields(&fragment.selection_set, fragments, expanding, fields);
+                        expanding.pop();
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::order_response_keys;
+    use graphql_parser::parse_query;
+    use serde_json::json;
+
+    #[test]
+    fn order_response_keys_follows_the_selection_order() {
+        let document = parse_query::<&str>("{ teams { name, abbrev }, season }").unwrap();
+        let mut data = json!({
+            "season": 2024,
+            "unselected": true,
+            "teams": [{ "abbrev": "NYG", "name": "Giants" }, { "name": "Cowboys", "abbrev": "DAL" }]
+        });
+        order_response_keys(&document, None, &mut data);
+        assert_eq!(
+            r#"{"teams":[{"name":"Giants","abbrev":"NYG"},{"name":"Cowboys","abbrev":"DAL"}],"season":2024,"unselected":true}"#,
+            data.to_string()
+        );
+    }
+
+    #[test]
+    fn order_response_keys_looks_through_fragments() {
+        let document = parse_query::<&str>(
+            "{ node { id, ...TeamFields, ... on Team { logo { url }, coach { name } } } }
+            fragment TeamFields on Team { name, id, coach { id } }",
+        )
+        .unwrap();
+        let mut data = json!({
+            "node": {
+                "logo": { "url": "nyg.png" },
+                "coach": { "name": "Brian Daboll", "id": 1 },
+                "name": "Giants",
+                "id": "T1"
+            }
+        });
+        order_response_keys(&document, None, &mut data);
+        assert_eq!(
+            r#"{"node":{"id":"T1","name":"Giants","coach":{"id":1,"name":"Brian Daboll"},"logo":{"url":"nyg.png"}}}"#,
+            data.to_string()
+        );
+    }
+
+    #[test]
+    fn order_response_keys_follows_the_requested_operation() {
+        let document =
+            parse_query::<&str>("query A { season, teams } query B { teams, season }").unwrap();
+        let mut data = json!({ "season": 2024, "teams": [] });
+        order_response_keys(&document, Some("B"), &mut data);
+        assert_eq!(r#"{"teams":[],"season":2024}"#, data.to_string());
+        order_response_keys(&document, Some("A"), &mut data);
+        assert_eq!(r#"{"season":2024,"teams":[]}"#, data.to_string());
+    }
+}
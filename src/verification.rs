@@ -0,0 +1,217 @@
+// Copyright 2024 Aurelia Peters
+//
+// This file is part of GraphQL Cacher.
+// 
+// GraphQL Cacher is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// 
+// GraphQL Cacher is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License along with GraphQL Cacher. If not, see <https://www.gnu.org/licenses/>. 
+//! Checks that the response to a partitioned document has everything the document asked for
+use crate::response_order::requested_selection_set;
+use graphql_parser::query::{
+    Definition, Directive, Document, FragmentDefinition, Selection, SelectionSet, TypeCondition,
+};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+type Fragments<'f, 'a> = HashMap<&'a str, &'f FragmentDefinition<'a, &'a str>>;
+
+/// List the paths of the response keys that the requested operation selects but that the data of
+/// its response lacks. The operation is the one named `operation_name`, or the first one if no
+/// name is given. Paths are written as for partitioning, without list indices; each is listed once
+/// however many list items lack it.
+///
+/// Selections with `@skip` or `@include` are not required, as whether they apply depends on the
+/// request's variables. A fragment is checked only if it applies to the object in hand: if its
+/// type condition is the object's `__typename` or, where that does not settle it, if any of the
+/// fields directly within it are present.
+pub fn missing_response_keys<'a>(
+    document: &Document<'a, &'a str>,
+    operation_name: Option<&str>,
+    data: &Value,
+) -> Vec<String> {
+    let fragments = document
+        .definitions
+        .iter()
+        .filter_map(|definition| match definition {
+            Definition::Fragment(fragment) => Some((fragment.name, fragment)),
+            Definition::Operation(_) => None,
+        })
+        .collect::<Fragments>();
+    let mut missing = vec![];
+    if let Some(selection_set) = requested_selection_set(document, operation_name) {
+        collect_missing(
+            selection_set,
+            data,
+            "",
+            &fragments,
+            &mut vec![],
+            &mut missing,
+        );
+    }
+    missing
+}
+
+/// Collect the paths of the response keys that the selection set selects but that the value lacks.
+/// `expanding` holds the names of the fragments the selection set is within, below the field the
+/// value is for, so that a fragment that spreads itself is not followed forever.
+fn collect_missing<'a>(
+    selection_set: &SelectionSet<'a, &'a str>,
+    value: &Value,
+    path: &str,
+    fragments: &Fragments<'_, 'a>,
+    expanding: &mut Vec<&'a str>,
+    missing: &mut Vec<String>,
+) {
+    let object = match value {
+        Value::Array(items) => {
+            for item in items {
+                collect_missing(selection_set, item, path, fragments, expanding, missing);
+            }
+            return;
+        }
+        Value::Object(object) => object,
+        // Null, whether for an error or not, and leaf values have nothing more to check
+        _ => return,
+    };
+    for selection in &selection_set.items {
+        match selection {
+            Selection::Field(field) => {
+                if is_conditional(&field.directives) {
+                    continue;
+                }
+                let key = field.alias.unwrap_or(field.name);
+                let path = if path.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                match object.get(key) {
+                    Some(value) => collect_missing(
+                        &field.selection_set,
+                        value,
+                        &path,
+                        fragments,
+                        &mut vec![],
+                        missing,
+                    ),
+                    None if !missing.contains(&path) => missing.push(path),
+                    None => (),
+                }
+            }
+            Selection::InlineFragment(inline_fragment) => {
+                let type_condition = inline_fragment
+                    .type_condition
+                    .as_ref()
+                    .map(|TypeCondition::On(type_name)| *type_name);
+                if !is_conditional(&inline_fragment.directives)
+                    && applies(type_condition, &inline_fragment.selection_set, object)
+                {
+                    collect_missing(
+                        &inline_fragment.selection_set,
+                        value,
+                        path,
+                        fragments,
+                        expanding,
+                        missing,
+                    );
+                }
+            }
+            Selection::FragmentSpread(spread) => {
+                if let Some(fragment) = fragments.get(spread.fragment_name) {
+                    let TypeCondition::On(type_name) = fragment.type_condition;
+                    if !is_conditional(&spread.directives)
+                        && !expanding.contains(&spread.fragment_name)
+                        && applies(Some(type_name), &fragment.selection_set, object)
+                    {
+                        expanding.push(spread.fragment_name);
+                        collect_missing(
+                            &fragment.selection_set,
+                            value,
+                            path,
+                            fragments,
+                            expanding,
+                            missing,
+                        );
+                        expanding.pop();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns true if the selection is subject to `@skip` or `@include`
+fn is_conditional<'a>(directives: &[Directive<'a, &'a str>]) -> bool {
+    directives
+        .iter()
+        .any(|directive| directive.name == "skip" || directive.name == "include")
+}
+
+/// Returns true if a fragment with the given type condition and selection set applies to the
+/// object
+fn applies<'a>(
+    type_condition: Option<&str>,
+    selection_set: &SelectionSet<'a, &'a str>,
+    object: &Map<String, Value>,
+) -> bool {
+    if type_condition.is_none()
+        || type_condition == object.get("__typename").and_then(Value::as_str)
+    {
+        return true;
+    }
+    selection_set.items.iter().any(|selection| match selection {
+        Selection::Field(field) => object.contains_key(field.alias.unwrap_or(field.name)),
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::missing_response_keys;
+    use graphql_parser::parse_query;
+    use serde_json::json;
+
+    #[test]
+    fn missing_response_keys_lists_each_missing_path_once() {
+        let document = parse_query::<&str>(
+            "{ teams { name, abbrev, logo @include(if: $logos) }, season }",
+        )
+        .unwrap();
+        let data = json!({ "teams": [{ "name": "Giants" }, { "name": "Cowboys" }] });
+        assert_eq!(
+            missing_response_keys(&document, None, &data),
+            vec!["teams.abbrev", "season"]
+        );
+    }
+
+    #[test]
+    fn missing_response_keys_checks_fragments_that_apply() {
+        let document = parse_query::<&str>(
+            "{ node { ... on Team { name }, ...PlayerFields } }
+            fragment PlayerFields on Player { position, number }",
+        )
+        .unwrap();
+        let team = json!({ "node": { "__typename": "Team" } });
+        assert_eq!(missing_response_keys(&document, None, &team), vec!["node.name"]);
+        let player = json!({ "node": { "position": "QB" } });
+        assert_eq!(missing_response_keys(&document, None, &player), vec!["node.number"]);
+    }
+
+    #[test]
+    fn missing_response_keys_follows_a_self_spreading_fragment_once() {
+        let document =
+            parse_query::<&str>("query Q { a { ...A } } fragment A on T { b, c, ...A }").unwrap();
+        let data = json!({ "a": { "__typename": "T", "b": 1 } });
+        assert_eq!(missing_response_keys(&document, None, &data), vec!["a.c"]);
+    }
+
+    #[test]
+    fn missing_response_keys_checks_only_the_requested_operation() {
+        let document = parse_query::<&str>("query A { a } query B { b }").unwrap();
+        let data = json!({ "b": 1 });
+        assert!(missing_response_keys(&document, Some("B"), &data).is_empty());
+        assert_eq!(missing_response_keys(&document, Some("A"), &data), vec!["a"]);
+    }
+}
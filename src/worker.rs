@@ -12,57 +12,89 @@ use crate::backend_response::BackendResponse;
 use crate::graphql_request;
 use crate::headers::Headers;
 use crate::json_merge;
+use crate::partition_plan::{self, FetchMethod, PartitionPlan, Subquery};
+use crate::response_order::order_response_keys;
+use crate::verification::missing_response_keys;
 // use crate::{graphql_request, HeaderMap};
-use anyhow::bail;
 use anyhow::{Error, Result};
-use fastly::http::request::PendingRequest;
-use fastly::Response;
-use graphql_parser::query::{FragmentDefinition, OperationDefinition};
+use fastly::cache::simple;
+use fastly::http::{request::PendingRequest, Method};
+use fastly::{Request, Response};
+use graphql_parser::{
+    query::{Definition, Document, OperationDefinition},
+    Pos,
+};
 use graphql_request::GraphqlRequest;
+use itertools::Itertools;
 use json_merge::Merge;
-use partition_operation::Partition;
+use partition_operation::{
+    comparisions::diff_partitions,
+    normalize::normalize,
+    planner::{Plan, PlannedPartition, Policy},
+    schema::Schema,
+    source_map::SourceMap,
+    Partition, PartitionError, Partitions,
+};
 use serde_json::{json, Value};
-use tracing::{debug, debug_span, error};
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing::{debug, debug_span, error, warn};
 use uuid::Uuid;
 
+/// A document normalized for planning, or why it could not be
+type Normalized<'a> = Result<Document<'a, &'a str>, PartitionError>;
+
+/// How a worker partitions documents
+#[derive(Debug, Clone, Copy)]
+pub enum Partitioning<'a> {
+    /// Carve out one uncached subrequest for each path
+    Paths(&'a [&'a str]),
+    /// Carve out one subrequest for each cache policy found in the schema
+    Schema(&'a Schema),
+}
+
 #[derive(Debug)]
 pub struct Worker<'a> {
     backend: &'a Backend,
-    path: &'a str,
+    partitioning: Partitioning<'a>,
     headers: &'a Headers<'a>,
+    /// The operation name the client gave, which a document sent unpartitioned keeps
+    operation_name: &'a Option<String>,
     variables: &'a Option<Value>,
     request_id: Uuid,
     is_subscriber: bool,
-    fragments: Vec<FragmentDefinition<'a, &'a str>>,
+    /// Check that the partitions add up to the document and that the response has everything
+    /// the document asked for, logging any mismatch
+    verify: bool,
 }
 
 impl<'a> Worker<'a> {
     pub fn new(
         backend: &'a Backend,
-        path: &'a str,
+        partitioning: Partitioning<'a>,
         headers: &'a Headers<'a>,
+        operation_name: &'a Option<String>,
         variables: &'a Option<serde_json::Value>,
         is_subscriber: bool,
-        fragments: Vec<FragmentDefinition<'a, &'a str>>,
+        verify: bool,
     ) -> Self {
         let request_id = Uuid::new_v4();
         Worker {
             backend,
-            path,
+            partitioning,
             headers,
+            operation_name,
             variables,
             request_id,
             is_subscriber,
-            fragments,
+            verify,
         }
     }
 
     // #[instrument]
-    pub fn process_operation(
-        &self,
-        operation: OperationDefinition<'a, &'a str>,
-    ) -> Result<Response> {
-        let mut requests = self.get_requests(operation)?;
+    pub fn process_document(&self, document: Document<'a, &'a str>) -> Result<Response> {
+        let (plan, mut normalized) = self.partition_plan(&document);
+        let (mut requests, queries) = self.get_requests(&document, plan)?;
 
         debug!("Got {} requests from document", requests.len());
         let mut container: Value = serde_json::from_str("{}").unwrap();
@@ -138,8 +170,22 @@ impl<'a> Worker<'a> {
                             counter,
                             graphql_errors.len()
                         );
+                        // Subqueries keep the positions of the normalized document, and are
+                        // matched to it by where their nodes are in it, so errors are mapped
+                        // back through it. A cached plan did not need it, so it is only
+                        // normalized now.
+                        let source_map = request
+                            .get_header_str("X-Graphql-Cacher-Request-Id")
+                            .and_then(|request_id| queries.get(request_id))
+                            .and_then(|query| {
+                                let normalized =
+                                    normalized.get_or_insert_with(|| self.normalize(&document));
+                                SourceMap::new(normalized.as_ref().unwrap_or(&document), query).ok()
+                            });
                         for (i, error) in graphql_errors.iter().enumerate() {
-                            if !errors.contains(&error.value) {
+                            let value =
+                                remap_locations(error.value.to_owned(), source_map.as_ref());
+                            if !errors.contains(&value) {
                                 error!(
                                     message = format!(
                                         "Error {}/{}: {}",
@@ -159,7 +205,7 @@ impl<'a> Worker<'a> {
                                         .join("; ")
                                         .as_str(),
                                 );
-                                errors.push(error.value.to_owned());
+                                errors.push(value);
                             }
                         }
                     } else {
@@ -176,6 +222,12 @@ impl<'a> Worker<'a> {
             // debug!("Backend URL: {}", backend_url);
             counter += 1;
         }
+        if let Some(data) = container.get_mut("data") {
+            order_response_keys(&document, self.operation_name.as_deref(), data);
+        }
+        if self.verify {
+            self.verify_response(&document, &container);
+        }
         let mut response = response.unwrap();
         response.set_body_json(&container)?;
 
@@ -189,70 +241,338 @@ impl<'a> Worker<'a> {
         (BackendResponse::new(response), remaining_requests)
     }
 
+    /// Send the subrequests planned for the document. Also returns, by subrequest ID, the query
+    /// each subrequest sends, so that the locations of errors can be mapped back to the document.
     // #[instrument]
     fn get_requests(
         &self,
-        operation: OperationDefinition<'a, &'a str>,
-    ) -> Result<Vec<PendingRequest>> {
-        match operation.partition_by_path(self.path)? {
-            Some((left, right)) => {
-                // println!("Left operation (POST) is {}", left);
-                // println!("Right operation (GET) is {}", right);
-                let left_request =
-                    GraphqlRequest::from_operation_definition(left, vec![], self.variables.clone())
-                        .post(self.headers)?;
-
-                let right_request = GraphqlRequest::from_operation_definition(
-                    right,
-                    self.fragments.clone(), // FIXME: Can I get around cloning?
-                    self.variables.clone(),
-                )
-                .get(self.headers, Some(self.is_subscriber))?
-                .with_header("x-gql", "true");
-
-                vec![left_request, right_request]
-                    .into_iter()
-                    .map(|mut request| {
-                        let request_id = Uuid::new_v4();
-                        let composite_request_id =
-                            format!("{}:{}", self.request_id.as_simple(), request_id.as_simple());
-                        if !request.contains_header("x-backend-env") {
-                            request.set_header("X-Backend-Env", self.backend.env.as_str());
-                        }
-                        request.set_header("X-Graphql-Cacher-Request-Id", composite_request_id);
-                        tracing::debug!(
-                            request.method = request.get_method().as_str(),
-                            request.url = request.get_url_str(),
-                            "Send subquery: {} {}",
-                            request.get_method_str(),
-                            request.get_url_str()
-                        );
-                        // if request.get_method_str() == "POST" {
-                        //     let mut clone = request.clone_with_body();
-                        //     println!("---- BEGIN POST REQUEST ----");
-                        //     println!("{} {}", clone.get_method_str(), clone.get_url_str());
-                        //     for (header, value) in &clone.headers_as_hash_map() {
-                        //         println!("{}: {}", header, value);
-                        //     }
-                        //     println!();
-                        //     let body = clone.take_body_str();
-                        //     println!("{}", body);
-                        //     println!("---- END POST REQUEST ----");
-                        // }
-                        self.backend.send_async(request).map_err(Error::from)
-                    })
-                    .collect::<Result<Vec<PendingRequest>>>()
-            }
-            None => {
-                tracing::error!(
-                    "Path \"{}\" did not match any paths in the given operation definition",
-                    self.path
+        document: &Document<'a, &'a str>,
+        plan: PartitionPlan,
+    ) -> Result<(Vec<PendingRequest>, HashMap<String, String>)> {
+        debug!(
+            plan = serde_json::to_string(&plan)?.as_str(),
+            "Planned {} subqueries for operation {}",
+            plan.subqueries.len(),
+            operation_name(document)
+        );
+
+        let mut queries = HashMap::new();
+        let requests = plan
+            .subqueries
+            .into_iter()
+            .map(|subquery| {
+                let mut request = self.subquery_request(subquery)?;
+                let request_id = Uuid::new_v4();
+                let composite_request_id =
+                    format!("{}:{}", self.request_id.as_simple(), request_id.as_simple());
+                if let Some(query) = Self::sent_query(&mut request) {
+                    queries.insert(composite_request_id.clone(), query);
+                }
+                if !request.contains_header("x-backend-env") {
+                    request.set_header("X-Backend-Env", self.backend.env.as_str());
+                }
+                request.set_header("X-Graphql-Cacher-Request-Id", composite_request_id);
+                tracing::debug!(
+                    request.method = request.get_method().as_str(),
+                    request.url = request.get_url_str(),
+                    "Send subquery: {} {}",
+                    request.get_method_str(),
+                    request.get_url_str()
                 );
-                bail!(
-                    "Path \"{}\" did not match any paths in the given operation definition",
-                    self.path
-                )
+                // if request.get_method_str() == "POST" {
+                //     let mut clone = request.clone_with_body();
+                //     println!("---- BEGIN POST REQUEST ----");
+                //     println!("{} {}", clone.get_method_str(), clone.get_url_str());
+                //     for (header, value) in &clone.headers_as_hash_map() {
+                //         println!("{}: {}", header, value);
+                //     }
+                //     println!();
+                //     let body = clone.take_body_str();
+                //     println!("{}", body);
+                //     println!("---- END POST REQUEST ----");
+                // }
+                self.backend.send_async(request).map_err(Error::from)
+            })
+            .collect::<Result<Vec<PendingRequest>>>()?;
+        Ok((requests, queries))
+    }
+
+    /// Normalize the document for planning with the request's variables; see
+    /// `partition_operation::normalize::normalize`. The document is cloned so that it can still
+    /// be sent whole if partitioning fails.
+    fn normalize(&self, document: &Document<'a, &'a str>) -> Normalized<'a> {
+        normalize(document.clone(), |name| {
+            self.variables.as_ref()?.get(name)?.as_bool()
+        })
+    }
+
+    /// Plan the subrequests for the document. Plans are cached, so that later requests for the
+    /// same operation skip planning, unless their partitions are to be verified. A document that
+    /// cannot be partitioned is planned to be sent whole; that plan is not cached, so that the
+    /// failure is reported for every request. The document is only normalized if it has to be
+    /// planned, in which case the normalized document is returned along with the plan.
+    fn partition_plan(
+        &self,
+        document: &Document<'a, &'a str>,
+    ) -> (PartitionPlan, Option<Normalized<'a>>) {
+        let cache_key = partition_plan::cache_key(&crate::VERSION, document, self.variables);
+        if !self.verify {
+            if let Some(plan) = cached_plan(&cache_key) {
+                debug!("Using cached plan {}", cache_key);
+                return (plan.with_variables(self.variables), None);
+            }
+        }
+
+        let normalized = self.normalize(document);
+
+        let partitions = match self.partitioning {
+            Partitioning::Paths(paths) => normalized
+                .clone()
+                .and_then(|d| d.partition_by_paths(paths))
+                .map(Self::path_partitions),
+            Partitioning::Schema(schema) => normalized.clone().and_then(|d| d.plan(schema)),
+        };
+        let (partitions, is_cacheable) = match partitions {
+            Ok(partitions) => (partitions, true),
+            Err(why) => (vec![self.unpartitioned(document.clone(), why)], false),
+        };
+
+        if self.verify {
+            let partitions = partitions
+                .iter()
+                .map(|planned| planned.partition.clone())
+                .collect::<Vec<_>>();
+            self.verify_partitions(normalized.as_ref().unwrap_or(document), &partitions);
+        }
+
+        let plan = PartitionPlan::new(partitions, self.variables);
+        if !is_cacheable {
+            // The document is sent as the client sent it, which may be with several operations
+            let plan = plan.with_requested_operation(self.operation_name, self.variables);
+            return (plan, Some(normalized));
+        }
+        cache_plan(cache_key, plan.clone().without_variable_values());
+        (plan, Some(normalized))
+    }
+
+    /// Log how the union of the partitions differs from the document, if it does
+    fn verify_partitions(
+        &self,
+        document: &Document<'a, &'a str>,
+        partitions: &[Document<'a, &'a str>],
+    ) {
+        let operation_name = operation_name(document);
+        match diff_partitions(document, partitions) {
+            Ok(diff) if diff.is_empty() => debug!(
+                operation_name,
+                "Verified {} partitions of operation {}",
+                partitions.len(),
+                operation_name
+            ),
+            Ok(diff) => error!(
+                operation_name,
+                diff = diff.to_string().as_str(),
+                "Partitions of operation {} do not add up to it:\n{}",
+                operation_name,
+                diff
+            ),
+            Err(why) => warn!(
+                operation_name,
+                "Could not verify partitions of operation {}: {}", operation_name, why
+            ),
+        }
+    }
+
+    /// Log the response keys the document selects that are missing from the response data. A
+    /// response without data, e.g. because the backend rejected the query, is not checked.
+    fn verify_response(&self, document: &Document<'a, &'a str>, container: &Value) {
+        let data = match container.get("data") {
+            Some(data) if !data.is_null() => data,
+            _ => return,
+        };
+        let operation_name = self
+            .operation_name
+            .as_deref()
+            .unwrap_or_else(|| operation_name(document));
+        let missing = missing_response_keys(document, self.operation_name.as_deref(), data);
+        if !missing.is_empty() {
+            error!(
+                operation_name,
+                missing = missing.join(", ").as_str(),
+                "Response to operation {} is missing {} keys:\n{}",
+                operation_name,
+                missing.len(),
+                missing.iter().map(|path| format!("- {}", path)).join("\n")
+            );
+        }
+    }
+
+    /// Return the query the request sends. It is read back from the request, since GET requests
+    /// send it canonicalized.
+    fn sent_query(request: &mut Request) -> Option<String> {
+        if request.get_method() == Method::GET {
+            Some(request.get_query_parameter("query")?.to_string())
+        } else {
+            request
+                .clone_with_body()
+                .take_body_json::<GraphqlRequest>()
+                .ok()?
+                .query
+        }
+    }
+
+    /// Plan a document that could not be partitioned. The document is sent whole and uncached,
+    /// since it may contain user-specific data that could not be separated out; how loudly the
+    /// failure is reported depends on what went wrong.
+    fn unpartitioned(
+        &self,
+        document: Document<'a, &'a str>,
+        why: PartitionError,
+    ) -> PlannedPartition<Document<'a, &'a str>> {
+        match why {
+            // Expected for some requests, e.g. when a query does not select a user-specific path
+            // or selects nothing else
+            PartitionError::PathNotFound { .. } | PartitionError::EmptyResult { .. } => {
+                debug!("Sending document unpartitioned: {}", why)
             }
+            PartitionError::UnsupportedOperationType { .. }
+            | PartitionError::OperationCount { .. }
+            | PartitionError::UnresolvedFragment { .. } => {
+                warn!("Sending document unpartitioned: {}", why)
+            }
+            // The processing instructions themselves are wrong
+            PartitionError::InvalidPathElement { .. } => error!(
+                "Partitioning {:?} is not valid, sending document unpartitioned: {}",
+                self.partitioning, why
+            ),
+        }
+        PlannedPartition {
+            policy: Policy::Private,
+            partition: document,
+        }
+    }
+
+    /// Plan the partitions carved out by paths. Each partition is user-specific, so it is
+    /// private. The remainder, if the paths left anything over, is public.
+    fn path_partitions(
+        (partitions, remainder): Partitions<Document<'a, &'a str>>,
+    ) -> Vec<PlannedPartition<Document<'a, &'a str>>> {
+        let mut planned = partitions
+            .into_iter()
+            .map(|partition| PlannedPartition {
+                policy: Policy::Private,
+                partition,
+            })
+            .collect::<Vec<_>>();
+        match remainder {
+            Some(partition) => planned.push(PlannedPartition {
+                policy: Policy::Public { ttl: None },
+                partition,
+            }),
+            None => debug!("Paths matched the whole document, so there is no remainder to send"),
         }
+        planned
+    }
+
+    /// Build the request for a subquery. Subqueries fetched via GET can be served from cache, for
+    /// their TTL if they have one; those fetched via POST are sent uncached.
+    fn subquery_request(&self, subquery: Subquery) -> Result<Request> {
+        let Subquery {
+            mut request,
+            method,
+            cache_policy,
+            ..
+        } = subquery;
+        let request = match method {
+            FetchMethod::Get => {
+                let mut request = request
+                    .get(self.headers, Some(self.is_subscriber))?
+                    .with_header("x-gql", "true");
+                if let Policy::Public { ttl: Some(ttl) } = cache_policy {
+                    request.set_ttl(ttl);
+                }
+                request
+            }
+            FetchMethod::Post => request.post(self.headers)?,
+        };
+        Ok(request)
+    }
+}
+
+/// Return the plan cached under the given key, if there is one that can be read
+fn cached_plan(key: &str) -> Option<PartitionPlan> {
+    match simple::get(key.to_string()) {
+        Ok(Some(body)) => match serde_json::from_slice(&body.into_bytes()) {
+            Ok(plan) => Some(plan),
+            Err(why) => {
+                warn!("Could not read cached plan {}: {}", key, why);
+                None
+            }
+        },
+        Ok(None) => None,
+        Err(why) => {
+            warn!("Could not look up cached plan {}: {}", key, why);
+            None
+        }
+    }
+}
+
+/// Cache the plan under the given key, unless another request has cached one there meanwhile.
+/// The plan is shared by every request for the operation, so it must not carry variable values.
+fn cache_plan(key: String, plan: PartitionPlan) {
+    let ttl = Duration::from_secs(crate::PLAN_CACHE_TTL_SECONDS);
+    let result = serde_json::to_string(&plan)
+        .map_err(Error::from)
+        .and_then(|body| simple::get_or_set(key.clone(), body, ttl).map_err(Error::from));
+    if let Err(why) = result {
+        warn!("Could not cache plan {}: {}", key, why);
+    }
+}
+
+/// Return the name of the document's first operation, or "None" if it has none or the operation
+/// is anonymous
+fn operation_name<'a>(document: &Document<'a, &'a str>) -> &'a str {
+    document
+        .definitions
+        .iter()
+        .find_map(|definition| match definition {
+            Definition::Operation(OperationDefinition::Query(query)) => Some(query.name),
+            Definition::Operation(OperationDefinition::Mutation(mutation)) => Some(mutation.name),
+            Definition::Operation(OperationDefinition::Subscription(subscription)) => {
+                Some(subscription.name)
+            }
+            Definition::Operation(OperationDefinition::SelectionSet(_)) => Some(None),
+            Definition::Fragment(_) => None,
+        })
+        .flatten()
+        .unwrap_or("None")
+}
+
+/// Point the locations of a GraphQL error reported for a subrequest into the client's original
+/// query. Locations that cannot be mapped would point at the wrong place, so they are dropped,
+/// along with the "locations" entry itself if none are left.
+fn remap_locations(mut error: Value, source_map: Option<&SourceMap>) -> Value {
+    let error_object = match error.as_object_mut() {
+        Some(error_object) => error_object,
+        None => return error,
+    };
+    let locations = match error_object.remove("locations") {
+        Some(Value::Array(locations)) => locations,
+        _ => return error,
+    };
+    let locations = locations
+        .iter()
+        .filter_map(|location| {
+            let position = Pos {
+                line: location["line"].as_u64()? as usize,
+                column: location["column"].as_u64()? as usize,
+            };
+            let original = source_map?.original_position(position)?;
+            Some(json!({ "line": original.line, "column": original.column }))
+        })
+        .collect::<Vec<_>>();
+    if !locations.is_empty() {
+        error_object.insert("locations".to_string(), Value::Array(locations));
     }
+    error
 }